@@ -0,0 +1,41 @@
+use protocol::{unix_time_us, ClockEstimate, ClockEstimator, ClockSample, Message};
+
+// Exchanges sent back to back after joining, so there is a usable estimate straight away
+const INITIAL_SAMPLES: usize = 8;
+
+// Our estimate of how far the server's clock is from ours, fed by TIME_REQUEST/TIME_RESPONSE
+// exchanges. After the first few, one exchange is made per server ping.
+#[derive(Default)]
+pub struct ClockSync {
+    estimator: ClockEstimator,
+}
+
+impl ClockSync {
+    pub fn request() -> Message {
+        Message::TimeRequest { client_send_us: unix_time_us() }
+    }
+
+    // Take in a TIME_RESPONSE. Returns the report for the server and, while still warming up,
+    // the next request.
+    pub fn handle_response(&mut self, client_send_us: u64, server_receive_us: u64, server_send_us: u64) -> Vec<Message> {
+        let now = unix_time_us();
+        self.estimator.add(ClockSample::from_timestamps(client_send_us, server_receive_us, server_send_us, now));
+
+        let mut replies = Vec::new();
+        if let Some(estimate) = self.estimator.estimate(now) {
+            if cfg!(debug_assertions) {
+                println!("Clock sync: server is {}us ahead, +/- {}us, drift {:.2}ppm over {} samples",
+                         estimate.offset_us, estimate.uncertainty_us, estimate.drift_ppm, estimate.samples);
+            }
+            replies.push(Message::ClockReport { estimate });
+        }
+        if self.estimator.sample_count() < INITIAL_SAMPLES {
+            replies.push(Self::request());
+        }
+        replies
+    }
+
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.estimator.estimate(unix_time_us())
+    }
+}
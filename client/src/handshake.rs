@@ -0,0 +1,65 @@
+use std::env;
+use std::net::TcpStream;
+use websocket::OwnedMessage;
+use websocket::sync::Client;
+use protocol::{Capability, ClientInfo, Message, SchedulingMode, ServerConfig, PROTOCOL_VERSION};
+
+// What this client can do, offered to the server in HELLO
+const CLIENT_CAPABILITIES: &[Capability] = &[Capability::PhotonClock, Capability::ClockSync];
+
+// What the server told us in WELCOME
+pub struct Session {
+    pub session_id: u64,
+    pub config: ServerConfig,
+    pub features: Vec<Capability>,
+}
+
+// LAGLOCK_SCHEDULING=staggered asks the server to trigger us on receipt rather than rely on our
+// clock, e.g. to compare the two modes
+fn scheduling_preference() -> Option<SchedulingMode> {
+    match env::var("LAGLOCK_SCHEDULING").ok()?.as_str() {
+        "scheduled" => Some(SchedulingMode::Scheduled),
+        "staggered" => Some(SchedulingMode::Staggered),
+        other => {
+            println!("Ignoring unknown LAGLOCK_SCHEDULING {}", other);
+            None
+        }
+    }
+}
+
+// Introduce ourselves to the server and wait for its WELCOME. The error is the server's reason
+// for turning us away, or what went wrong on the way.
+pub fn say_hello(client: &mut Client<TcpStream>) -> Result<Session, String> {
+    let hello = Message::Hello {
+        protocol_version: PROTOCOL_VERSION,
+        client: ClientInfo {
+            // LAGLOCK_NAME tells the clients in a room apart in the server's log
+            display_name: env::var("LAGLOCK_NAME").unwrap_or_else(|_| "client".to_string()),
+            platform: env::consts::OS.to_string(),
+            software_version: env!("CARGO_PKG_VERSION").to_string(),
+            capabilities: CLIENT_CAPABILITIES.to_vec(),
+            scheduling: scheduling_preference(),
+        },
+    };
+    client.send_message(&OwnedMessage::Text(protocol::encode(&hello)))
+        .map_err(|e| format!("could not send hello: {}", e))?;
+
+    loop {
+        let text = match client.recv_message().map_err(|e| format!("no welcome: {}", e))? {
+            OwnedMessage::Text(text) => text,
+            OwnedMessage::Ping(data) => {
+                let _ = client.send_message(&OwnedMessage::Pong(data));
+                continue;
+            }
+            OwnedMessage::Close(_) => return Err("the server closed the connection".to_string()),
+            _ => continue,
+        };
+
+        return match protocol::decode(&text) {
+            Ok(Message::Welcome { session_id, config, features }) => Ok(Session { session_id, config, features }),
+            Ok(Message::Error { code, message }) => Err(format!("{:?}: {}", code, message)),
+            Ok(message) => Err(format!("expected welcome, got {:?}", message)),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+}
@@ -1,24 +1,71 @@
+mod clock_sync;
+mod handshake;
 mod models;
 mod photon_ping;
+mod playback;
 
 use std::collections::HashMap;
+use std::env;
+use std::net::TcpStream;
+use std::process;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
 use std::thread;
+use std::time::SystemTime;
 use websocket::{ClientBuilder, OwnedMessage};
-use crate::models::{get_ping_data_json};
-use crate::photon_ping::{fetch_regions_once, ping_cached_regions};
+use websocket::sync::Writer;
+use protocol::{Capability, Message, PlayTarget};
+use crate::clock_sync::ClockSync;
+use crate::handshake::say_hello;
+use crate::models::{get_ping_data_json, RegionPingData};
+use crate::photon_ping::{cached_regions, monitor_regions, ping_cached_regions};
+use crate::playback::{deadline_at, play_at_instant, play_now};
 
 fn main() {
-    let client = ClientBuilder::new("ws://127.0.0.1:8080")
+    // Report when we lose and regain Photon, as PLAY_PHOTON falls back to the wall clock meanwhile
+    let connection_events = photon::subscribe_connection_events();
+    thread::spawn(move || {
+        for event in connection_events {
+            println!("Photon connection: {:?}", event);
+        }
+    });
+
+    // Recording the Photon traffic lets field problems be replayed later
+    if let Ok(path) = env::var("PHOTON_CAPTURE")
+        && let Err(e) = photon::start_capture(&path) {
+        println!("Could not capture Photon traffic to {}: {}", path, e);
+    }
+
+    // Connect to Photon straight away so the shared clock is synchronised before the first PLAY_PHOTON
+    photon::start_connection();
+
+    thread::spawn(monitor_regions);
+
+    let mut client = ClientBuilder::new("ws://127.0.0.1:8080")
         .unwrap()
         .connect_insecure()
         .unwrap();
 
+    let session = match say_hello(&mut client) {
+        Ok(session) => session,
+        Err(reason) => {
+            println!("Server turned us away: {}", reason);
+            process::exit(1);
+        }
+    };
+    println!("Joined as session {} with features {:?}, server config {:?}",
+             session.session_id, session.features, session.config);
+
     let ping_data = Arc::new(Mutex::new(HashMap::new()));
 
     let (mut receiver, mut sender) = client.split().unwrap();
 
+    // Learn how far our clock is from the server's, if it takes part
+    let clock_sync_enabled = session.features.contains(&Capability::ClockSync);
+    let mut clock_sync = ClockSync::default();
+    if clock_sync_enabled {
+        send(&mut sender, &ClockSync::request());
+    }
+
     for message in receiver.incoming_messages() {
         let unwrapped_msg = message.unwrap();
         match unwrapped_msg {
@@ -33,7 +80,8 @@ fn main() {
                     let rtt = u128::from_be_bytes(rtt_bytes);
 
                     let current_timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis();
-                    let time_difference = current_timestamp - sent_timestamp;
+                    // Negative when our clock is behind the server's
+                    let time_difference = current_timestamp as i128 - sent_timestamp as i128;
 
                     // Log only if needed for debugging
                     if cfg!(debug_assertions) {
@@ -41,116 +89,136 @@ fn main() {
                                  sent_timestamp, rtt, time_difference);
                     }
                 }
-                sender.send_message(&OwnedMessage::Pong(ping));
+                let _ = sender.send_message(&OwnedMessage::Pong(ping));
+
+                // Keep the clock estimate fresh at the pace of the server's pings
+                if clock_sync_enabled {
+                    send(&mut sender, &ClockSync::request());
+                }
             }
             OwnedMessage::Text(text) => {
                 println!("Received text message: {}", text);
 
-                // Check if this is a play message with a future timestamp
-                if text.starts_with("PLAY:") {
-                    let parts: Vec<&str> = text.splitn(3, ':').collect();
-                    if parts.len() >= 3 {
-                        if let Ok(target_timestamp) = parts[1].parse::<u128>() {
-                            let message_content = parts[2];
-
-                            // Get current timestamp
-                            let now = SystemTime::now()
-                                .duration_since(UNIX_EPOCH)
-                                .expect("Time went backwards")
-                                .as_millis();
-
-                            if target_timestamp > now {
-                                // Calculate how long to wait
-                                let wait_time = target_timestamp - now;
-                                println!("Received play message: '{}' to be played at timestamp {}. Current time: {}. Waiting for {} ms", 
-                                         message_content, target_timestamp, now, wait_time);
-
-                                // Wait until the specified timestamp
-                                thread::sleep(Duration::from_millis(wait_time as u64));
-
-                                // Play the message
-                                println!("PLAYING NOW: {}", message_content);
-                                // Here you would trigger the actual playback
-                            } else {
-                                // The timestamp is in the past, play immediately
-                                println!("PLAYING IMMEDIATELY (timestamp already passed): {}", message_content);
-                                // Here you would trigger the actual playback
+                match protocol::decode(&text) {
+                    Ok(Message::Play { target, content, .. }) => {
+                        let timestamp_us = match target {
+                            PlayTarget::Local { timestamp_us } => timestamp_us,
+                            PlayTarget::Server { timestamp_us } => local_time_us(&clock_sync, timestamp_us),
+                        };
+                        play_at_instant(deadline_at(timestamp_us), &content);
+                    }
+                    Ok(Message::Trigger { content }) => {
+                        // The server already held this back by our one-way delay
+                        play_now(&content);
+                    }
+                    Ok(Message::PlayPhoton { photon_timestamp, target_timestamp, content, photon_server }) => {
+                        // Prefer the shared Photon clock, our wall clock may not agree with the server's.
+                        // Only the server the target was given for counts in its time.
+                        match photon::clock().filter(|clock| photon_server.as_deref() == Some(clock.server())) {
+                            Some(clock) => {
+                                if cfg!(debug_assertions) {
+                                    println!("Photon time now: {}, target: {}, uncertainty: {:?}",
+                                             clock.server_timestamp(), photon_timestamp, clock.uncertainty());
+                                }
+                                play_at_instant(clock.instant_at(photon_timestamp), &content);
+                            }
+                            None => {
+                                println!("Photon clock not synchronised with {:?}, falling back to wall clock", photon_server);
+                                play_at_instant(deadline_at(local_time_us(&clock_sync, target_timestamp * 1000)), &content);
                             }
-                        } else {
-                            println!("Invalid timestamp format in play message: {}", text);
                         }
-                    } else {
-                        println!("Invalid play message format: {}", text);
+                    }
+                    Ok(Message::RequestPings { region }) => {
+                        // If no region is specified, use all regions
+                        let target_region = region.as_deref().unwrap_or("");
+                        ping_regions_on_demand(&ping_data, target_region, &mut sender);
+                    }
+                    Ok(Message::TimeResponse { client_send_us, server_receive_us, server_send_us }) => {
+                        for reply in clock_sync.handle_response(client_send_us, server_receive_us, server_send_us) {
+                            send(&mut sender, &reply);
+                        }
+                    }
+                    Ok(Message::Error { code, message }) => {
+                        println!("Server rejected a message ({:?}): {}", code, message);
+                    }
+                    // Confirmations of what we sent
+                    Ok(_) => {}
+                    Err(e) => {
+                        println!("Could not read message from server: {}", e);
+                        send(&mut sender, &e.reply());
                     }
                 }
-                else if text.starts_with("REQUEST_PING:") {
-                    // Check if a specific region is requested
-                    let parts: Vec<&str> = text.splitn(2, ':').collect();
-                    let target_region = if parts.len() > 1 && !parts[1].is_empty() {
-                        parts[1]
-                    } else {
-                        // If no region specified, use all regions
-                        ""
-                    };
+            }
+            _ => {
+                println!("Unknown Recv: {:?}", unwrapped_msg);
+            }
+        }
 
-                    if cfg!(debug_assertions) {
-                        println!("Starting on-demand ping for region: {}", target_region);
-                    }
+    }
+}
 
-                    // Create a tokio runtime for async operations
-                    let rt = tokio::runtime::Runtime::new().unwrap();
-
-                    // Fetch regions
-                    let regions = rt.block_on(fetch_regions_once());
-
-                    // Filter regions if a specific one is requested
-                    let regions_to_ping = if !target_region.is_empty() {
-                        regions.into_iter()
-                            .filter(|r| r.short_name == target_region)
-                            .collect::<Vec<_>>()
-                    } else {
-                        regions
-                    };
-
-                    if regions_to_ping.is_empty() {
-                        println!("No matching regions found for: {}", target_region);
-                        // Send an empty response
-                        let json_data = get_ping_data_json(&ping_data, target_region);
-                        sender.send_message(&OwnedMessage::Text(json_data));
-                        continue;
-                    }
+// A time on the server's clock converted to ours, as far as we know the difference
+fn local_time_us(clock_sync: &ClockSync, server_us: u64) -> u64 {
+    clock_sync.estimate().map_or(server_us, |estimate| estimate.to_local_time(server_us))
+}
 
-                    // Ping the regions
-                    let ping_results = rt.block_on(ping_cached_regions(&regions_to_ping));
+fn send(sender: &mut Writer<TcpStream>, message: &Message) {
+    if let Err(e) = sender.send_message(&OwnedMessage::Text(protocol::encode(message))) {
+        println!("Error sending message to server: {:?}", e);
+    }
+}
 
-                    // Update the ping data
-                    {
-                        let mut data = ping_data.lock().unwrap();
-                        let now = SystemTime::now();
+fn ping_regions_on_demand(ping_data: &Arc<Mutex<RegionPingData>>, target_region: &str, sender: &mut Writer<TcpStream>) {
+    if cfg!(debug_assertions) {
+        println!("Starting on-demand ping for region: {}", target_region);
+    }
 
-                        for (region, latency) in ping_results {
-                            let region_name = region.short_name.clone();
-                            data.insert(region_name.clone(), (latency, now));
+    // Fetch regions, which waits for the name server if the cache has expired
+    let regions = cached_regions();
+
+    // Create a tokio runtime for async operations
+    let rt = tokio::runtime::Runtime::new().unwrap();
+
+    // Filter regions if a specific one is requested
+    let regions_to_ping = if !target_region.is_empty() {
+        regions.into_iter()
+            .filter(|r| r.short_name == target_region)
+            .collect::<Vec<_>>()
+    } else {
+        regions
+    };
+
+    if regions_to_ping.is_empty() {
+        println!("No matching regions found for: {}", target_region);
+        // Send an empty response
+        let json_data = get_ping_data_json(ping_data, target_region);
+        let _ = sender.send_message(&OwnedMessage::Text(json_data));
+        return;
+    }
 
-                            if cfg!(debug_assertions) {
-                                println!("Region {}: {}ms", region_name, latency);
-                            }
-                        }
-                    }
+    // Ping the regions
+    let ping_results = rt.block_on(ping_cached_regions(&regions_to_ping));
 
-                    // Send the ping data back to the server
-                    let json_data = get_ping_data_json(&ping_data, target_region);
-                    sender.send_message(&OwnedMessage::Text(json_data));
+    // Update the ping data
+    {
+        let mut data = ping_data.lock().unwrap();
+        let now = SystemTime::now();
 
-                    if cfg!(debug_assertions) {
-                        println!("On-demand ping cycle finished.");
-                    }
-                }
-            }
-            _ => {
-                println!("Unknown Recv: {:?}", unwrapped_msg);
+        for (region, stats) in ping_results {
+            if cfg!(debug_assertions) {
+                println!("Region {} ({:?}): median {}us, p95 {}us, jitter {}us, loss {:.1}%",
+                         region.short_name, stats.method, stats.median_us, stats.p95_us, stats.jitter_us, stats.loss_percent());
             }
+
+            data.insert(region.short_name, (stats, now));
         }
+    }
+
+    // Send the ping data back to the server
+    let json_data = get_ping_data_json(ping_data, target_region);
+    let _ = sender.send_message(&OwnedMessage::Text(json_data));
 
+    if cfg!(debug_assertions) {
+        println!("On-demand ping cycle finished.");
     }
 }
@@ -1,39 +1,55 @@
 use std::time::{SystemTime, UNIX_EPOCH};
 use std::collections::HashMap;
-use serde::{Serialize, Deserialize};
+use protocol::{Message, PingMethod, PingStats, RegionPingInfo};
 
 // Type alias for storing region ping data
-pub type RegionPingData = HashMap<String, (u128, SystemTime)>;
-
-#[derive(Serialize, Deserialize)]
-pub struct RegionPingInfo {
-    pub region: String,
-    pub latency: u128,
-    pub last_updated: u128,
-}
-
-#[derive(Serialize, Deserialize)]
-pub struct PhotonPingsResponse {
-    pub regions: Vec<RegionPingInfo>,
-}
+pub type RegionPingData = HashMap<String, (photon::PingStats, SystemTime)>;
 
 pub fn get_ping_data_json(ping_data: &std::sync::Arc<std::sync::Mutex<RegionPingData>>, target_region: &str) -> String {
     let data = ping_data.lock().unwrap();
     let mut regions = Vec::new();
 
-    for (region, (latency, last_updated)) in data.iter() {
+    for (region, (stats, last_updated)) in data.iter() {
         // If a target region is specified, only include data for that region
         if target_region.is_empty() || region == target_region {
-            let timestamp = last_updated.duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let timestamp = last_updated.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
 
             regions.push(RegionPingInfo {
                 region: region.clone(),
-                latency: *latency,
+                latency: stats.mean_ms() as u64,
                 last_updated: timestamp,
+                stats: Some(ping_stats_message(stats)),
             });
         }
     }
 
-    let response = PhotonPingsResponse { regions };
-    format!("PHOTON_PINGS:{}", serde_json::to_string(&response).unwrap())
-}
\ No newline at end of file
+    // The server only times us on Photon's clock if it follows the same Photon server, and needs
+    // to know how closely we follow it
+    let clock = photon::clock();
+    protocol::encode(&Message::PhotonPings {
+        regions,
+        photon_server: clock.as_ref().map(|clock| clock.server().to_string()),
+        photon_uncertainty_us: clock.map(|clock| clock.uncertainty().as_micros() as u64),
+    })
+}
+// Our ping statistics as the protocol carries them
+fn ping_stats_message(stats: &photon::PingStats) -> PingStats {
+    PingStats {
+        method: match stats.method {
+            photon::PingMethod::Udp => PingMethod::Udp,
+            photon::PingMethod::WebSocket => PingMethod::WebSocket,
+            photon::PingMethod::TcpConnect => PingMethod::TcpConnect,
+        },
+        endpoint: stats.endpoint.map(|endpoint| endpoint.to_string()),
+        samples_us: stats.samples_us.clone(),
+        min_us: stats.min_us,
+        max_us: stats.max_us,
+        mean_us: stats.mean_us,
+        median_us: stats.median_us,
+        p95_us: stats.p95_us,
+        std_dev_us: stats.std_dev_us,
+        jitter_us: stats.jitter_us,
+        sent: stats.sent,
+        received: stats.received,
+    }
+}
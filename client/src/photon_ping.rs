@@ -1,39 +1,57 @@
-use photon;
+use std::time::Duration;
 
-pub async fn fetch_regions_once() -> Vec<photon::PhotonRegion> {
+const REGION_CACHE_PATH: &str = "photon_regions.json";
+const REGION_CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+const REGION_FETCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+// The region list from the cache or the name server. This blocks on the name server, so it must
+// not be called from async code.
+pub fn cached_regions() -> Vec<photon::PhotonRegion> {
     if cfg!(debug_assertions) {
         println!("Fetching regions...");
     }
-    let regions = photon::get_regions_async();
+    let cache = photon::RegionCache::new(REGION_CACHE_PATH, REGION_CACHE_TTL);
+    let cached = match photon::get_regions_cached(&cache, REGION_FETCH_TIMEOUT) {
+        Ok(cached) => cached,
+        Err(e) => {
+            println!("Name server unreachable and no cached region list: {}", e);
+            return Vec::new();
+        }
+    };
+    if cached.stale {
+        println!("Name server unreachable, using stale region list from {:?} (fetched {:?})", cached.source, cached.fetched_at);
+    }
     if cfg!(debug_assertions) {
-        println!("Found {} regions", regions.len());
+        println!("Found {} regions ({:?})", cached.regions.len(), cached.source);
     }
-    regions
+    cached.regions
 }
 
-pub async fn ping_cached_regions(regions: &[photon::PhotonRegion]) -> Vec<(photon::PhotonRegion, u128)> {
+// Keep an eye on region latency between REQUEST_PINGs, so changes show up in the log
+pub fn monitor_regions() {
+    let regions = cached_regions();
+    let (_monitor, events) = photon::RegionMonitor::start_with_subscriber(regions, photon::MonitorOptions::default());
+    for event in events {
+        println!("Region monitor: {:?}", event);
+    }
+}
+
+pub async fn ping_cached_regions(regions: &[photon::PhotonRegion]) -> Vec<(photon::PhotonRegion, photon::PingStats)> {
     if cfg!(debug_assertions) {
         println!("Pinging {} regions...", regions.len());
     }
-    let handles: Vec<_> = regions
-        .iter()
-        .cloned()
-        .map(|region| {
-            tokio::spawn(async move {
-                let pinger = photon::Pinger::new(&region);
-                let latency = pinger.start_ping(20);
-                (region, latency)
-            })
-        })
-        .collect();
+    let options = photon::SweepOptions {
+        samples: 20,
+        ..photon::SweepOptions::default()
+    };
 
-    let mut results = Vec::with_capacity(handles.len());
-    for handle in handles {
-        match handle.await {
-            Ok(pair) => results.push(pair),
-            Err(e) => eprintln!("A task panicked: {e:?}"),
+    let mut results = Vec::with_capacity(regions.len());
+    for (region, stats) in photon::ping_regions(regions, &options).await {
+        match stats {
+            Ok(stats) => results.push((region, stats)),
+            Err(e) => println!("Could not ping region {}: {}", region.short_name, e),
         }
     }
 
     results
-}
\ No newline at end of file
+}
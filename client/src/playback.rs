@@ -0,0 +1,39 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use protocol::unix_time_us;
+
+// The moment our wall clock reads `timestamp_us`. Converting straight away means a wall clock
+// adjustment while we wait doesn't move the deadline.
+pub fn deadline_at(timestamp_us: u64) -> Instant {
+    let now_us = unix_time_us();
+    let now = Instant::now();
+    if timestamp_us > now_us {
+        now + Duration::from_micros(timestamp_us - now_us)
+    } else {
+        now.checked_sub(Duration::from_micros(now_us - timestamp_us)).unwrap_or(now)
+    }
+}
+
+// Play a message at a local deadline
+pub fn play_at_instant(deadline: Instant, message_content: &str) {
+    let now = Instant::now();
+
+    if deadline > now {
+        let wait_time = deadline - now;
+        println!("Received play message: '{}'. Waiting for {} ms", message_content, wait_time.as_millis());
+
+        thread::sleep(wait_time);
+
+        println!("PLAYING NOW: {}", message_content);
+        // Here you would trigger the actual playback
+    } else {
+        println!("PLAYING IMMEDIATELY (deadline already passed): {}", message_content);
+        // Here you would trigger the actual playback
+    }
+}
+
+// Play a message straight away, for triggers the server has already timed
+pub fn play_now(message_content: &str) {
+    println!("PLAYING NOW (triggered): {}", message_content);
+    // Here you would trigger the actual playback
+}
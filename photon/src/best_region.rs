@@ -0,0 +1,296 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use serde::{Deserialize, Serialize};
+use crate::photon_region::PhotonRegion;
+use crate::fallback_ping::ping_with_fallback;
+use crate::pinger::DEFAULT_PROBE_TIMEOUT;
+
+/// The previous best region is kept without pinging the others as long as its ping is within
+/// this factor of what it was when it was chosen, same as PUN's BestRegion
+const PREVIOUS_PING_FACTOR_NUMERATOR: u128 = 3;
+const PREVIOUS_PING_FACTOR_DENOMINATOR: u128 = 2;
+
+/// The outcome of the previous selection, persisted so the next start only has to ping one region
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionSummary {
+    /// Full region name of the selected region, including the cluster
+    pub region: String,
+    /// Its ping in milliseconds when it was selected
+    pub ping: u128,
+    /// Sorted full names of all candidates at the time, a changed list means a full re-ping
+    pub available: Vec<String>,
+}
+
+impl RegionSummary {
+    /// Reads a summary file. Missing or unreadable files just mean there is no previous choice.
+    pub fn load<P: AsRef<Path>>(path: P) -> Option<RegionSummary> {
+        let contents = fs::read_to_string(path).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    pub fn store<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// The region picked by [`RegionSelection`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct BestRegion {
+    pub region: PhotonRegion,
+    /// Ping to `region` in milliseconds
+    pub ping: u128,
+    /// Every region that answered, sorted by ping
+    pub pings: Vec<(PhotonRegion, u128)>,
+    /// The previous choice was kept
+    pub from_previous: bool,
+}
+
+/// Picks the region with the lowest latency, like PUN's BestRegion.
+///
+/// Allow and deny entries are region codes, where a plain code (`eu`) matches every cluster of
+/// the region and a full name (`eu/cluster1`) matches only that cluster. Regions within
+/// `tolerance_ms` of the lowest ping count as equally good, in which case the previous choice wins
+/// so clients don't flip between two nearby regions.
+#[derive(Debug, Clone)]
+pub struct RegionSelection {
+    /// Only these regions are considered, all of them if empty
+    pub allow_list: Vec<String>,
+    /// These regions are never considered, even if allowed
+    pub deny_list: Vec<String>,
+    pub tolerance_ms: u128,
+    /// Pings averaged per region
+    pub sample_size: i32,
+    /// Where the previous [`RegionSummary`] is kept, nothing is persisted if `None`
+    pub summary_path: Option<PathBuf>,
+}
+
+impl Default for RegionSelection {
+    fn default() -> Self {
+        RegionSelection {
+            allow_list: Vec::new(),
+            deny_list: Vec::new(),
+            tolerance_ms: 10,
+            sample_size: 5,
+            summary_path: None,
+        }
+    }
+}
+
+impl RegionSelection {
+    /// The regions that pass the allow and deny lists
+    pub fn candidates(&self, regions: &[PhotonRegion]) -> Vec<PhotonRegion> {
+        regions
+            .iter()
+            .filter(|region| self.allow_list.is_empty() || self.allow_list.iter().any(|entry| region.matches(entry)))
+            .filter(|region| !self.deny_list.iter().any(|entry| region.matches(entry)))
+            .cloned()
+            .collect()
+    }
+
+    /// Pings the candidates, over UDP where possible, and picks the best one
+    pub fn select(&self, regions: &[PhotonRegion]) -> Option<BestRegion> {
+        let sample_size = self.sample_size.max(0) as u32;
+        self.select_with(regions, |region| {
+            match ping_with_fallback(region, sample_size, DEFAULT_PROBE_TIMEOUT) {
+                Ok(stats) if stats.is_reachable() => Some(stats.mean_ms()),
+                Ok(_) => None,
+                Err(e) => {
+                    println!("Could not ping region {}: {}", region.short_name, e);
+                    None
+                }
+            }
+        })
+    }
+
+    /// Like [`select`](Self::select), but measures with `ping`, which returns `None` for regions
+    /// that couldn't be reached. Candidates are pinged in parallel.
+    pub fn select_with<F>(&self, regions: &[PhotonRegion], ping: F) -> Option<BestRegion>
+    where
+        F: Fn(&PhotonRegion) -> Option<u128> + Sync,
+    {
+        let candidates = self.candidates(regions);
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut available: Vec<String> = candidates.iter().map(|region| region.short_name.clone()).collect();
+        available.sort();
+
+        let previous = self.summary_path.as_ref().and_then(RegionSummary::load);
+
+        // Fast path: same candidates as last time and the previous choice is still about as fast
+        if let Some(previous) = &previous && previous.available == available
+            && let Some(region) = candidates.iter().find(|region| region.short_name == previous.region)
+            && let Some(latency) = ping(region)
+            && latency <= previous.ping * PREVIOUS_PING_FACTOR_NUMERATOR / PREVIOUS_PING_FACTOR_DENOMINATOR
+        {
+            let best = BestRegion {
+                region: region.clone(),
+                ping: latency,
+                pings: vec![(region.clone(), latency)],
+                from_previous: true,
+            };
+            self.store_summary(&best, available);
+            return Some(best);
+        }
+
+        let mut pings = ping_all(&candidates, &ping);
+        if pings.is_empty() {
+            return None;
+        }
+        pings.sort_by_key(|(_, latency)| *latency);
+
+        let lowest = pings[0].1;
+        let previous_within_tolerance = previous.as_ref().and_then(|previous| {
+            pings.iter().find(|(region, latency)| region.short_name == previous.region && *latency <= lowest + self.tolerance_ms)
+        });
+        let (region, latency) = previous_within_tolerance.unwrap_or(&pings[0]).clone();
+
+        let best = BestRegion {
+            from_previous: previous.is_some_and(|previous| previous.region == region.short_name),
+            region,
+            ping: latency,
+            pings,
+        };
+        self.store_summary(&best, available);
+        Some(best)
+    }
+
+    fn store_summary(&self, best: &BestRegion, available: Vec<String>) {
+        if let Some(path) = &self.summary_path {
+            let summary = RegionSummary {
+                region: best.region.short_name.clone(),
+                ping: best.ping,
+                available,
+            };
+            if let Err(e) = summary.store(path) {
+                println!("Could not write region summary {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn ping_all<F>(regions: &[PhotonRegion], ping: &F) -> Vec<(PhotonRegion, u128)>
+where
+    F: Fn(&PhotonRegion) -> Option<u128> + Sync,
+{
+    thread::scope(|scope| {
+        let handles: Vec<_> = regions
+            .iter()
+            .map(|region| scope.spawn(move || ping(region).map(|latency| (region.clone(), latency))))
+            .collect();
+
+        handles
+            .into_iter()
+            .filter_map(|handle| match handle.join() {
+                Ok(result) => result,
+                Err(_) => {
+                    println!("A region ping panicked");
+                    None
+                }
+            })
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn region(name: &str) -> PhotonRegion {
+        PhotonRegion { short_name: name.to_string(), address: format!("wss://{}.example:19090", name) }
+    }
+
+    fn regions() -> Vec<PhotonRegion> {
+        vec![region("eu"), region("eu/cluster1"), region("us"), region("asia")]
+    }
+
+    fn latency(region: &PhotonRegion) -> Option<u128> {
+        match region.short_name.as_str() {
+            "eu" => Some(30),
+            "eu/cluster1" => Some(35),
+            "us" => Some(25),
+            _ => None,
+        }
+    }
+
+    fn names(regions: &[PhotonRegion]) -> Vec<&str> {
+        regions.iter().map(|region| region.short_name.as_str()).collect()
+    }
+
+    fn temp_summary(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("photon_best_region_{}_{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_allow_and_deny_lists_understand_clusters() {
+        let selection = RegionSelection {
+            allow_list: vec!["eu".to_string(), "US".to_string()],
+            deny_list: vec!["eu/cluster1".to_string()],
+            ..RegionSelection::default()
+        };
+
+        assert_eq!(names(&selection.candidates(&regions())), vec!["eu", "us"]);
+    }
+
+    #[test]
+    fn test_selects_lowest_ping_and_skips_unreachable() {
+        let best = RegionSelection::default().select_with(&regions(), latency).unwrap();
+
+        assert_eq!(best.region.short_name, "us");
+        assert_eq!(best.ping, 25);
+        assert_eq!(best.pings.len(), 3);
+        assert!(!best.from_previous);
+    }
+
+    #[test]
+    fn test_none_when_nothing_answers() {
+        assert_eq!(RegionSelection::default().select_with(&regions(), |_| None), None);
+    }
+
+    #[test]
+    fn test_previous_choice_within_tolerance_is_kept() {
+        let path = temp_summary("tolerance");
+        RegionSummary { region: "eu".to_string(), ping: 10, available: Vec::new() }.store(&path).unwrap();
+        let selection = RegionSelection { summary_path: Some(path.clone()), ..RegionSelection::default() };
+
+        // The candidates changed, so everything is pinged, but eu is within 10ms of us
+        let best = selection.select_with(&regions(), latency).unwrap();
+        assert_eq!(best.region.short_name, "eu");
+        assert!(best.from_previous);
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_summary_makes_next_selection_ping_one_region() {
+        let path = temp_summary("fast");
+        let selection = RegionSelection { summary_path: Some(path.clone()), ..RegionSelection::default() };
+        selection.select_with(&regions(), latency).unwrap();
+
+        let pinged = AtomicUsize::new(0);
+        let best = selection
+            .select_with(&regions(), |region| {
+                pinged.fetch_add(1, Ordering::SeqCst);
+                latency(region)
+            })
+            .unwrap();
+        assert_eq!(best.region.short_name, "us");
+        assert!(best.from_previous);
+        assert_eq!(pinged.load(Ordering::SeqCst), 1);
+
+        // Much slower than before, so all regions are pinged again
+        let best = selection
+            .select_with(&regions(), |region| if region.short_name == "us" { Some(200) } else { latency(region) })
+            .unwrap();
+        assert_eq!(best.region.short_name, "eu");
+        assert!(!best.from_previous);
+
+        let _ = fs::remove_file(&path);
+    }
+}
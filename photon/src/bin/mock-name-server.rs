@@ -0,0 +1,76 @@
+//! Runs a local Photon name server for testing the client without a network.
+//!
+//! Usage: mock-name-server [--port N] [--regions FILE] [--delay-ms N] [--region-error CODE:MESSAGE]
+//!                         [--disconnect CODE:MESSAGE] [--malformed] [--no-pings]
+
+use std::env;
+use std::fs;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use photon::{MockNameServer, MockNameServerOptions, PhotonRegion};
+
+const USAGE: &str = "Usage: mock-name-server [--port N] [--regions FILE] [--delay-ms N] \
+[--region-error CODE:MESSAGE] [--disconnect CODE:MESSAGE] [--malformed] [--no-pings]";
+
+fn main() {
+    let (port, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let server = match MockNameServer::bind(("127.0.0.1", port), options) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Could not start mock name server: {}", e);
+            process::exit(1);
+        }
+    };
+    println!("Mock name server listening on {}", server.url());
+
+    // Runs until killed
+    loop {
+        thread::sleep(Duration::from_secs(60));
+        println!("{} connections so far", server.connections());
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(u16, MockNameServerOptions), String> {
+    let mut port = 0;
+    let mut options = MockNameServerOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--port" => port = value()?.parse().map_err(|e| format!("Invalid port: {}", e))?,
+            "--regions" => options.regions = read_regions(&value()?)?,
+            "--delay-ms" => {
+                let delay = value()?.parse().map_err(|e| format!("Invalid delay: {}", e))?;
+                options.reply_delay = Duration::from_millis(delay);
+            }
+            "--region-error" => options.region_error = Some(parse_code(&value()?)?),
+            "--disconnect" => options.disconnect = Some(parse_code(&value()?)?),
+            "--malformed" => options.malformed_frames = true,
+            "--no-pings" => options.answer_pings = false,
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    Ok((port, options))
+}
+
+/// A JSON array of `{"short_name": .., "address": ..}` objects, as in the region cache
+fn read_regions(path: &str) -> Result<Vec<PhotonRegion>, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("Invalid region list in {}: {}", path, e))
+}
+
+/// `CODE:MESSAGE`, where the message is optional
+fn parse_code(value: &str) -> Result<(i16, String), String> {
+    let (code, message) = value.split_once(':').unwrap_or((value, ""));
+    let code = code.parse().map_err(|e| format!("Invalid code {}: {}", code, e))?;
+    Ok((code, message.to_string()))
+}
@@ -0,0 +1,222 @@
+//! Decodes GpBinaryV18 messages into a readable tree or JSON, and encodes JSON back into bytes.
+//!
+//! Usage: photon-decode [--input hex|base64|raw] [--json] [--capture FILE] [DATA...]
+//!        photon-decode --encode [--output hex|base64|raw] [JSON]
+//!
+//! Each DATA argument is one message. Without any, messages are read from stdin, one per line,
+//! or as a single message with `--input raw`.
+
+use std::env;
+use std::io::{self, Read, Write};
+use std::process;
+use photon::{decode_message, encode_message, from_hex, read_capture, to_hex, DecodedMessage, Direction};
+
+const USAGE: &str = "Usage: photon-decode [--input hex|base64|raw] [--json] [--capture FILE] [DATA...]
+       photon-decode --encode [--output hex|base64|raw] [JSON]";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Encoding {
+    Hex,
+    Base64,
+    Raw,
+}
+
+struct Options {
+    input: Option<Encoding>,
+    output: Encoding,
+    json: bool,
+    encode: bool,
+    capture: Option<String>,
+    data: Vec<String>,
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let result = if options.encode { encode(&options) } else { decode(&options) };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options { input: None, output: Encoding::Hex, json: false, encode: false, capture: None, data: Vec::new() };
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--input" => options.input = Some(parse_encoding(&value()?)?),
+            "--output" => options.output = parse_encoding(&value()?)?,
+            "--json" => options.json = true,
+            "--encode" => options.encode = true,
+            "--capture" => options.capture = Some(value()?),
+            _ if arg.starts_with("--") => return Err(format!("Unknown argument {}", arg)),
+            _ => options.data.push(arg),
+        }
+    }
+
+    if options.input == Some(Encoding::Raw) && !options.data.is_empty() {
+        return Err("Raw input can only come from stdin".to_string());
+    }
+    Ok(options)
+}
+
+fn parse_encoding(value: &str) -> Result<Encoding, String> {
+    match value {
+        "hex" => Ok(Encoding::Hex),
+        "base64" => Ok(Encoding::Base64),
+        "raw" => Ok(Encoding::Raw),
+        _ => Err(format!("Unknown encoding {}", value)),
+    }
+}
+
+fn decode(options: &Options) -> Result<(), String> {
+    if let Some(path) = &options.capture {
+        let frames = read_capture(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+        for frame in frames {
+            let arrow = match frame.direction {
+                Direction::Inbound => "<-",
+                Direction::Outbound => "->",
+            };
+            println!("{} connection {} at {}us", arrow, frame.connection, frame.timestamp_us);
+            print_message(&frame.data, options.json);
+        }
+        return Ok(());
+    }
+
+    for frame in input_frames(options)? {
+        print_message(&frame, options.json);
+    }
+    Ok(())
+}
+
+fn input_frames(options: &Options) -> Result<Vec<Vec<u8>>, String> {
+    let texts = if options.data.is_empty() {
+        let mut input = Vec::new();
+        io::stdin().read_to_end(&mut input).map_err(|e| format!("Could not read stdin: {}", e))?;
+        if options.input == Some(Encoding::Raw) {
+            return Ok(vec![input]);
+        }
+        let input = String::from_utf8(input).map_err(|_| "Binary input needs --input raw".to_string())?;
+        input.lines().filter(|line| !line.trim().is_empty()).map(str::to_string).collect()
+    } else {
+        options.data.clone()
+    };
+
+    texts.iter().map(|text| parse_text(text.trim(), options.input)).collect()
+}
+
+/// Hex or base64, guessing which if not told. Hex wins for text that could be either.
+fn parse_text(text: &str, encoding: Option<Encoding>) -> Result<Vec<u8>, String> {
+    match encoding {
+        Some(Encoding::Hex) => from_hex(text),
+        Some(Encoding::Base64) => from_base64(text),
+        _ => from_hex(text).or_else(|_| from_base64(text)).map_err(|_| format!("{} is neither hex nor base64", text)),
+    }
+}
+
+fn print_message(frame: &[u8], json: bool) {
+    match decode_message(frame) {
+        Ok(message) if json => match serde_json::to_string_pretty(&message) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("Could not write JSON: {}", e),
+        },
+        Ok(message) => print_tree(&message),
+        Err(e) => println!("{} ({})", e, to_hex(frame)),
+    }
+}
+
+fn print_tree(message: &DecodedMessage) {
+    println!("{:?}", message.message_type);
+    if let Some(code) = message.operation_code {
+        println!("  operation: {} {}", code, message.operation.as_deref().unwrap_or("(unknown)"));
+    }
+    if let Some(code) = message.return_code {
+        println!("  return code: {}", code);
+    }
+    if let Some(code) = message.disconnect_code {
+        println!("  disconnect code: {}", code);
+    }
+    if let Some(debug_message) = &message.debug_message {
+        println!("  debug message: {:?}", debug_message);
+    }
+    if message.parameters.is_empty() {
+        return;
+    }
+    println!("  parameters:");
+    for parameter in &message.parameters {
+        // Serialized as {"type": .., "value": ..}, which is exactly what we want to show
+        let value = serde_json::to_value(&parameter.value).unwrap_or_default();
+        let shown = value.get("value").map(|v| v.to_string()).unwrap_or_default();
+        // The wire type, since e.g. Int1 and CompressedInt are both an Int once decoded
+        let gp_type = match parameter.gp_type {
+            Some(gp_type) => format!("{:?}", gp_type),
+            None => value["type"].as_str().unwrap_or("?").to_string(),
+        };
+        println!("    {} {}: {} {}",
+                 parameter.code,
+                 parameter.name.as_deref().unwrap_or("(unknown)"),
+                 gp_type,
+                 shown);
+    }
+}
+
+fn encode(options: &Options) -> Result<(), String> {
+    let json = if options.data.is_empty() {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input).map_err(|e| format!("Could not read stdin: {}", e))?;
+        input
+    } else {
+        options.data.join(" ")
+    };
+
+    let message: DecodedMessage = serde_json::from_str(&json).map_err(|e| format!("Invalid message JSON: {}", e))?;
+    let bytes = encode_message(&message).map_err(|e| e.to_string())?;
+
+    match options.output {
+        Encoding::Hex => println!("{}", to_hex(&bytes)),
+        Encoding::Base64 => println!("{}", to_base64(&bytes)),
+        Encoding::Raw => io::stdout().write_all(&bytes).map_err(|e| e.to_string())?,
+    }
+    Ok(())
+}
+
+fn to_base64(data: &[u8]) -> String {
+    base64::encode(data)
+}
+
+/// Standard or URL-safe base64, padding optional
+fn from_base64(text: &str) -> Result<Vec<u8>, String> {
+    let text: String = text.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    let config = if text.contains(['-', '_']) { base64::URL_SAFE } else { base64::STANDARD };
+    base64::decode_config(&text, config).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base64_round_trip() {
+        for data in [&b""[..], b"f", b"fo", b"foo", &[243, 255, 254, 62, 63]] {
+            assert_eq!(from_base64(&to_base64(data)).unwrap(), data);
+        }
+        assert_eq!(to_base64(b"fo"), "Zm8=");
+    }
+
+    #[test]
+    fn test_base64_accepts_url_safe_and_unpadded_input() {
+        assert_eq!(from_base64("Zm8").unwrap(), b"fo");
+        assert_eq!(from_base64("8__-").unwrap(), [243, 255, 254]);
+        assert_eq!(from_base64("8//+").unwrap(), [243, 255, 254]);
+        assert_eq!(from_base64(" Zm9v\n").unwrap(), b"foo");
+        assert!(from_base64("Zm9v!").is_err());
+    }
+}
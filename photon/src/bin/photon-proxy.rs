@@ -0,0 +1,69 @@
+//! Sits between a game client and its name server, logging every decoded frame as JSON.
+//!
+//! Usage: photon-proxy --upstream URL [--listen ADDRESS] [--rules FILE] [--log FILE]
+//!
+//! The rules file is a JSON array of rules such as
+//! `{"direction": "outbound", "operation_code": 220, "action": {"delay": {"ms": 500}}}`,
+//! with `"drop"` or `{"rewrite": {"return_code": -2}}` as the other actions.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use photon::{Proxy, ProxyOptions, ProxyRule};
+
+const USAGE: &str = "Usage: photon-proxy --upstream URL [--listen ADDRESS] [--rules FILE] [--log FILE]";
+
+fn main() {
+    let (listen, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let upstream = options.upstream.clone();
+    let proxy = match Proxy::start(listen.as_str(), options) {
+        Ok(proxy) => proxy,
+        Err(e) => {
+            eprintln!("Could not start proxy on {}: {}", listen, e);
+            process::exit(1);
+        }
+    };
+    // On stderr, so stdout stays pure JSON when logging there
+    eprintln!("Proxying {} to {}", proxy.url(), upstream);
+
+    // Runs until killed
+    loop {
+        thread::sleep(Duration::from_secs(60));
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, ProxyOptions), String> {
+    let mut listen = "127.0.0.1:9090".to_string();
+    let mut options = ProxyOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--listen" => listen = value()?,
+            "--upstream" => options.upstream = value()?,
+            "--rules" => options.rules = read_rules(&value()?)?,
+            "--log" => options.log_path = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    if options.upstream.is_empty() {
+        return Err("--upstream is required".to_string());
+    }
+    Ok((listen, options))
+}
+
+fn read_rules(path: &str) -> Result<Vec<ProxyRule>, String> {
+    let json = fs::read_to_string(path).map_err(|e| format!("Could not read {}: {}", path, e))?;
+    serde_json::from_str(&json).map_err(|e| format!("Invalid rules in {}: {}", path, e))
+}
@@ -0,0 +1,64 @@
+//! Answers Photon UDP pings, to stand in for regions offline or on a LAN.
+//!
+//! Usage: ping-responder [--bind ADDRESS] [--delay-ms N] [--jitter-ms N] [--drop PERCENT]
+
+use std::env;
+use std::process;
+use std::thread;
+use std::time::Duration;
+use photon::{PingResponder, PingResponderOptions, PING_PORT};
+
+const USAGE: &str = "Usage: ping-responder [--bind ADDRESS] [--delay-ms N] [--jitter-ms N] [--drop PERCENT]";
+
+fn main() {
+    let (bind, options) = match parse_args(env::args().skip(1)) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    let responder = match PingResponder::bind(bind.as_str(), options) {
+        Ok(responder) => responder,
+        Err(e) => {
+            eprintln!("Could not bind {}: {}", bind, e);
+            process::exit(1);
+        }
+    };
+    println!("Answering Photon pings on {}", responder.address());
+
+    // Runs until killed
+    loop {
+        thread::sleep(Duration::from_secs(60));
+        println!("{:?}", responder.stats());
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<(String, PingResponderOptions), String> {
+    // Photon's own port, so regions can point at this host without a port override
+    let mut bind = format!("0.0.0.0:{}", PING_PORT);
+    let mut options = PingResponderOptions::default();
+
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "--bind" => bind = value()?,
+            "--delay-ms" => options.delay = Duration::from_millis(parse_number(&value()?)?),
+            "--jitter-ms" => options.jitter = Duration::from_millis(parse_number(&value()?)?),
+            "--drop" => {
+                options.drop_percent = value()?.parse().map_err(|e| format!("Invalid drop rate: {}", e))?;
+                if !(0.0..=100.0).contains(&options.drop_percent) {
+                    return Err("The drop rate is a percentage from 0 to 100".to_string());
+                }
+            }
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+
+    Ok((bind, options))
+}
+
+fn parse_number(value: &str) -> Result<u64, String> {
+    value.parse().map_err(|e| format!("Invalid number {}: {}", value, e))
+}
@@ -0,0 +1,415 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::decode::{decode_message, panic_message, DecodeError};
+use crate::disconnect_reason::DisconnectInfo;
+use crate::message_type::EgMessageType;
+use crate::parameter_dictionary::Value;
+use crate::photon_codes;
+use crate::photon_region::PhotonRegion;
+use crate::round_trip_time::RoundTripStats;
+use crate::stream_buffer::StreamBuffer;
+use crate::transport::{Transport, TransportError};
+use crate::{DispatchContext, Dispatched};
+
+/// Which way a captured frame went
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// From the server to us
+    Inbound,
+    /// From us to the server
+    Outbound,
+}
+
+/// One frame of a capture file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CapturedFrame {
+    /// Microseconds since the unix epoch
+    pub timestamp_us: u64,
+    /// Counts up with every connection the worker opens, so reconnects can be told apart
+    pub connection: u64,
+    pub direction: Direction,
+    #[serde(serialize_with = "serialize_hex", deserialize_with = "deserialize_hex")]
+    pub data: Vec<u8>,
+}
+
+fn serialize_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(data))
+}
+
+fn deserialize_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    from_hex(&hex).map_err(serde::de::Error::custom)
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Parses hex digits, ignoring whitespace
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<u8> = hex.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".to_string());
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).map_err(|e| e.to_string())?;
+            u8::from_str_radix(pair, 16).map_err(|_| format!("invalid hex byte {:?}", pair))
+        })
+        .collect()
+}
+
+/// Appends frames to a capture file, one JSON object per line so a capture that was cut off by a
+/// crash can still be read
+pub struct CaptureWriter {
+    file: File,
+}
+
+impl CaptureWriter {
+    /// Creates the file, or appends to it if it already exists
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(CaptureWriter { file })
+    }
+
+    pub fn record(&mut self, connection: u64, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let timestamp_us = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_micros() as u64;
+        let frame = CapturedFrame { timestamp_us, connection, direction, data: data.to_vec() };
+        let mut line = serde_json::to_string(&frame).map_err(io::Error::other)?;
+        line.push('\n');
+        // Written in one go, and unbuffered, so the frame that crashed the process is on disk
+        self.file.write_all(line.as_bytes())
+    }
+}
+
+/// Reads every frame of a capture file. Fails on the first line that isn't a frame.
+pub fn read_capture<P: AsRef<Path>>(path: P) -> io::Result<Vec<CapturedFrame>> {
+    let reader = BufReader::new(File::open(path)?);
+    let mut frames = Vec::new();
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let frame = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", number + 1, e)))?;
+        frames.push(frame);
+    }
+    Ok(frames)
+}
+
+/// A [`Transport`] that records every frame that passes through it
+pub struct CaptureTransport<T: Transport> {
+    inner: T,
+    writer: Arc<Mutex<CaptureWriter>>,
+    connection: u64,
+}
+
+impl<T: Transport> CaptureTransport<T> {
+    pub fn new(inner: T, writer: Arc<Mutex<CaptureWriter>>, connection: u64) -> Self {
+        CaptureTransport { inner, writer, connection }
+    }
+
+    fn record(&self, direction: Direction, frame: &[u8]) {
+        // Losing the capture must never take the connection down with it
+        if let Err(e) = self.writer.lock().unwrap().record(self.connection, direction, frame) {
+            println!("Could not capture frame: {}", e);
+        }
+    }
+}
+
+impl<T: Transport> Transport for CaptureTransport<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        self.record(Direction::Outbound, frame);
+        self.inner.send_frame(frame)
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        let frame = self.inner.receive_frame(timeout)?;
+        if let Some(frame) = &frame {
+            self.record(Direction::Inbound, frame);
+        }
+        Ok(frame)
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.close()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.inner.rtt()
+    }
+}
+
+/// Why a replay stopped early
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplayError {
+    /// The dispatcher ended the connection, as it would have live
+    Disconnected { index: usize, info: DisconnectInfo },
+    /// The dispatcher panicked on the frame at `index` of the capture
+    Panicked { index: usize, message: String },
+}
+
+/// What replaying a capture did
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplayReport {
+    /// Inbound frames fed to the dispatcher
+    pub dispatched: usize,
+    /// Frames the dispatcher sent in response, in order
+    pub replies: Vec<Vec<u8>>,
+    /// The last region list the capture delivered
+    pub regions: Option<Vec<PhotonRegion>>,
+    /// The round trip statistics from the PING results in the capture
+    pub round_trip: Option<RoundTripStats>,
+    /// Frames the dispatcher couldn't read and ignored, by their index in the capture
+    pub malformed: Vec<(usize, DecodeError)>,
+    pub error: Option<ReplayError>,
+}
+
+/// When `frame` was recorded, in wrapping milliseconds like a Photon timestamp
+fn recorded_millis(frame: &CapturedFrame) -> i32 {
+    (frame.timestamp_us / 1000) as u32 as i32
+}
+
+/// Collects what the dispatcher sends during a replay
+#[derive(Default)]
+struct ReplayTransport {
+    sent: Vec<Vec<u8>>,
+}
+
+impl Transport for ReplayTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        self.sent.push(frame.to_vec());
+        Ok(())
+    }
+
+    fn receive_frame(&mut self, _timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        Ok(None)
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        Ok(())
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Feeds the inbound frames of a capture to the dispatcher in their recorded order, without a
+/// network or the keep-alive timer, so the same capture always takes the same path.
+///
+/// The dispatcher runs against a context of its own rather than the live connection's, so a
+/// replay neither changes the round trip time nor hands regions to subscribers. Its clock is the
+/// recorded time of each frame, lined up with the timestamps of the PINGs the capture sent.
+pub fn replay(frames: &[CapturedFrame]) -> ReplayReport {
+    let mut transport = ReplayTransport::default();
+    let mut context = DispatchContext::default();
+    let mut clock = ReplayClock::default();
+    let mut dispatched = 0;
+    let mut regions = None;
+    let mut malformed = Vec::new();
+    let mut error = None;
+
+    for (index, frame) in frames.iter().enumerate() {
+        if frame.direction == Direction::Outbound {
+            clock.sync(frame);
+            continue;
+        }
+
+        dispatched += 1;
+        context.now = clock.timestamp(frame);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut buffer = StreamBuffer::new(&frame.data);
+            crate::deserialize_message_and_callback(&mut buffer, &mut transport, &mut context)
+        }));
+
+        match result {
+            Ok(Ok(Dispatched::Regions(list))) => regions = Some(list),
+            Ok(Ok(Dispatched::Malformed(e))) => malformed.push((index, e)),
+            Ok(Ok(_)) => {}
+            Ok(Err(info)) => {
+                error = Some(ReplayError::Disconnected { index, info });
+                break;
+            }
+            Err(payload) => {
+                error = Some(ReplayError::Panicked { index, message: panic_message(payload.as_ref()) });
+                break;
+            }
+        }
+    }
+
+    ReplayReport {
+        dispatched,
+        replies: transport.sent,
+        regions,
+        round_trip: context.round_trip_time.stats(),
+        malformed,
+        error,
+    }
+}
+
+/// Turns the recorded time of a frame into the Photon timestamp the live worker had then
+#[derive(Default)]
+struct ReplayClock {
+    /// The live worker's timestamp minus the capture's, from the last PING it sent
+    offset: i32,
+}
+
+impl ReplayClock {
+    fn timestamp(&self, frame: &CapturedFrame) -> i32 {
+        recorded_millis(frame).wrapping_add(self.offset)
+    }
+
+    /// Lines the clock up with the timestamp of an outbound PING, if `frame` is one
+    fn sync(&mut self, frame: &CapturedFrame) {
+        let Ok(message) = decode_message(&frame.data) else { return };
+        if message.message_type != EgMessageType::InternalOperationRequest || message.operation_code != Some(photon_codes::PING) {
+            return;
+        }
+        if let Some(Value::Int(sent)) = message.parameters.iter().find(|p| p.code == 1).map(|p| &p.value) {
+            self.offset = sent.wrapping_sub(recorded_millis(frame));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use crate::disconnect_reason::DisconnectReason;
+    use crate::operation_response::OperationResponse;
+    use crate::parameter_codes::{ADDRESS, REGION};
+    use crate::parameter_dictionary::ParameterDictionary;
+    use crate::protocol_v18::serialize_operation_response;
+    use crate::transport::MemoryTransport;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = env::temp_dir().join(format!("photon_capture_{}_{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn inbound(data: &[u8]) -> CapturedFrame {
+        CapturedFrame { timestamp_us: 0, connection: 1, direction: Direction::Inbound, data: data.to_vec() }
+    }
+
+    fn response(message_type: EgMessageType, operation_code: u8, payload: ParameterDictionary) -> Vec<u8> {
+        let response = OperationResponse { operation_code, return_code: 0, debug_message: None, payload };
+        let mut buffer = StreamBuffer::with_capacity(0);
+        buffer.write(&[243, message_type as u8]);
+        serialize_operation_response(&mut buffer, &response, false).unwrap();
+        buffer.get_buffer()[..buffer.length()].to_vec()
+    }
+
+    #[test]
+    fn test_hex_round_trip() {
+        assert_eq!(to_hex(&[243, 1, 0]), "f30100");
+        assert_eq!(from_hex("f3 01\n00").unwrap(), vec![243, 1, 0]);
+        assert!(from_hex("f30").is_err());
+        assert!(from_hex("zz").is_err());
+    }
+
+    #[test]
+    fn test_capture_transport_records_both_directions() {
+        let path = temp_path("transport");
+        let writer = Arc::new(Mutex::new(CaptureWriter::open(&path).unwrap()));
+        let (client, mut server) = MemoryTransport::pair();
+        let mut client = CaptureTransport::new(client, writer, 7);
+
+        client.send_frame(&[243, 2, 220]).unwrap();
+        server.send_frame(&[243, 1]).unwrap();
+        client.receive_frame(Some(Duration::from_millis(100))).unwrap();
+
+        let frames = read_capture(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!((frames[0].connection, frames[0].direction, frames[0].data.clone()), (7, Direction::Outbound, vec![243, 2, 220]));
+        assert_eq!((frames[1].direction, frames[1].data.clone()), (Direction::Inbound, vec![243, 1]));
+        assert!(frames[0].timestamp_us <= frames[1].timestamp_us);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_capture_reports_bad_line() {
+        let path = temp_path("bad_line");
+        fs::write(&path, "{\"timestamp_us\":1,\"connection\":1,\"direction\":\"inbound\",\"data\":\"f301\"}\nnot json\n").unwrap();
+
+        let error = read_capture(&path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_answers_init() {
+        let outbound = CapturedFrame { direction: Direction::Outbound, ..inbound(&[243, 2, 220, 0]) };
+        let report = replay(&[inbound(&[243, EgMessageType::InitResponse as u8]), outbound]);
+
+        assert_eq!(report.dispatched, 1);
+        assert_eq!(report.error, None);
+        // A ping and the region request, like the live worker sends
+        assert_eq!(report.replies.len(), 2);
+        assert_eq!(&report.replies[0][..3], &[243, EgMessageType::InternalOperationRequest as u8, photon_codes::PING]);
+        assert_eq!(&report.replies[1][..3], &[243, EgMessageType::Operation as u8, 220]);
+    }
+
+    #[test]
+    fn test_replay_keeps_its_own_state() {
+        // A PING sent with our timestamp 5000, answered 40ms later by the capture's clock
+        let sent_us = 1_700_000_000_000_000;
+        let ping = CapturedFrame { timestamp_us: sent_us, direction: Direction::Outbound, ..inbound(&crate::ping_message(5000)) };
+        let mut payload = ParameterDictionary::new();
+        payload.set(1, Value::Int(5000));
+        payload.set(2, Value::Int(123_456));
+        let pong = CapturedFrame {
+            timestamp_us: sent_us + 40_000,
+            ..inbound(&response(EgMessageType::InternalOperationResponse, photon_codes::PING, payload))
+        };
+        let mut payload = ParameterDictionary::new();
+        payload.set(REGION, Value::StringArray(vec!["eu".to_string()]));
+        payload.set(ADDRESS, Value::StringArray(vec!["10.0.0.1:5058".to_string()]));
+        let regions = inbound(&response(EgMessageType::OperationResponse, 220, payload));
+
+        let frames = [ping, pong, regions];
+        let report = replay(&frames);
+
+        assert_eq!(report.error, None);
+        assert_eq!(report.round_trip.map(|stats| stats.last_round_trip_time), Some(40));
+        assert_eq!(report.regions, Some(vec![PhotonRegion { short_name: "eu".to_string(), address: "10.0.0.1:5058".to_string() }]));
+        // Nothing carries over from one replay to the next
+        assert_eq!(replay(&frames), report);
+    }
+
+    #[test]
+    fn test_replay_stops_at_disconnect() {
+        let disconnect = [243, EgMessageType::DisconnectReason as u8, 0xFF, 0x7F, 8, 0];
+        let report = replay(&[inbound(&disconnect), inbound(&[243, EgMessageType::InitResponse as u8])]);
+
+        assert_eq!(report.dispatched, 1);
+        assert_eq!(report.error, Some(ReplayError::Disconnected {
+            index: 0,
+            info: DisconnectInfo::new(DisconnectReason::InvalidAuth, None),
+        }));
+    }
+
+    #[test]
+    fn test_replay_reports_malformed_frame() {
+        // An operation response cut off after its op code, which is skipped like it is live
+        let init = inbound(&[243, EgMessageType::InitResponse as u8]);
+        let report = replay(&[inbound(&[243, EgMessageType::OperationResponse as u8, 220]), init]);
+
+        assert_eq!(report.dispatched, 2);
+        assert_eq!(report.error, None);
+        assert_eq!(report.malformed.len(), 1);
+        assert!(matches!(report.malformed[0], (0, DecodeError::Malformed(_))));
+        assert_eq!(report.replies.len(), 2);
+    }
+}
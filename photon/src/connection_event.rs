@@ -0,0 +1,19 @@
+use std::time::Duration;
+use crate::disconnect_reason::DisconnectInfo;
+
+/// State changes of the name server connection, see [`crate::subscribe_connection_events`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionEvent {
+    /// The first connection attempt has started
+    Connecting,
+    /// The connection is up
+    Connected,
+    /// The connection dropped. We're offline until `Recovered`, or for good after `GaveUp`.
+    Disconnected(DisconnectInfo),
+    /// A reconnect attempt will be made after `delay`
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The connection is back after `attempts` reconnect attempts
+    Recovered { attempts: u32 },
+    /// No more reconnect attempts will be made
+    GaveUp(DisconnectInfo),
+}
@@ -0,0 +1,356 @@
+use std::fmt;
+use serde::{Deserialize, Serialize};
+use crate::disconnect_message::DisconnectMessage;
+use crate::gp_type::GpType;
+use crate::message_type::EgMessageType;
+use crate::operation_response::OperationResponse;
+use crate::parameter_codes;
+use crate::parameter_dictionary::{ParameterDictionary, Value};
+use crate::photon_codes;
+use crate::protocol_v18::{
+    deserialize_disconnect_message_with_types, deserialize_operation_request_with_types,
+    deserialize_operation_response_with_types, serialize_disconnect_message, serialize_operation_request,
+    serialize_operation_response, WireTypes,
+};
+use crate::stream_buffer::StreamBuffer;
+
+/// Errors from [`decode_message`] and [`encode_message`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Shorter than the two byte header
+    Truncated,
+    /// Doesn't start with 243 or 253
+    NotPhoton(u8),
+    /// The encrypted flag is set, and we have no keys
+    Encrypted,
+    /// A message type or value we can't handle yet
+    Unsupported(String),
+    /// The body doesn't match its header
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "message is shorter than its header"),
+            DecodeError::NotPhoton(byte) => write!(f, "not a Photon message, starts with {}", byte),
+            DecodeError::Encrypted => write!(f, "message is encrypted"),
+            DecodeError::Unsupported(what) => write!(f, "unsupported: {}", what),
+            DecodeError::Malformed(e) => write!(f, "malformed message: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// One parameter of a decoded message
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedParameter {
+    pub code: u8,
+    /// The parameter's name, if we know it. Ignored when encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The type the value had on the wire, which tells apart e.g. `Int1` and `CompressedInt`.
+    /// Ignored when encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gp_type: Option<GpType>,
+    #[serde(flatten)]
+    pub value: Value,
+}
+
+/// A Photon message in a form that can be printed, or written as JSON and turned back into bytes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DecodedMessage {
+    pub message_type: EgMessageType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_code: Option<u8>,
+    /// The operation's name, if we know it. Ignored when encoding.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub return_code: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disconnect_code: Option<i16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub debug_message: Option<String>,
+    /// Sorted by code
+    #[serde(default)]
+    pub parameters: Vec<DecodedParameter>,
+}
+
+impl DecodedMessage {
+    fn new(message_type: EgMessageType) -> Self {
+        DecodedMessage {
+            message_type,
+            operation_code: None,
+            operation: None,
+            return_code: None,
+            disconnect_code: None,
+            debug_message: None,
+            parameters: Vec::new(),
+        }
+    }
+
+    fn with_operation(mut self, operation_code: u8, parameters: ParameterDictionary, types: &WireTypes) -> Self {
+        let internal = is_internal(self.message_type);
+        self.operation_code = Some(operation_code);
+        self.operation = operation_name(internal, operation_code).map(str::to_string);
+        self.parameters = decoded_parameters(internal, operation_code, parameters, types);
+        self
+    }
+}
+
+fn is_internal(message_type: EgMessageType) -> bool {
+    matches!(message_type, EgMessageType::InternalOperationRequest | EgMessageType::InternalOperationResponse)
+}
+
+/// Names of the operations we use or are likely to see, as in PUN's `OperationCode`
+pub fn operation_name(internal: bool, code: u8) -> Option<&'static str> {
+    if internal {
+        return match code {
+            photon_codes::INIT_ENCRYPTION => Some("InitEncryption"),
+            photon_codes::PING => Some("Ping"),
+            _ => None,
+        };
+    }
+    match code {
+        220 => Some("GetRegions"),
+        225 => Some("JoinRandomGame"),
+        226 => Some("JoinGame"),
+        227 => Some("CreateGame"),
+        228 => Some("LeaveLobby"),
+        229 => Some("JoinLobby"),
+        230 => Some("Authenticate"),
+        231 => Some("AuthenticateOnce"),
+        251 => Some("GetProperties"),
+        252 => Some("SetProperties"),
+        253 => Some("RaiseEvent"),
+        254 => Some("Leave"),
+        _ => None,
+    }
+}
+
+/// Parameter codes are only unique within an operation, internal PINGs reuse the low ones
+pub fn parameter_name(internal: bool, operation_code: u8, code: u8) -> Option<&'static str> {
+    if internal && operation_code == photon_codes::PING {
+        return match code {
+            1 => Some("ClientTimestamp"),
+            2 => Some("ServerTimestamp"),
+            _ => None,
+        };
+    }
+    match code {
+        parameter_codes::APPLICATION_ID => Some("ApplicationId"),
+        parameter_codes::SECRET => Some("Secret"),
+        parameter_codes::APP_VERSION => Some("AppVersion"),
+        parameter_codes::CLIENT_AUTHENTICATION_TYPE => Some("ClientAuthenticationType"),
+        parameter_codes::CLIENT_AUTHENTICATION_PARAMS => Some("ClientAuthenticationParams"),
+        parameter_codes::CLIENT_AUTHENTICATION_DATA => Some("ClientAuthenticationData"),
+        parameter_codes::REGION => Some("Region"),
+        parameter_codes::ADDRESS => Some("Address"),
+        parameter_codes::USER_ID => Some("UserId"),
+        parameter_codes::DATA => Some("Data"),
+        _ => None,
+    }
+}
+
+fn decoded_parameters(internal: bool, operation_code: u8, parameters: ParameterDictionary, types: &WireTypes) -> Vec<DecodedParameter> {
+    let mut decoded: Vec<DecodedParameter> = parameters
+        .into_iter()
+        .map(|(code, value)| DecodedParameter {
+            code,
+            name: parameter_name(internal, operation_code, code).map(str::to_string),
+            gp_type: types.get(&code).copied(),
+            value,
+        })
+        .collect();
+    decoded.sort_by_key(|parameter| parameter.code);
+    decoded
+}
+
+fn parameter_dictionary(parameters: &[DecodedParameter]) -> ParameterDictionary {
+    let mut dictionary = ParameterDictionary::with_capacity(parameters.len());
+    for parameter in parameters {
+        dictionary.set(parameter.code, parameter.value.clone());
+    }
+    dictionary
+}
+
+/// Decodes one complete message, header included, with the same decoder the connection uses
+pub fn decode_message(frame: &[u8]) -> Result<DecodedMessage, DecodeError> {
+    if frame.len() < 2 {
+        return Err(DecodeError::Truncated);
+    }
+    if frame[0] != 243 && frame[0] != 253 {
+        return Err(DecodeError::NotPhoton(frame[0]));
+    }
+    if frame[1] & 0x80 != 0 {
+        return Err(DecodeError::Encrypted);
+    }
+    let message_type = EgMessageType::try_from(frame[1] & 0x7F)
+        .map_err(|_| DecodeError::Unsupported(format!("message type {}", frame[1] & 0x7F)))?;
+
+    decode_body(message_type, &frame[2..])
+}
+
+fn decode_body(message_type: EgMessageType, body: &[u8]) -> Result<DecodedMessage, DecodeError> {
+    let mut stream = StreamBuffer::new(body);
+    let mut types = WireTypes::new();
+    let message = DecodedMessage::new(message_type);
+    match message_type {
+        EgMessageType::InitResponse => Ok(message),
+        EgMessageType::Operation | EgMessageType::InternalOperationRequest => {
+            let request = deserialize_operation_request_with_types(&mut stream, &mut types)?;
+            Ok(message.with_operation(request.operation_code, request.parameters, &types))
+        }
+        EgMessageType::OperationResponse | EgMessageType::InternalOperationResponse => {
+            let response = deserialize_operation_response_with_types(&mut stream, &mut types)?;
+            let mut message = message.with_operation(response.operation_code, response.payload, &types);
+            message.return_code = Some(response.return_code);
+            message.debug_message = response.debug_message;
+            Ok(message)
+        }
+        EgMessageType::DisconnectReason => {
+            let disconnect = deserialize_disconnect_message_with_types(&mut stream, &mut types)?;
+            let mut message = message.with_operation(0, disconnect.parameters, &types);
+            message.operation_code = None;
+            message.operation = None;
+            message.disconnect_code = Some(disconnect.code);
+            message.debug_message = disconnect.debug_message;
+            Ok(message)
+        }
+        other => Err(DecodeError::Unsupported(format!("{:?} messages", other))),
+    }
+}
+
+pub(crate) fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic".to_string())
+}
+
+/// Turns a message back into bytes, the inverse of [`decode_message`]
+pub fn encode_message(message: &DecodedMessage) -> Result<Vec<u8>, DecodeError> {
+    let operation_code = || message.operation_code
+        .ok_or_else(|| DecodeError::Malformed(format!("{:?} needs an operation_code", message.message_type)));
+
+    let mut buffer = StreamBuffer::with_capacity(0);
+    buffer.write(&[243, message.message_type as u8]);
+    match message.message_type {
+        EgMessageType::InitResponse => {}
+        EgMessageType::Operation | EgMessageType::InternalOperationRequest => {
+            serialize_operation_request(&mut buffer, operation_code()?, parameter_dictionary(&message.parameters), false)?;
+        }
+        EgMessageType::OperationResponse | EgMessageType::InternalOperationResponse => {
+            let response = OperationResponse {
+                operation_code: operation_code()?,
+                return_code: message.return_code.unwrap_or(0),
+                debug_message: message.debug_message.clone(),
+                payload: parameter_dictionary(&message.parameters),
+            };
+            serialize_operation_response(&mut buffer, &response, false)?;
+        }
+        EgMessageType::DisconnectReason => {
+            let disconnect = DisconnectMessage {
+                code: message.disconnect_code
+                    .ok_or_else(|| DecodeError::Malformed("DisconnectReason needs a disconnect_code".to_string()))?,
+                debug_message: message.debug_message.clone(),
+                parameters: parameter_dictionary(&message.parameters),
+            };
+            serialize_disconnect_message(&mut buffer, &disconnect)?;
+        }
+        other => return Err(DecodeError::Unsupported(format!("{:?} messages", other))),
+    }
+    Ok(buffer.get_buffer()[..buffer.length()].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{get_regions, ping_message};
+
+    #[test]
+    fn test_decode_get_regions_request() {
+        let message = decode_message(&get_regions()).unwrap();
+
+        assert_eq!(message.message_type, EgMessageType::Operation);
+        assert_eq!(message.operation_code, Some(220));
+        assert_eq!(message.operation.as_deref(), Some("GetRegions"));
+        assert_eq!(message.parameters.len(), 1);
+        assert_eq!(message.parameters[0].name.as_deref(), Some("ApplicationId"));
+    }
+
+    #[test]
+    fn test_decode_names_ping_parameters() {
+        let message = decode_message(&ping_message(0)).unwrap();
+
+        assert_eq!(message.message_type, EgMessageType::InternalOperationRequest);
+        assert_eq!(message.operation.as_deref(), Some("Ping"));
+        assert_eq!(message.parameters[0].name.as_deref(), Some("ClientTimestamp"));
+        assert_eq!(message.parameters[0].gp_type, Some(GpType::IntZero));
+
+        let message = decode_message(&ping_message(100_000)).unwrap();
+        assert_eq!(message.parameters[0].gp_type, Some(GpType::CompressedInt));
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let json = r#"{
+            "message_type": "OperationResponse",
+            "operation_code": 220,
+            "return_code": 0,
+            "parameters": [
+                {"code": 210, "type": "StringArray", "value": ["eu", "us"]},
+                {"code": 230, "name": "Address", "type": "StringArray", "value": ["wss://eu", "wss://us"]}
+            ]
+        }"#;
+        let message: DecodedMessage = serde_json::from_str(json).unwrap();
+
+        let bytes = encode_message(&message).unwrap();
+        assert_eq!(&bytes[..3], &[243, EgMessageType::OperationResponse as u8, 220]);
+
+        let decoded = decode_message(&bytes).unwrap();
+        assert_eq!(decoded.operation.as_deref(), Some("GetRegions"));
+        assert_eq!(decoded.parameters[0].name.as_deref(), Some("Region"));
+        assert_eq!(decoded.parameters[1].value, message.parameters[1].value);
+        let json = serde_json::to_value(&decoded).unwrap();
+        assert_eq!(json["parameters"][0]["type"], "StringArray");
+    }
+
+    #[test]
+    fn test_disconnect_round_trip() {
+        let message = DecodedMessage {
+            disconnect_code: Some(32767),
+            debug_message: Some("bad app".to_string()),
+            ..DecodedMessage::new(EgMessageType::DisconnectReason)
+        };
+
+        assert_eq!(decode_message(&encode_message(&message).unwrap()).unwrap(), message);
+    }
+
+    #[test]
+    fn test_decode_errors() {
+        assert_eq!(decode_message(&[243]), Err(DecodeError::Truncated));
+        assert_eq!(decode_message(&[1, 2, 3]), Err(DecodeError::NotPhoton(1)));
+        assert_eq!(decode_message(&[243, 0x83]), Err(DecodeError::Encrypted));
+        assert!(matches!(decode_message(&[243, EgMessageType::OperationResponse as u8, 220]), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_encode_requires_operation_code() {
+        let message = DecodedMessage::new(EgMessageType::Operation);
+        assert!(matches!(encode_message(&message), Err(DecodeError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_encode_rejects_unwritable_values() {
+        let message = DecodedMessage {
+            operation_code: Some(1),
+            parameters: vec![DecodedParameter { code: 3, name: None, gp_type: None, value: Value::Double(1.5) }],
+            ..DecodedMessage::new(EgMessageType::Operation)
+        };
+        assert!(matches!(encode_message(&message), Err(DecodeError::Unsupported(_))));
+    }
+}
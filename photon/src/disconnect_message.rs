@@ -0,0 +1,8 @@
+use crate::parameter_dictionary::ParameterDictionary;
+
+#[derive(Debug)]
+pub struct DisconnectMessage {
+    pub code: i16,
+    pub debug_message: Option<String>,
+    pub parameters: ParameterDictionary
+}
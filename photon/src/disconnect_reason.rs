@@ -0,0 +1,149 @@
+use std::fmt;
+use crate::disconnect_message::DisconnectMessage;
+use crate::transport::TransportError;
+
+/// Why the connection to Photon ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The server didn't hear from us in time
+    ServerTimeout,
+    /// We didn't hear from the server in time
+    ClientTimeout,
+    /// The server is at its user limit
+    ServerFull,
+    /// The server disconnected us because of its own logic
+    ServerLogic,
+    /// The server disconnected us without telling us why
+    ServerReasonUnknown,
+    /// The AppId or authentication values were rejected
+    InvalidAuth,
+    /// Custom authentication was configured and failed
+    CustomAuthenticationFailed,
+    /// The authentication ticket expired
+    AuthenticationTicketExpired,
+    /// The application's peak CCU for its subscription was reached
+    MaxCcuReached,
+    /// The region isn't available for the application
+    InvalidRegion,
+    /// We sent more operations than the server allows
+    OperationLimitReached,
+    /// We closed the connection ourselves
+    ClientDisconnect,
+    /// The connection went away without a disconnect message
+    ConnectionClosed,
+    /// The connection could not be established
+    ConnectFailed,
+    /// Sending or receiving failed
+    TransportFailure,
+    /// Handling a message from the server failed unexpectedly
+    DispatchFailure,
+    /// A disconnect code we don't know
+    Unknown(i16),
+}
+
+impl DisconnectReason {
+    /// Maps the code of a DisconnectReason message. Photon uses the same values as PUN's
+    /// `StatusCode` for transport level reasons and its `ErrorCode` for authentication ones.
+    pub fn from_code(code: i16) -> Self {
+        match code {
+            1040 => DisconnectReason::ClientTimeout,
+            1041 => DisconnectReason::ServerTimeout,
+            1042 => DisconnectReason::ServerFull,
+            1043 => DisconnectReason::ServerLogic,
+            1044 => DisconnectReason::ServerReasonUnknown,
+            32767 => DisconnectReason::InvalidAuth,
+            32757 => DisconnectReason::MaxCcuReached,
+            32756 => DisconnectReason::InvalidRegion,
+            32755 => DisconnectReason::CustomAuthenticationFailed,
+            32753 => DisconnectReason::AuthenticationTicketExpired,
+            32743 => DisconnectReason::OperationLimitReached,
+            _ => DisconnectReason::Unknown(code),
+        }
+    }
+
+    /// Whether reconnecting could help. Authentication and quota problems, and disconnects we
+    /// asked for, will just happen again.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self,
+            DisconnectReason::InvalidAuth
+            | DisconnectReason::CustomAuthenticationFailed
+            | DisconnectReason::AuthenticationTicketExpired
+            | DisconnectReason::MaxCcuReached
+            | DisconnectReason::InvalidRegion
+            | DisconnectReason::ClientDisconnect)
+    }
+}
+
+/// A [`DisconnectReason`] together with whatever detail came with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisconnectInfo {
+    pub reason: DisconnectReason,
+    pub debug_message: Option<String>,
+}
+
+impl DisconnectInfo {
+    pub fn new(reason: DisconnectReason, debug_message: Option<String>) -> Self {
+        DisconnectInfo { reason, debug_message }
+    }
+}
+
+impl fmt::Display for DisconnectInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.debug_message {
+            Some(message) => write!(f, "disconnected from Photon ({:?}): {}", self.reason, message),
+            None => write!(f, "disconnected from Photon ({:?})", self.reason),
+        }
+    }
+}
+
+impl std::error::Error for DisconnectInfo {}
+
+impl From<&DisconnectMessage> for DisconnectInfo {
+    fn from(message: &DisconnectMessage) -> Self {
+        DisconnectInfo::new(DisconnectReason::from_code(message.code), message.debug_message.clone())
+    }
+}
+
+impl From<TransportError> for DisconnectInfo {
+    fn from(error: TransportError) -> Self {
+        match error {
+            TransportError::Closed => DisconnectInfo::new(DisconnectReason::ConnectionClosed, None),
+            TransportError::Connect(e) => DisconnectInfo::new(DisconnectReason::ConnectFailed, Some(e)),
+            TransportError::Send(e) => DisconnectInfo::new(DisconnectReason::TransportFailure, Some(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parameter_dictionary::ParameterDictionary;
+
+    #[test]
+    fn test_from_code() {
+        assert_eq!(DisconnectReason::from_code(1041), DisconnectReason::ServerTimeout);
+        assert_eq!(DisconnectReason::from_code(32757), DisconnectReason::MaxCcuReached);
+        assert_eq!(DisconnectReason::from_code(7), DisconnectReason::Unknown(7));
+    }
+
+    #[test]
+    fn test_is_recoverable() {
+        assert!(DisconnectReason::ServerTimeout.is_recoverable());
+        assert!(DisconnectReason::ConnectionClosed.is_recoverable());
+        assert!(!DisconnectReason::InvalidAuth.is_recoverable());
+        assert!(!DisconnectReason::ClientDisconnect.is_recoverable());
+    }
+
+    #[test]
+    fn test_from_disconnect_message() {
+        let message = DisconnectMessage {
+            code: 32767,
+            debug_message: Some("Invalid AppId".to_string()),
+            parameters: ParameterDictionary::new(),
+        };
+
+        let info = DisconnectInfo::from(&message);
+        assert_eq!(info.reason, DisconnectReason::InvalidAuth);
+        assert_eq!(info.to_string(), "disconnected from Photon (InvalidAuth): Invalid AppId");
+    }
+}
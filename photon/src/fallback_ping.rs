@@ -0,0 +1,257 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+use websocket::{ClientBuilder, OwnedMessage, WebSocketError};
+use websocket::native_tls::TlsConnector;
+use websocket::stream::sync::Stream;
+use websocket::sync::Client;
+use websocket::url::Url;
+use crate::message_type::EgMessageType;
+use crate::parameter_dictionary::{ParameterDictionary, Value};
+use crate::photon_codes;
+use crate::photon_region::PhotonRegion;
+use crate::ping_stats::{PingMethod, PingStats};
+use crate::pinger::{region_host, PingError, Pinger};
+use crate::protocol_v18::deserialize_operation_response;
+use crate::stream_buffer::StreamBuffer;
+use crate::{serialize_operation_to_message, SUBPROTOCOL};
+
+/// Port used when UDP is blocked. Networks that block UDP nearly always let HTTPS through.
+const FALLBACK_PORT: u16 = 443;
+
+/// Pings a region over UDP, falling back to [`websocket_ping`] and then [`tcp_ping`] if no UDP
+/// probe is answered. The returned stats record which method produced them.
+pub fn ping_with_fallback(region: &PhotonRegion, samples: u32, timeout: Duration) -> Result<PingStats, PingError> {
+    let udp = Pinger::new(region).and_then(|mut pinger| {
+        pinger.set_timeout(timeout);
+        pinger.start_ping(samples as i32)
+    });
+
+    match udp {
+        Ok(stats) if stats.is_reachable() => Ok(stats),
+        // No point trying other ports on a host that doesn't exist
+        Err(e @ (PingError::InvalidAddress(_) | PingError::Resolve(_))) => Err(e),
+        _ => fallback_ping(region, samples, timeout),
+    }
+}
+
+/// The non-UDP part of [`ping_with_fallback`], for when UDP has already timed out
+pub fn fallback_ping(region: &PhotonRegion, samples: u32, timeout: Duration) -> Result<PingStats, PingError> {
+    match websocket_ping(region, samples, timeout) {
+        Ok(stats) if stats.is_reachable() => Ok(stats),
+        Ok(_) => tcp_ping(region, samples, timeout),
+        Err(e) => {
+            if cfg!(debug_assertions) {
+                println!("Websocket ping to {} failed, trying TCP: {}", region.short_name, e);
+            }
+            tcp_ping(region, samples, timeout)
+        }
+    }
+}
+
+/// Pings a region with Photon's internal PING operation over a wss connection on port 443
+pub fn websocket_ping(region: &PhotonRegion, samples: u32, timeout: Duration) -> Result<PingStats, PingError> {
+    let host = region_host(region)?;
+    let mut url = Url::parse(&region.address).map_err(|e| PingError::InvalidAddress(e.to_string()))?;
+    url.set_scheme("wss").map_err(|_| PingError::InvalidAddress(format!("{}: can't use wss", region.address)))?;
+    url.set_port(Some(FALLBACK_PORT)).map_err(|_| PingError::InvalidAddress(format!("{}: can't set port", region.address)))?;
+
+    let endpoint = resolve(&host)?;
+    let tcp = connect(endpoint, timeout)?;
+    let connector = TlsConnector::new().map_err(|e| PingError::WebSocket(e.to_string()))?;
+    let tls = connector.connect(&host, tcp).map_err(|e| PingError::WebSocket(e.to_string()))?;
+    let mut client = ClientBuilder::from_url(&url)
+        .add_protocol(SUBPROTOCOL)
+        .connect_on(tls)
+        .map_err(|e| PingError::WebSocket(e.to_string()))?;
+
+    let stats = ping_over_websocket(&mut client, samples, timeout);
+    let _ = client.shutdown();
+    stats.map(|stats| PingStats { endpoint: Some(endpoint), ..stats })
+}
+
+/// Measures how long it takes to open a TCP connection to the region on port 443
+pub fn tcp_ping(region: &PhotonRegion, samples: u32, timeout: Duration) -> Result<PingStats, PingError> {
+    let host = region_host(region)?;
+    let endpoint = resolve(&host)?;
+    Ok(PingStats { endpoint: Some(endpoint), ..tcp_connect_ping(endpoint, samples, timeout) })
+}
+
+fn resolve(host: &str) -> Result<SocketAddr, PingError> {
+    (host, FALLBACK_PORT)
+        .to_socket_addrs()
+        .map_err(|e| PingError::Resolve(format!("{}: {}", host, e)))?
+        .next()
+        .ok_or_else(|| PingError::Resolve(format!("{}: no addresses", host)))
+}
+
+fn connect(endpoint: SocketAddr, timeout: Duration) -> Result<TcpStream, PingError> {
+    let stream = TcpStream::connect_timeout(&endpoint, timeout)?;
+    // Bounds every read and write of the handshakes and pings that follow
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn tcp_connect_ping(endpoint: SocketAddr, samples: u32, timeout: Duration) -> PingStats {
+    let mut measured = Vec::with_capacity(samples as usize);
+    // The first connection is the warm-up and is discarded like the UDP one
+    for probe in 0..=samples {
+        let start = Instant::now();
+        // A refused connection also takes one round trip, but it may come from a firewall much
+        // closer than the region, so only completed handshakes count
+        if TcpStream::connect_timeout(&endpoint, timeout).is_ok() && probe > 0 {
+            measured.push(start.elapsed());
+        }
+    }
+    PingStats::from_method_samples(PingMethod::TcpConnect, &measured, samples)
+}
+
+/// Waits for Photon's init response, then sends internal PINGs one at a time. The server echoes
+/// parameter 1, which carries a sequence number so late answers can't be mistaken for the current one.
+fn ping_over_websocket<S: Stream>(client: &mut Client<S>, samples: u32, timeout: Duration) -> Result<PingStats, PingError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match receive_frame(client)? {
+            Some(frame) if frame.len() >= 2 && frame[1] == EgMessageType::InitResponse as u8 => break,
+            _ if Instant::now() >= deadline => return Err(PingError::WebSocket("no init response".to_string())),
+            _ => {}
+        }
+    }
+
+    let mut measured = Vec::with_capacity(samples as usize);
+    for sequence in 0..=samples as i32 {
+        match ping_once(client, sequence, timeout)? {
+            Some(sample) if sequence > 0 => measured.push(sample),
+            Some(_) => {}
+            // A read timed out, possibly in the middle of a frame, so the connection can't be trusted
+            None => break,
+        }
+    }
+    Ok(PingStats::from_method_samples(PingMethod::WebSocket, &measured, samples))
+}
+
+fn ping_once<S: Stream>(client: &mut Client<S>, sequence: i32, timeout: Duration) -> Result<Option<Duration>, PingError> {
+    let mut parameters = ParameterDictionary::new();
+    parameters.set(1, Value::Int(sequence));
+    let ping = serialize_operation_to_message(photon_codes::PING, parameters, EgMessageType::InternalOperationRequest);
+
+    let start = Instant::now();
+    client.send_message(&OwnedMessage::Binary(ping)).map_err(|e| PingError::WebSocket(e.to_string()))?;
+
+    while start.elapsed() < timeout {
+        let frame = match receive_frame(client)? {
+            Some(frame) => frame,
+            None => return Ok(None),
+        };
+        if frame.len() < 3 || frame[0] != 243 || frame[1] != EgMessageType::InternalOperationResponse as u8 {
+            continue;
+        }
+
+        let Ok(response) = deserialize_operation_response(&mut StreamBuffer::new(&frame[2..])) else {
+            continue;
+        };
+        if response.operation_code == photon_codes::PING && matches!(response.payload.get(1), Some(Value::Int(echo)) if *echo == sequence) {
+            return Ok(Some(start.elapsed()));
+        }
+    }
+    Ok(None)
+}
+
+/// The next binary frame, `None` if the read timed out
+fn receive_frame<S: Stream>(client: &mut Client<S>) -> Result<Option<Vec<u8>>, PingError> {
+    match client.recv_message() {
+        Ok(OwnedMessage::Binary(frame)) => Ok(Some(frame)),
+        Ok(OwnedMessage::Close(_)) => Err(PingError::WebSocket("connection closed".to_string())),
+        Ok(_) => Ok(Some(Vec::new())),
+        Err(WebSocketError::IoError(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(PingError::WebSocket(e.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use websocket::sync::Server;
+    use crate::operation_response::OperationResponse;
+    use crate::protocol_v18::{deserialize_operation_request, serialize_operation_response};
+
+    /// A websocket server that sends Photon's init response and answers PINGs, except the
+    /// sequence numbers in `ignore`
+    fn photon_server(ignore: Vec<i32>) -> SocketAddr {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        thread::spawn(move || {
+            let request = server.accept().ok().unwrap();
+            let mut client = request.use_protocol(SUBPROTOCOL).accept().unwrap();
+            client.send_message(&OwnedMessage::Binary(vec![243, EgMessageType::InitResponse as u8])).unwrap();
+
+            while let Ok(OwnedMessage::Binary(frame)) = client.recv_message() {
+                let request = deserialize_operation_request(&mut StreamBuffer::new(&frame[2..])).unwrap();
+                let sequence = request.parameters.get(1).cloned().unwrap();
+                if matches!(sequence, Value::Int(sequence) if ignore.contains(&sequence)) {
+                    continue;
+                }
+
+                let mut payload = ParameterDictionary::new();
+                payload.set(1, sequence);
+                payload.set(2, Value::Int(1000));
+                let response = OperationResponse { operation_code: photon_codes::PING, return_code: 0, debug_message: None, payload };
+                let mut buffer = StreamBuffer::with_capacity(0);
+                buffer.write(&[243, EgMessageType::InternalOperationResponse as u8]);
+                serialize_operation_response(&mut buffer, &response, false).unwrap();
+                if client.send_message(&OwnedMessage::Binary(buffer.get_buffer()[..buffer.length()].to_vec())).is_err() {
+                    break;
+                }
+            }
+        });
+        address
+    }
+
+    fn connect_plain(address: SocketAddr, timeout: Duration) -> Client<TcpStream> {
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(timeout)).unwrap();
+        ClientBuilder::new(&format!("ws://{}", address)).unwrap()
+            .add_protocol(SUBPROTOCOL)
+            .connect_on(stream)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_websocket_ping() {
+        let timeout = Duration::from_secs(1);
+        let mut client = connect_plain(photon_server(Vec::new()), timeout);
+
+        let stats = ping_over_websocket(&mut client, 4, timeout).unwrap();
+
+        assert_eq!(stats.method, PingMethod::WebSocket);
+        assert_eq!(stats.sent, 4);
+        assert_eq!(stats.received, 4);
+    }
+
+    #[test]
+    fn test_websocket_ping_stops_after_timeout() {
+        let timeout = Duration::from_millis(100);
+        let mut client = connect_plain(photon_server(vec![2]), timeout);
+
+        let stats = ping_over_websocket(&mut client, 4, timeout).unwrap();
+
+        // Sample 1 was answered, 2 wasn't, and 3 and 4 were never sent
+        assert_eq!(stats.received, 1);
+        assert_eq!(stats.loss_percent(), 75.0);
+    }
+
+    #[test]
+    fn test_tcp_connect_ping() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let stats = tcp_connect_ping(address, 3, Duration::from_secs(1));
+
+        assert_eq!(stats.method, PingMethod::TcpConnect);
+        assert_eq!(stats.received, 3);
+    }
+}
@@ -1,8 +1,10 @@
 use num_enum::{TryFromPrimitive, IntoPrimitive};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, TryFromPrimitive, IntoPrimitive, Copy, Clone)]
+/// The type tag in front of each value on the wire, as in GpBinaryV18
+#[derive(Debug, TryFromPrimitive, IntoPrimitive, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
-pub(crate) enum GpType {
+pub enum GpType {
     Unknown = 0,
     Boolean = 2,
     Byte = 3,
@@ -1,19 +1,42 @@
-use crossbeam_channel::{unbounded, Sender, Receiver};
-use std::net::TcpStream;
+use crossbeam_channel::{unbounded, Sender, Receiver, RecvTimeoutError};
 use std::string::ToString;
-use std::sync::{Mutex, OnceLock};
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
-use std::time::Instant;
-use websocket::{ClientBuilder, OwnedMessage};
-use websocket::sync::{Writer};
+use std::time::{Duration, Instant};
 use once_cell::sync::Lazy;
-use crate::message_type::EgMessageType;
 use crate::parameter_codes::{ADDRESS, REGION};
-use crate::parameter_dictionary::{ParameterDictionary, Value};
+use crate::parameter_dictionary::ParameterDictionary;
 use crate::parameter_dictionary::Value::Int;
+pub use crate::best_region::{BestRegion, RegionSelection, RegionSummary};
+pub use crate::capture::{from_hex, read_capture, replay, to_hex, CaptureTransport, CaptureWriter, CapturedFrame, Direction, ReplayError, ReplayReport};
+pub use crate::connection_event::ConnectionEvent;
+pub use crate::decode::{decode_message, encode_message, operation_name, parameter_name, DecodeError, DecodedMessage, DecodedParameter};
+pub use crate::disconnect_reason::{DisconnectInfo, DisconnectReason};
+pub use crate::gp_type::GpType;
+pub use crate::mock_name_server::{MockNameServer, MockNameServerOptions};
+pub use crate::message_type::EgMessageType;
+pub use crate::multi_pinger::{ping_regions, SweepOptions};
+pub use crate::network_simulation::{NetworkSimulationSet, NetworkSimulationStats, NetworkSimulator, SimulatedTransport, SimulationSettings, SimulationStats};
+pub use crate::parameter_dictionary::Value;
 pub use crate::photon_region::PhotonRegion;
-pub use crate::pinger::Pinger;
-use crate::protocol_v18::{deserialize_operation_response, serialize_operation_request};
+pub use crate::pinger::{ping_each_endpoint, EndpointPing, ping_port, resolve_ping_endpoints, PingEndpointOptions, PingError, Pinger, PING_PORT};
+pub use crate::ping_responder::{PingResponder, PingResponderOptions, PingResponderStats};
+pub use crate::ping_stats::{PingMethod, PingStats};
+pub use crate::fallback_ping::{fallback_ping, ping_with_fallback, tcp_ping, websocket_ping};
+pub use crate::proxy::{Proxy, ProxyAction, ProxyLogEntry, ProxyOptions, ProxyRule, Rewrite, RuleAction};
+pub use crate::reconnect_policy::ReconnectPolicy;
+pub use crate::region_monitor::{MonitorEvent, MonitorOptions, RegionHistory, RegionMonitor, RegionSample, RegionTrend};
+pub use crate::region_cache::{CachedRegions, RegionCache, RegionSource};
+pub use crate::photon_clock::PhotonClock;
+pub use crate::round_trip_time::RoundTripStats;
+pub use crate::traffic_stats::{TrafficRecorder, TrafficStats, TrafficTransport};
+pub use crate::transport::{MemoryTransport, Transport, TransportError, WebSocketTransport};
+use crate::protocol_v18::{deserialize_disconnect_message, deserialize_operation_response, serialize_operation_request};
+use crate::round_trip_time::{local_timestamp, timestamp_diff, RoundTripTime};
 use crate::stream_buffer::StreamBuffer;
 
 mod protocol_v18;
@@ -21,42 +44,259 @@ mod stream_buffer;
 mod parameter_dictionary;
 mod photon_codes;
 mod message_type;
+mod operation_request;
 mod operation_response;
 mod parameter_codes;
 mod pinger;
+mod ping_stats;
+mod multi_pinger;
+mod fallback_ping;
 mod gp_type;
 mod photon_region;
+mod transport;
+mod round_trip_time;
+mod photon_clock;
+mod disconnect_message;
+mod disconnect_reason;
+mod reconnect_policy;
+mod connection_event;
+mod region_cache;
+mod best_region;
+mod region_monitor;
+mod mock_name_server;
+mod capture;
+mod decode;
+mod proxy;
+mod traffic_stats;
+mod network_simulation;
+mod ping_responder;
 
 const MESSAGE_HEADER: [u8; 2] = [243, 2];
-static START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
-static SUBSCRIBERS: OnceLock<Mutex<Vec<Sender<Vec<PhotonRegion>>>>> = OnceLock::new();
-static START_WORKER: OnceLock<()> = OnceLock::new();
+static ROUND_TRIP_TIME: Mutex<RoundTripTime> = Mutex::new(RoundTripTime::new());
+static CLOCK_SERVER: Mutex<Option<String>> = Mutex::new(None);
+static LAST_REGIONS: Mutex<Option<Vec<PhotonRegion>>> = Mutex::new(None);
+static LAST_DISCONNECT: Mutex<Option<DisconnectInfo>> = Mutex::new(None);
+static SUBSCRIBERS: OnceLock<Mutex<Vec<Sender<RegionsResult>>>> = OnceLock::new();
+static EVENT_SUBSCRIBERS: Mutex<Vec<Sender<ConnectionEvent>>> = Mutex::new(Vec::new());
+static WORKER_RUNNING: Mutex<bool> = Mutex::new(false);
+static RECONNECT_POLICY: Lazy<Mutex<ReconnectPolicy>> = Lazy::new(|| Mutex::new(ReconnectPolicy::default()));
+static CAPTURE: Mutex<Option<Arc<Mutex<CaptureWriter>>>> = Mutex::new(None);
+static NETWORK_SIMULATOR: Mutex<Option<NetworkSimulator>> = Mutex::new(None);
+static TRAFFIC: Lazy<Arc<Mutex<TrafficRecorder>>> = Lazy::new(|| Arc::new(Mutex::new(TrafficRecorder::default())));
+static CONNECTION_COUNT: AtomicU64 = AtomicU64::new(0);
+static NAME_SERVER_OVERRIDE: Mutex<Option<String>> = Mutex::new(None);
+static COMMANDS: Lazy<(Sender<WorkerCommand>, Receiver<WorkerCommand>)> = Lazy::new(unbounded);
 const APP_ID: &str = "0d501af7-d643-47dd-811a-cfc25ef543be";
+const NAME_SERVER_ADDRESS: &str = "wss://ns.photonengine.io:80";
+const SUBPROTOCOL: &str = "GpBinaryV18";
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_millis(1000);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_millis(10_000);
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
-#[inline]
-fn millis_since_start() -> u64 {
-    START_TIME.elapsed().as_millis() as u64
+type RegionsResult = Result<Vec<PhotonRegion>, DisconnectInfo>;
+
+/// Requests from the public API to the worker thread, which owns the transport
+enum WorkerCommand {
+    Disconnect,
+}
+
+/// The current round trip statistics of the name server connection, once a ping has completed
+pub fn round_trip_stats() -> Option<RoundTripStats> {
+    ROUND_TRIP_TIME.lock().unwrap().stats()
+}
+
+/// Milliseconds between the local Photon timestamp and the server's `ServerTimestamp`
+pub fn server_time_offset() -> Option<i32> {
+    ROUND_TRIP_TIME.lock().unwrap().server_time_offset()
+}
+
+/// The estimated current Photon `ServerTimestamp`, in wrapping milliseconds
+pub fn server_timestamp() -> Option<i32> {
+    ROUND_TRIP_TIME.lock().unwrap().server_timestamp(local_timestamp())
+}
+
+/// A snapshot of the Photon server clock, once the connection has a server time offset. Only
+/// clocks with the same [`PhotonClock::server`] share a time base.
+pub fn clock() -> Option<PhotonClock> {
+    let round_trip_time = ROUND_TRIP_TIME.lock().unwrap();
+    let offset = round_trip_time.server_time_offset()?;
+    let sample_round_trip_time = round_trip_time.offset_round_trip_time()?;
+    let server = CLOCK_SERVER.lock().unwrap().clone()?;
+    Some(PhotonClock::from_offset(offset, sample_round_trip_time, &server))
+}
+
+/// Starts the background connection to the name server, if it isn't running yet
+pub fn start_connection() {
+    let mut running = WORKER_RUNNING.lock().unwrap();
+    if !*running {
+        *running = true;
+        // Drop disconnect requests that were meant for a previous worker
+        while COMMANDS.1.try_recv().is_ok() {}
+        thread::spawn(photon_worker);
+    }
+}
+
+/// Closes the name server connection gracefully. Pending requests fail with
+/// [`DisconnectReason::ClientDisconnect`] and no reconnect is attempted.
+pub fn disconnect() {
+    let _ = COMMANDS.0.send(WorkerCommand::Disconnect);
+}
+
+/// Why the name server connection is down, if it is
+pub fn last_disconnect() -> Option<DisconnectInfo> {
+    LAST_DISCONNECT.lock().unwrap().clone()
+}
+
+/// Sets how the connection recovers from drops. Takes effect from the next disconnect.
+pub fn set_reconnect_policy(policy: ReconnectPolicy) {
+    *RECONNECT_POLICY.lock().unwrap() = policy;
+}
+
+pub fn reconnect_policy() -> ReconnectPolicy {
+    RECONNECT_POLICY.lock().unwrap().clone()
+}
+
+/// Points the connection at another name server, such as a [`MockNameServer`]. Takes effect from
+/// the next connect, so call it before [`start_connection`].
+pub fn set_name_server_address(address: &str) {
+    *NAME_SERVER_OVERRIDE.lock().unwrap() = Some(address.to_string());
+}
+
+/// The name server the connection uses, Photon's public one unless it was overridden
+pub fn name_server_address() -> String {
+    NAME_SERVER_OVERRIDE.lock().unwrap().clone().unwrap_or_else(|| NAME_SERVER_ADDRESS.to_string())
+}
+
+/// Traffic on the current name server connection, or the last one if it is down
+pub fn traffic_stats() -> TrafficStats {
+    TRAFFIC.lock().unwrap().snapshot()
+}
+
+/// Starts the traffic numbers over, e.g. to measure one phase of a session
+pub fn reset_traffic_stats() {
+    TRAFFIC.lock().unwrap().reset();
+}
+
+/// Records every frame to and from the name server in `path`, appending if it exists. Takes
+/// effect from the next connect. See [`replay`] for playing a capture back.
+pub fn start_capture<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let writer = CaptureWriter::open(path)?;
+    *CAPTURE.lock().unwrap() = Some(Arc::new(Mutex::new(writer)));
+    Ok(())
+}
+
+/// Stops recording frames, from the next connect
+pub fn stop_capture() {
+    *CAPTURE.lock().unwrap() = None;
+}
+
+/// Runs the name server connection through `simulator`, from the next connect. Its settings can
+/// be changed at any time through the handle, so pass a default one to start from a clean network.
+pub fn simulate_network(simulator: &NetworkSimulator) {
+    *NETWORK_SIMULATOR.lock().unwrap() = Some(simulator.clone());
+}
+
+/// Stops simulating network conditions, from the next connect
+pub fn stop_network_simulation() {
+    *NETWORK_SIMULATOR.lock().unwrap() = None;
+}
+
+/// Subscribes to connection state changes, so applications know when they are offline
+pub fn subscribe_connection_events() -> Receiver<ConnectionEvent> {
+    let (tx, rx) = unbounded();
+    EVENT_SUBSCRIBERS.lock().unwrap().push(tx);
+    rx
+}
+
+fn emit_connection_event(event: ConnectionEvent) {
+    // Forget subscribers that have gone away
+    EVENT_SUBSCRIBERS.lock().unwrap().retain(|s| s.send(event.clone()).is_ok());
+}
+
+pub fn get_regions_async() -> Result<Vec<PhotonRegion>, DisconnectInfo> {
+    wait_for_regions(None)
 }
 
-pub fn get_regions_async() -> Vec<PhotonRegion> {
-    subscribe().recv().expect("Region fetcher failed before returning cleanly")
+/// Like [`get_regions_async`], but gives up with [`DisconnectReason::ClientTimeout`] if the
+/// name server hasn't answered within `timeout`
+pub fn get_regions_timeout(timeout: Duration) -> Result<Vec<PhotonRegion>, DisconnectInfo> {
+    wait_for_regions(Some(timeout))
+}
+
+/// The region list through `cache`: the cached copy while it is within its TTL, otherwise a fresh
+/// one from the name server, falling back to the last known list if that fails
+pub fn get_regions_cached(cache: &RegionCache, timeout: Duration) -> Result<CachedRegions, DisconnectInfo> {
+    cache.get_or_fetch(|| get_regions_timeout(timeout))
+}
+
+fn wait_for_regions(timeout: Option<Duration>) -> Result<Vec<PhotonRegion>, DisconnectInfo> {
+    // The worker only fetches the list once per connection, so later callers get that copy.
+    // Holding the lock while subscribing means a broadcast can't slip in between.
+    let last_regions = LAST_REGIONS.lock().unwrap();
+    if let Some(regions) = last_regions.as_ref() {
+        return Ok(regions.clone());
+    }
+    // Between reconnect attempts, only wait if the request will be re-issued on the next connection
+    if *WORKER_RUNNING.lock().unwrap()
+        && !RECONNECT_POLICY.lock().unwrap().rejoin
+        && let Some(info) = last_disconnect() {
+        return Err(info);
+    }
+    let receiver = subscribe();
+    drop(last_regions);
+
+    let closed = DisconnectInfo::new(DisconnectReason::ConnectionClosed, None);
+    match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(DisconnectInfo::new(
+                DisconnectReason::ClientTimeout,
+                Some(format!("No region list within {:?}", timeout))
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(closed),
+        },
+        None => receiver.recv().unwrap_or(Err(closed)),
+    }
 }
 
-fn broadcast_regions(regions: &Vec<PhotonRegion>) {
+fn broadcast_regions(regions: &[PhotonRegion]) {
+    *LAST_REGIONS.lock().unwrap() = Some(regions.to_vec());
+
     if let Some(mutex) = SUBSCRIBERS.get() {
-        let subscribers = mutex.lock().unwrap();
-        for s in subscribers.iter() {
+        for s in mutex.lock().unwrap().drain(..) {
             // Ignore failures (e.g., if the receiver was dropped).
-            let _ = s.send(regions.to_vec());
+            let _ = s.send(Ok(regions.to_vec()));
+        }
+    }
+}
+
+/// Records why the connection dropped. Everyone still waiting on it is failed, unless
+/// `fail_pending` is false because the next connection will serve them.
+fn broadcast_disconnect(info: &DisconnectInfo, fail_pending: bool) {
+    // Same lock order as get_regions_async, so nobody can subscribe after we've drained
+    let _last_regions = LAST_REGIONS.lock().unwrap();
+    *LAST_DISCONNECT.lock().unwrap() = Some(info.clone());
+
+    if fail_pending && let Some(mutex) = SUBSCRIBERS.get() {
+        for s in mutex.lock().unwrap().drain(..) {
+            let _ = s.send(Err(info.clone()));
         }
     }
 }
 
-fn subscribe() -> Receiver<Vec<PhotonRegion>> { 
+/// Marks the worker as gone for good after `info`, failing everyone still waiting on it
+fn stop_worker(info: &DisconnectInfo) {
+    let last_regions = LAST_REGIONS.lock().unwrap();
+    *WORKER_RUNNING.lock().unwrap() = false;
+    drop(last_regions);
+
+    broadcast_disconnect(info, true);
+    emit_connection_event(ConnectionEvent::GaveUp(info.clone()));
+}
+
+fn subscribe() -> Receiver<RegionsResult> { 
     let subs_mutex = SUBSCRIBERS.get_or_init(|| Mutex::new(Vec::new()));
-    START_WORKER.get_or_init(|| {
-        thread::spawn(photon_worker);
-    });
+    start_connection();
     
     let (tx, rx) = unbounded();
     subs_mutex.lock().unwrap().push(tx);
@@ -66,7 +306,8 @@ fn subscribe() -> Receiver<Vec<PhotonRegion>> {
 fn serialize_operation_to_message(opcode: u8, param_dict: ParameterDictionary, message_type: EgMessageType) -> Vec<u8> {
     let mut buffer = StreamBuffer::with_capacity(0);
     buffer.write(&MESSAGE_HEADER);
-    serialize_operation_request(&mut buffer, opcode, param_dict, false);
+    serialize_operation_request(&mut buffer, opcode, param_dict, false)
+        .expect("our own requests only carry types we can write");
 
     // Now, for some reason, photon just decides that we need to replace the second byte
     let buffer_ref = buffer.get_buffer();
@@ -81,33 +322,43 @@ fn serialize_operation_to_message(opcode: u8, param_dict: ParameterDictionary, m
     raw_buffer
 }
 
-fn init_callback() -> Vec<u8> {
+/// An internal PING stamped with `now`, our Photon timestamp
+fn ping_message(now: i32) -> Vec<u8> {
     // AKA SendPing
-    println!("Initializing callback");
     let mut ping_param_dict = ParameterDictionary::new();
-    ping_param_dict.set(1, Int(millis_since_start() as i32));
+    ping_param_dict.set(1, Int(now));
 
     serialize_operation_to_message(photon_codes::PING, ping_param_dict, EgMessageType::InternalOperationRequest)
 }
 
-fn read_ping_result(operation_response: &operation_response::OperationResponse) {
+fn init_callback(now: i32) -> Vec<u8> {
+    println!("Initializing callback");
+    ping_message(now)
+}
+
+fn read_ping_result(operation_response: &operation_response::OperationResponse, context: &mut DispatchContext) -> Dispatched {
     let server_timestamp = match operation_response.payload.get(2) {
-        Some(Int(num)) => num,
+        Some(Int(num)) => *num,
         _ => {
             println!("No ping result received");
-            return
+            return Dispatched::Nothing
         }
     };
     let last_timestamp =  match operation_response.payload.get(1) {
-        Some(Int(num)) => *num as u64,
+        Some(Int(num)) => *num,
         _ => {
             println!("No ping result received");
-            return
+            return Dispatched::Nothing
         }
     };
 
-    let last_round_trip_time = millis_since_start().saturating_sub(last_timestamp);
-    println!("Ping result: {}ms. Server timestamp: {}", last_round_trip_time, server_timestamp);
+    context.round_trip_time.add_sample(last_timestamp, server_timestamp, context.now);
+    if cfg!(debug_assertions) {
+        println!("Ping result: {:?}. Server timestamp: {}", context.round_trip_time.stats(), server_timestamp);
+    }
+
+    let rtt = timestamp_diff(context.now, last_timestamp);
+    if rtt >= 0 { Dispatched::PingResult(rtt) } else { Dispatched::Nothing }
 }
 
 fn get_regions() -> Vec<u8> {
@@ -116,11 +367,39 @@ fn get_regions() -> Vec<u8> {
     serialize_operation_to_message(220, parameters, EgMessageType::Operation)
 }
 
-fn deserialize_message_and_callback(stream: &mut StreamBuffer, sender: &mut Writer<TcpStream>) {
+/// What the dispatcher works with besides the message. The worker keeps one per connection and
+/// publishes what it learns; a replay starts from a fresh one, so it leaves the live state alone.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DispatchContext {
+    pub(crate) round_trip_time: RoundTripTime,
+    /// Our Photon timestamp when the message arrived
+    pub(crate) now: i32,
+}
+
+/// What a message told the dispatcher, for the caller to act on
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Dispatched {
+    Nothing,
+    /// The name server's region list
+    Regions(Vec<PhotonRegion>),
+    /// A PING result went into the context's round trip time, measuring this many milliseconds
+    PingResult(i32),
+    /// The message couldn't be read, and was ignored
+    Malformed(DecodeError),
+}
+
+/// Handles one message from the server against `context`, sending any replies on `transport`.
+/// Returns `Err` when the connection has to end.
+pub(crate) fn deserialize_message_and_callback(stream: &mut StreamBuffer, transport: &mut dyn Transport, context: &mut DispatchContext) -> Result<Dispatched, DisconnectInfo> {
+    if stream.remaining() < 2 {
+        println!("Ignoring truncated message of {} bytes", stream.remaining());
+        return Ok(Dispatched::Nothing);
+    }
+
     let b = stream.read_byte();
     if b != 243 && b != 253 {
         // No regular operation UDP message
-        return;
+        return Ok(Dispatched::Nothing);
     }
 
     let b2 = stream.read_byte();
@@ -130,8 +409,9 @@ fn deserialize_message_and_callback(stream: &mut StreamBuffer, sender: &mut Writ
     // Handle encryption
     if b3 != 1 {
         if flag {
-            // Throw as we have no implementation of decryption
-            panic!("Decryption not implemented.")
+            // We have no implementation of decryption, and never ask for encryption either
+            println!("Ignoring encrypted message of type {}", b3);
+            return Ok(Dispatched::Nothing);
         }
         else {
             stream.seek(2);
@@ -142,115 +422,403 @@ fn deserialize_message_and_callback(stream: &mut StreamBuffer, sender: &mut Writ
     match b3 {
         1 => {
             // Initial Callback
-            sender.send_message(&OwnedMessage::Binary(init_callback())).unwrap();
-            sender.send_message(&OwnedMessage::Binary(get_regions())).unwrap();
+            transport.send_frame(&init_callback(context.now))?;
+            transport.send_frame(&get_regions())?;
         }
         7 => {
             // Operation response
-            let operation_response = deserialize_operation_response(stream);
+            let operation_response = match deserialize_operation_response(stream) {
+                Ok(response) => response,
+                Err(e) => return Ok(malformed(b3, e)),
+            };
             if operation_response.return_code != 0 {
                 println!("Operation with opcode {} failed with msg {:?}", operation_response.operation_code, operation_response.debug_message);
             }
             
-            println!("Operation Response: {:?}", operation_response);
-            match operation_response.operation_code {
-                photon_codes::PING => {
-                    read_ping_result(&operation_response);
-                }
-                _ => {}
+            if operation_response.operation_code == photon_codes::PING {
+                return Ok(read_ping_result(&operation_response, context));
+            } else {
+                println!("Operation Response: {:?}", operation_response);
             }
         }
         3 => {
-            let op_res = protocol_v18::deserialize_operation_response(stream);
+            let op_res = match deserialize_operation_response(stream) {
+                Ok(response) => response,
+                Err(e) => return Ok(malformed(b3, e)),
+            };
             if op_res.return_code != 0 {
                 println!("Operation failed: {:?}", op_res);
-                return;
+                return Ok(Dispatched::Nothing);
             }
-            match op_res.operation_code {
-                220 => {
-                    let region_shortnames = match op_res.payload.get(REGION) {
-                        Some(Value::StringArray(regions)) => regions,
-                        _ => {
-                            println!("No regions received");
-                            return
-                        }
-                    };
-                    let addresses = match op_res.payload.get(ADDRESS) {
-                        Some(Value::StringArray(addresses)) => addresses,
-                        _ => {
-                            println!("No addresses received");
-                            return
-                        }
-                    };
-                    
-                    // For each region, construct a new PhotonRegion and then broadcast back to subscribers
-                    let mut regions: Vec<PhotonRegion> = Vec::new();
-                    for i in 0..region_shortnames.len() {
-                        regions.push(PhotonRegion{
-                            short_name: region_shortnames[i].clone(),
-                            address: addresses[i].clone()
-                        })
+            if op_res.operation_code == 220 {
+                let region_shortnames = match op_res.payload.get(REGION) {
+                    Some(Value::StringArray(regions)) => regions,
+                    _ => {
+                        println!("No regions received");
+                        return Ok(Dispatched::Nothing)
                     }
-                    broadcast_regions(&regions);
-                }
-                _ => {}
+                };
+                let addresses = match op_res.payload.get(ADDRESS) {
+                    Some(Value::StringArray(addresses)) => addresses,
+                    _ => {
+                        println!("No addresses received");
+                        return Ok(Dispatched::Nothing)
+                    }
+                };
+
+                // For each region, construct a new PhotonRegion for the caller to hand out
+                let regions: Vec<PhotonRegion> = region_shortnames.iter()
+                    .zip(addresses.iter())
+                    .map(|(short_name, address)| PhotonRegion {
+                        short_name: short_name.clone(),
+                        address: address.clone()
+                    })
+                    .collect();
+                println!("Operation Response: {:?}", op_res);
+                return Ok(Dispatched::Regions(regions));
             }
             println!("Operation Response: {:?}", op_res);
         }
         5 => {
             // Disconnect
-            println!("Disconnect");
+            // The server closes the connection after this anyway, so an unreadable one isn't the end
+            let message = match deserialize_disconnect_message(stream) {
+                Ok(message) => message,
+                Err(e) => return Ok(malformed(b3, e)),
+            };
+            println!("Disconnect: {:?}", message);
+            return Err(DisconnectInfo::from(&message));
+        }
+        _ => {
+            // Events and the like, which nothing on the name server connection needs
+            println!("Ignoring message of type {}", b3);
+        }
+    }
+
+    Ok(Dispatched::Nothing)
+}
+
+fn malformed(message_type: u8, error: DecodeError) -> Dispatched {
+    println!("Ignoring unreadable message of type {}: {}", message_type, error);
+    Dispatched::Malformed(error)
+}
+
+/// Reads frames from `transport` and dispatches them until the connection goes away, sending an
+/// internal PING every [`KEEP_ALIVE_INTERVAL`] to keep the connection and the RTT estimate fresh
+fn run_worker(transport: &mut dyn Transport, commands: &Receiver<WorkerCommand>) -> DisconnectInfo {
+    // Pings only make sense once the server has answered our init, which also sends the first one
+    let mut last_ping: Option<Instant> = None;
+    let mut last_received = Instant::now();
+    let mut context = DispatchContext::default();
+
+    loop {
+        if let Ok(command) = commands.try_recv() {
+            match command {
+                WorkerCommand::Disconnect => {
+                    let _ = transport.close();
+                    return DisconnectInfo::new(DisconnectReason::ClientDisconnect, None);
+                }
+            }
+        }
+
+        if last_received.elapsed() >= DISCONNECT_TIMEOUT {
+            let _ = transport.close();
+            return DisconnectInfo::new(
+                DisconnectReason::ClientTimeout,
+                Some(format!("Nothing received for {:?}", DISCONNECT_TIMEOUT))
+            );
+        }
+
+        if let Some(sent) = last_ping
+            && sent.elapsed() >= KEEP_ALIVE_INTERVAL {
+            if let Err(e) = transport.send_frame(&ping_message(local_timestamp())) {
+                return e.into();
+            }
+            last_ping = Some(Instant::now());
+        }
+
+        let mut timeout = COMMAND_POLL_INTERVAL;
+        if let Some(sent) = last_ping {
+            timeout = timeout.min(KEEP_ALIVE_INTERVAL.saturating_sub(sent.elapsed()));
+        }
+
+        match transport.receive_frame(Some(timeout)) {
+            Ok(Some(data)) => {
+                // TrafficTransport already counts the frame for traffic_stats()
+                last_received = Instant::now();
+
+                if data.get(1) == Some(&(EgMessageType::InitResponse as u8)) {
+                    last_ping = Some(Instant::now());
+                }
+
+                let mut buffer = StreamBuffer::new(&data);
+                let dispatch_started = Instant::now();
+                context.now = local_timestamp();
+                let result = deserialize_message_and_callback(&mut buffer, transport, &mut context);
+                TRAFFIC.lock().unwrap().record_dispatch(dispatch_started.elapsed());
+                match result {
+                    Ok(Dispatched::Nothing | Dispatched::Malformed(_)) => {}
+                    Ok(Dispatched::Regions(regions)) => broadcast_regions(&regions),
+                    Ok(Dispatched::PingResult(rtt)) => {
+                        *ROUND_TRIP_TIME.lock().unwrap() = context.round_trip_time.clone();
+                        TRAFFIC.lock().unwrap().record_rtt(rtt);
+                    }
+                    Err(info) => {
+                        let _ = transport.close();
+                        return info;
+                    }
+                }
+            }
+            Ok(None) => {}
+            Err(e) => return e.into(),
+        }
+    }
+}
+
+/// Like [`run_worker`], but a panic while handling the connection ends it like any other failure,
+/// so it is reconnected and `start_connection` still knows whether a worker is running
+fn run_worker_guarded(transport: &mut dyn Transport, commands: &Receiver<WorkerCommand>) -> DisconnectInfo {
+    match panic::catch_unwind(AssertUnwindSafe(|| run_worker(transport, commands))) {
+        Ok(info) => info,
+        Err(payload) => {
+            let _ = transport.close();
+            DisconnectInfo::new(DisconnectReason::DispatchFailure, Some(decode::panic_message(payload.as_ref())))
         }
-        _ => {panic!("Unknown operation response type")}
     }
 }
 
 fn photon_worker() {
-    // Open a websocket to ws://ns.photonengine.io:80 with a subprotocol with name "GpBinaryV18"
-    let client = ClientBuilder::new("wss://ns.photonengine.io:80")
-        .unwrap()
-        .add_protocol("GpBinaryV18")
-        .connect_insecure()
-        .unwrap();
-
-    // Split the client into a sender and receiver
-    let (mut receiver, mut sender) = client.split().unwrap();
-
-    println!("Connected to Photon server. Waiting for messages...");
-
-    // Read messages from the websocket connection
-    for message in receiver.incoming_messages() {
-        match message {
-            Ok(msg) => {
-                match msg {
-                    OwnedMessage::Binary(data) => {
-                        println!("Received binary message of {} bytes", data.len());
-
-                        let mut buffer = StreamBuffer::with_capacity(data.len());
-
-                        // Write the received data into the buffer
-                        buffer.write(&data);
-
-                        // Reset the buffer position to read from the beginning
-                        buffer.reset_position();
-
-                        deserialize_message_and_callback(&mut buffer, &mut sender)
-                    },
-                    OwnedMessage::Close(_) => {
-                        println!("Connection closed");
-                        break;
-                    },
-                    _ => {
-                        println!("Received non-binary message: {:?}", msg);
-                    }
+    emit_connection_event(ConnectionEvent::Connecting);
+
+    // Reconnect attempts since the last successful connection
+    let mut attempt = 0;
+    loop {
+        // Open a websocket to the name server with a subprotocol with name "GpBinaryV18"
+        let info = match WebSocketTransport::connect(&name_server_address(), SUBPROTOCOL) {
+            Ok(transport) => {
+                let connection = CONNECTION_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+                // The host name may stand for many servers, each with a timestamp of its own
+                *CLOCK_SERVER.lock().unwrap() = Some(match transport.peer_addr() {
+                    Some(peer) => peer.to_string(),
+                    None => name_server_address(),
+                });
+                // Simulated conditions sit closest to the wire, so everything above sees them
+                let mut transport: Box<dyn Transport> = Box::new(transport);
+                if let Some(simulator) = NETWORK_SIMULATOR.lock().unwrap().clone() {
+                    transport = Box::new(SimulatedTransport::new(transport, simulator));
                 }
-            },
-            Err(e) => {
-                println!("Error receiving message: {:?}", e);
-                break;
+                if let Some(writer) = CAPTURE.lock().unwrap().clone() {
+                    transport = Box::new(CaptureTransport::new(transport, writer, connection));
+                }
+                TRAFFIC.lock().unwrap().start_connection(connection);
+                let mut transport = TrafficTransport::new(transport, TRAFFIC.clone());
+                println!("Connected to Photon server. Waiting for messages...");
+                *LAST_DISCONNECT.lock().unwrap() = None;
+                emit_connection_event(match attempt {
+                    0 => ConnectionEvent::Connected,
+                    attempts => ConnectionEvent::Recovered { attempts },
+                });
+                attempt = 0;
+
+                run_worker_guarded(&mut transport, &COMMANDS.1)
             }
+            Err(e) => e.into(),
+        };
+        println!("Disconnected from Photon server: {}", info);
+
+        // The next connection may well be to another server, with its own timestamps
+        *ROUND_TRIP_TIME.lock().unwrap() = RoundTripTime::new();
+        *CLOCK_SERVER.lock().unwrap() = None;
+
+        let policy = reconnect_policy();
+        attempt += 1;
+        if !info.reason.is_recoverable() || !policy.allows_attempt(attempt) {
+            emit_connection_event(ConnectionEvent::Disconnected(info.clone()));
+            stop_worker(&info);
+            return;
+        }
+
+        broadcast_disconnect(&info, !policy.rejoin);
+        emit_connection_event(ConnectionEvent::Disconnected(info));
+
+        let delay = policy.delay_for_attempt(attempt);
+        println!("Reconnecting to Photon server in {:?} (attempt {})", delay, attempt);
+        emit_connection_event(ConnectionEvent::Reconnecting { attempt, delay });
+
+        // Wait out the backoff, but give up straight away if asked to disconnect
+        if let Ok(WorkerCommand::Disconnect) = COMMANDS.1.recv_timeout(delay) {
+            stop_worker(&DisconnectInfo::new(DisconnectReason::ClientDisconnect, None));
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::*;
+    use crate::operation_response::OperationResponse;
+    use crate::protocol_v18::{deserialize_operation_request, serialize_operation_response};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    fn operation_response_frame(message_type: EgMessageType, response: &OperationResponse) -> Vec<u8> {
+        let mut buffer = StreamBuffer::with_capacity(0);
+        buffer.write(&[243, message_type as u8]);
+        serialize_operation_response(&mut buffer, response, false).unwrap();
+        buffer.get_buffer()[..buffer.length()].to_vec()
+    }
+
+    #[test]
+    fn test_region_flow_over_memory_transport() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        let (_commands_tx, commands) = unbounded();
+        let worker = thread::spawn(move || run_worker(&mut client, &commands));
+
+        let (tx, rx) = unbounded();
+        SUBSCRIBERS.get_or_init(|| Mutex::new(Vec::new())).lock().unwrap().push(tx);
+
+        // Init response, which the client answers with a ping and a region request
+        server.send_frame(&[243, EgMessageType::InitResponse as u8]).unwrap();
+        let ping = server.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&ping[..3], &[243, EgMessageType::InternalOperationRequest as u8, photon_codes::PING]);
+        let get_regions = server.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&get_regions[..3], &[243, EgMessageType::Operation as u8, 220]);
+
+        let mut payload = ParameterDictionary::new();
+        payload.set(REGION, Value::StringArray(vec!["eu".to_string(), "us".to_string()]));
+        payload.set(ADDRESS, Value::StringArray(vec!["wss://eu.example:19090".to_string(), "wss://us.example:19090".to_string()]));
+        let response = OperationResponse {
+            operation_code: 220,
+            return_code: 0,
+            debug_message: None,
+            payload,
+        };
+        server.send_frame(&operation_response_frame(EgMessageType::OperationResponse, &response)).unwrap();
+
+        let regions = rx.recv_timeout(TIMEOUT.unwrap()).unwrap().unwrap();
+        assert_eq!(regions, vec![
+            PhotonRegion { short_name: "eu".to_string(), address: "wss://eu.example:19090".to_string() },
+            PhotonRegion { short_name: "us".to_string(), address: "wss://us.example:19090".to_string() },
+        ]);
+
+        server.close().unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_keep_alive_pings_update_round_trip_time() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        let (_commands_tx, commands) = unbounded();
+        let worker = thread::spawn(move || run_worker(&mut client, &commands));
+
+        server.send_frame(&[243, EgMessageType::InitResponse as u8]).unwrap();
+        server.receive_frame(TIMEOUT).unwrap().unwrap();
+        server.receive_frame(TIMEOUT).unwrap().unwrap();
+
+        // Without any traffic from us, the worker keeps pinging on its own
+        let ping = server.receive_frame(TIMEOUT).unwrap().unwrap();
+        let mut stream = StreamBuffer::new(&ping[2..]);
+        let request = deserialize_operation_request(&mut stream).unwrap();
+        assert_eq!(request.operation_code, photon_codes::PING);
+
+        let mut payload = ParameterDictionary::new();
+        payload.set(1, request.parameters.get(1).unwrap().clone());
+        payload.set(2, Int(123_456));
+        let response = OperationResponse {
+            operation_code: photon_codes::PING,
+            return_code: 0,
+            debug_message: None,
+            payload,
+        };
+        server.send_frame(&operation_response_frame(EgMessageType::InternalOperationResponse, &response)).unwrap();
+
+        // The response is only processed once the worker reads it, so wait for the next ping
+        server.receive_frame(TIMEOUT).unwrap().unwrap();
+        let stats = round_trip_stats().unwrap();
+        assert!(stats.samples >= 1);
+        assert!(server_time_offset().is_some());
+
+        server.close().unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_disconnect_message_ends_worker() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        let (_commands_tx, commands) = unbounded();
+        let worker = thread::spawn(move || run_worker(&mut client, &commands));
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        buffer.write(&[243, EgMessageType::DisconnectReason as u8, 0xFF, 0x7F, 7, 7]);
+        buffer.write(b"bad app");
+        buffer.write(&[0]);
+        server.send_frame(&buffer.get_buffer()[..buffer.length()]).unwrap();
+
+        let info = worker.join().unwrap();
+        assert_eq!(info, DisconnectInfo::new(DisconnectReason::InvalidAuth, Some("bad app".to_string())));
+        // The worker closed its end too
+        assert!(matches!(server.receive_frame(TIMEOUT), Err(TransportError::Closed)));
+    }
+
+    #[test]
+    fn test_unhandled_messages_are_ignored() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        let (_commands_tx, commands) = unbounded();
+        let worker = thread::spawn(move || run_worker(&mut client, &commands));
+
+        // An event, an internal operation request and an encrypted response
+        server.send_frame(&[243, EgMessageType::Event as u8, 1, 0]).unwrap();
+        server.send_frame(&[243, EgMessageType::InternalOperationRequest as u8, 1, 0]).unwrap();
+        server.send_frame(&[243, 0x80 | EgMessageType::OperationResponse as u8, 0xAB, 0xCD]).unwrap();
+
+        // The worker is still there to answer the init
+        server.send_frame(&[243, EgMessageType::InitResponse as u8]).unwrap();
+        let ping = server.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(&ping[..3], &[243, EgMessageType::InternalOperationRequest as u8, photon_codes::PING]);
+
+        server.close().unwrap();
+        worker.join().unwrap();
+    }
+
+    struct PanickingTransport;
+
+    impl Transport for PanickingTransport {
+        fn send_frame(&mut self, _frame: &[u8]) -> Result<(), TransportError> {
+            Ok(())
+        }
+
+        fn receive_frame(&mut self, _timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+            panic!("receive failed");
         }
+
+        fn close(&mut self) -> Result<(), TransportError> {
+            Ok(())
+        }
+
+        fn rtt(&self) -> Option<Duration> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_worker_panic_becomes_disconnect() {
+        let (_commands_tx, commands) = unbounded();
+
+        let info = run_worker_guarded(&mut PanickingTransport, &commands);
+        assert_eq!(info, DisconnectInfo::new(DisconnectReason::DispatchFailure, Some("receive failed".to_string())));
+        assert!(info.reason.is_recoverable());
     }
 
-    println!("Disconnected from Photon server");
+    #[test]
+    fn test_client_disconnect_closes_transport() {
+        let (mut client, mut server) = MemoryTransport::pair();
+        let (commands_tx, commands) = unbounded();
+        let worker = thread::spawn(move || run_worker(&mut client, &commands));
+
+        commands_tx.send(WorkerCommand::Disconnect).unwrap();
+
+        assert_eq!(worker.join().unwrap().reason, DisconnectReason::ClientDisconnect);
+        assert!(matches!(server.receive_frame(TIMEOUT), Err(TransportError::Closed)));
+    }
 }
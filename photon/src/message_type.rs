@@ -1,4 +1,10 @@
+use num_enum::TryFromPrimitive;
+use serde::{Deserialize, Serialize};
+
+/// The second byte of every message, saying what follows the header
 #[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive, Serialize, Deserialize)]
+#[repr(u8)]
 pub enum EgMessageType {
     Init,
     InitResponse,
@@ -0,0 +1,357 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use websocket::{OwnedMessage, WebSocketError};
+use websocket::server::upgrade::WsUpgrade;
+use websocket::server::upgrade::sync::Buffer;
+use websocket::sync::{Client, Server};
+use crate::SUBPROTOCOL;
+use crate::disconnect_message::DisconnectMessage;
+use crate::message_type::EgMessageType;
+use crate::operation_response::OperationResponse;
+use crate::parameter_codes::{ADDRESS, REGION};
+use crate::parameter_dictionary::{ParameterDictionary, Value};
+use crate::photon_codes;
+use crate::photon_region::PhotonRegion;
+use crate::pinger::PING_PORT;
+use crate::protocol_v18::{deserialize_operation_request, serialize_disconnect_message, serialize_operation_response};
+use crate::round_trip_time::local_timestamp;
+use crate::stream_buffer::StreamBuffer;
+
+const GET_REGIONS: u8 = 220;
+/// How often the accept loop and the connections check whether the server was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How a [`MockNameServer`] answers, including the faults it injects
+#[derive(Debug, Clone)]
+pub struct MockNameServerOptions {
+    /// The regions GetRegions answers with. By default one region on this machine, which a
+    /// [`crate::PingResponder`] on [`PING_PORT`] answers pings for.
+    pub regions: Vec<PhotonRegion>,
+    /// Waits this long before every reply, to simulate a slow or distant server
+    pub reply_delay: Duration,
+    /// Answers GetRegions with this return code and debug message instead of the regions
+    pub region_error: Option<(i16, String)>,
+    /// Answers GetRegions with a DisconnectReason message with this code and debug message, then
+    /// closes the connection
+    pub disconnect: Option<(i16, String)>,
+    /// Sends a few frames that aren't valid Photon messages ahead of every reply
+    pub malformed_frames: bool,
+    /// Whether internal PINGs are answered, turn off to starve the client's keep-alive
+    pub answer_pings: bool,
+}
+
+impl Default for MockNameServerOptions {
+    fn default() -> Self {
+        MockNameServerOptions {
+            regions: vec![PhotonRegion {
+                short_name: "local".to_string(),
+                address: format!("udp://127.0.0.1:{}", PING_PORT),
+            }],
+            reply_delay: Duration::ZERO,
+            region_error: None,
+            disconnect: None,
+            malformed_frames: false,
+            answer_pings: true,
+        }
+    }
+}
+
+/// A local stand-in for Photon's name server, speaking GpBinaryV18 over plain websockets.
+///
+/// It sends the init response, answers internal PINGs and GetRegions, and can inject errors,
+/// delays and malformed frames, so the client can be tested without a network. Point the
+/// connection at it with [`crate::set_name_server_address`] and [`MockNameServer::url`].
+pub struct MockNameServer {
+    address: SocketAddr,
+    connections: Arc<AtomicUsize>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MockNameServer {
+    /// Starts the server on a free port on the loopback interface
+    pub fn start(options: MockNameServerOptions) -> io::Result<Self> {
+        Self::bind("127.0.0.1:0", options)
+    }
+
+    pub fn bind<A: ToSocketAddrs>(address: A, options: MockNameServerOptions) -> io::Result<Self> {
+        let mut server = Server::bind(address)?;
+        // Non-blocking so the accept loop notices when it is stopped
+        server.set_nonblocking(true)?;
+        let address = server.local_addr()?;
+
+        let connections = Arc::new(AtomicUsize::new(0));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let options = Arc::new(options);
+
+        let thread_connections = connections.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            while !thread_stopped.load(Ordering::Relaxed) {
+                let upgrade = match server.accept() {
+                    Ok(upgrade) => upgrade,
+                    Err(_) => {
+                        // Mostly WouldBlock, a failed handshake only affects that one client
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                };
+
+                thread_connections.fetch_add(1, Ordering::Relaxed);
+                let options = options.clone();
+                let stopped = thread_stopped.clone();
+                thread::spawn(move || {
+                    if let Err(e) = serve(upgrade, &options, &stopped) {
+                        println!("Mock name server connection ended: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(MockNameServer { address, connections, stopped, thread: Some(thread) })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address to connect to, e.g. `ws://127.0.0.1:45678`
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.address)
+    }
+
+    /// How many clients have connected so far
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Stops accepting connections and ends the open ones
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MockNameServer {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn serve(upgrade: WsUpgrade<TcpStream, Option<Buffer>>, options: &MockNameServerOptions, stopped: &AtomicBool) -> Result<(), WebSocketError> {
+    let mut client = upgrade.use_protocol(SUBPROTOCOL).accept().map_err(|(_, e)| e)?;
+    client.stream_ref().set_read_timeout(Some(POLL_INTERVAL))?;
+
+    reply(&mut client, options, vec![243, EgMessageType::InitResponse as u8])?;
+
+    while !stopped.load(Ordering::Relaxed) {
+        let frame = match client.recv_message() {
+            Ok(OwnedMessage::Binary(frame)) => frame,
+            Ok(OwnedMessage::Ping(data)) => {
+                client.send_message(&OwnedMessage::Pong(data))?;
+                continue;
+            }
+            Ok(OwnedMessage::Close(_)) => return Ok(()),
+            Ok(_) => continue,
+            Err(WebSocketError::IoError(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        if frame.len() < 3 || frame[0] != 243 {
+            continue;
+        }
+
+        let Ok(request) = deserialize_operation_request(&mut StreamBuffer::new(&frame[2..])) else {
+            continue;
+        };
+        if frame[1] == EgMessageType::InternalOperationRequest as u8 && request.operation_code == photon_codes::PING {
+            if options.answer_pings {
+                reply(&mut client, options, ping_response(request.parameters.get(1).cloned().unwrap_or(Value::Null)))?;
+            }
+        } else if frame[1] == EgMessageType::Operation as u8 && request.operation_code == GET_REGIONS {
+            if let Some((code, message)) = &options.disconnect {
+                reply(&mut client, options, disconnect_message(*code, message))?;
+                let _ = client.shutdown();
+                return Ok(());
+            }
+            reply(&mut client, options, regions_response(options))?;
+        }
+    }
+
+    let _ = client.shutdown();
+    Ok(())
+}
+
+/// Sends `frame` after the configured delay, preceded by the malformed frames if enabled
+fn reply(client: &mut Client<TcpStream>, options: &MockNameServerOptions, frame: Vec<u8>) -> Result<(), WebSocketError> {
+    thread::sleep(options.reply_delay);
+    if options.malformed_frames {
+        for malformed in malformed_frames() {
+            client.send_message(&OwnedMessage::Binary(malformed))?;
+        }
+    }
+    client.send_message(&OwnedMessage::Binary(frame))
+}
+
+/// An empty frame, one cut off after the first header byte, and one without the Photon header
+fn malformed_frames() -> Vec<Vec<u8>> {
+    vec![Vec::new(), vec![243], vec![0, 1, 2, 3]]
+}
+
+fn response_frame(message_type: EgMessageType, response: &OperationResponse) -> Vec<u8> {
+    let mut buffer = StreamBuffer::with_capacity(0);
+    buffer.write(&[243, message_type as u8]);
+    serialize_operation_response(&mut buffer, response, false).expect("mock responses only carry types we can write");
+    buffer.get_buffer()[..buffer.length()].to_vec()
+}
+
+fn ping_response(client_timestamp: Value) -> Vec<u8> {
+    let mut payload = ParameterDictionary::new();
+    payload.set(1, client_timestamp);
+    payload.set(2, Value::Int(local_timestamp()));
+    let response = OperationResponse { operation_code: photon_codes::PING, return_code: 0, debug_message: None, payload };
+    response_frame(EgMessageType::InternalOperationResponse, &response)
+}
+
+fn regions_response(options: &MockNameServerOptions) -> Vec<u8> {
+    let response = match &options.region_error {
+        Some((code, message)) => OperationResponse {
+            operation_code: GET_REGIONS,
+            return_code: *code,
+            debug_message: Some(message.clone()),
+            payload: ParameterDictionary::new(),
+        },
+        None => {
+            let mut payload = ParameterDictionary::new();
+            payload.set(REGION, Value::StringArray(options.regions.iter().map(|r| r.short_name.clone()).collect()));
+            payload.set(ADDRESS, Value::StringArray(options.regions.iter().map(|r| r.address.clone()).collect()));
+            OperationResponse { operation_code: GET_REGIONS, return_code: 0, debug_message: None, payload }
+        }
+    };
+    response_frame(EgMessageType::OperationResponse, &response)
+}
+
+fn disconnect_message(code: i16, debug_message: &str) -> Vec<u8> {
+    let message = DisconnectMessage {
+        code,
+        debug_message: Some(debug_message.to_string()),
+        parameters: ParameterDictionary::new(),
+    };
+    let mut buffer = StreamBuffer::with_capacity(0);
+    buffer.write(&[243, EgMessageType::DisconnectReason as u8]);
+    serialize_disconnect_message(&mut buffer, &message).expect("mock disconnects only carry types we can write");
+    buffer.get_buffer()[..buffer.length()].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use crate::protocol_v18::{deserialize_disconnect_message, deserialize_operation_response};
+    use crate::transport::{Transport, TransportError, WebSocketTransport};
+    use crate::{get_regions, ping_message};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(2));
+
+    fn regions() -> Vec<PhotonRegion> {
+        vec![
+            PhotonRegion { short_name: "eu".to_string(), address: "wss://eu.example:19090".to_string() },
+            PhotonRegion { short_name: "us".to_string(), address: "wss://us.example:19090".to_string() },
+        ]
+    }
+
+    fn connect(server: &MockNameServer) -> WebSocketTransport {
+        let mut transport = WebSocketTransport::connect(&server.url(), SUBPROTOCOL).unwrap();
+        // Skips the malformed frames, if any
+        while transport.receive_frame(TIMEOUT).unwrap().unwrap() != [243, EgMessageType::InitResponse as u8] {}
+        transport
+    }
+
+    fn response(frame: &[u8]) -> OperationResponse {
+        deserialize_operation_response(&mut StreamBuffer::new(&frame[2..])).unwrap()
+    }
+
+    #[test]
+    fn test_answers_pings_and_regions() {
+        let server = MockNameServer::start(MockNameServerOptions { regions: regions(), ..Default::default() }).unwrap();
+        let mut transport = connect(&server);
+
+        transport.send_frame(&ping_message(local_timestamp())).unwrap();
+        let pong = transport.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(pong[1], EgMessageType::InternalOperationResponse as u8);
+        let pong = response(&pong);
+        assert_eq!(pong.operation_code, photon_codes::PING);
+        assert!(matches!(pong.payload.get(2), Some(Value::Int(_))));
+
+        transport.send_frame(&get_regions()).unwrap();
+        let regions_frame = transport.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(regions_frame[1], EgMessageType::OperationResponse as u8);
+        let regions_response = response(&regions_frame);
+        assert_eq!(regions_response.return_code, 0);
+        assert_eq!(regions_response.payload.get(REGION), Some(&Value::StringArray(vec!["eu".to_string(), "us".to_string()])));
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[test]
+    fn test_region_error() {
+        let options = MockNameServerOptions { region_error: Some((-2, "no regions".to_string())), ..Default::default() };
+        let server = MockNameServer::start(options).unwrap();
+        let mut transport = connect(&server);
+
+        transport.send_frame(&get_regions()).unwrap();
+        let response = response(&transport.receive_frame(TIMEOUT).unwrap().unwrap());
+        assert_eq!(response.return_code, -2);
+        assert_eq!(response.debug_message, Some("no regions".to_string()));
+    }
+
+    #[test]
+    fn test_disconnect_closes_connection() {
+        let options = MockNameServerOptions { disconnect: Some((32767, "bad app".to_string())), ..Default::default() };
+        let server = MockNameServer::start(options).unwrap();
+        let mut transport = connect(&server);
+
+        transport.send_frame(&get_regions()).unwrap();
+        let frame = transport.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(frame[1], EgMessageType::DisconnectReason as u8);
+        let message = deserialize_disconnect_message(&mut StreamBuffer::new(&frame[2..])).unwrap();
+        assert_eq!(message.code, 32767);
+        assert!(matches!(transport.receive_frame(TIMEOUT), Err(TransportError::Closed)));
+    }
+
+    #[test]
+    fn test_delay_and_malformed_frames() {
+        let options = MockNameServerOptions {
+            reply_delay: Duration::from_millis(100),
+            malformed_frames: true,
+            answer_pings: false,
+            ..Default::default()
+        };
+        let server = MockNameServer::start(options).unwrap();
+        let mut transport = connect(&server);
+
+        // Unanswered, so the next frames belong to the region request
+        transport.send_frame(&ping_message(local_timestamp())).unwrap();
+        let sent = Instant::now();
+        transport.send_frame(&get_regions()).unwrap();
+        for malformed in malformed_frames() {
+            assert_eq!(transport.receive_frame(TIMEOUT).unwrap().unwrap(), malformed);
+        }
+        let frame = transport.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert!(sent.elapsed() >= Duration::from_millis(100));
+        assert_eq!(response(&frame).operation_code, GET_REGIONS);
+    }
+
+    #[test]
+    fn test_stop_ends_connections() {
+        let mut server = MockNameServer::start(MockNameServerOptions::default()).unwrap();
+        let mut transport = connect(&server);
+
+        server.stop();
+        assert!(matches!(transport.receive_frame(TIMEOUT), Err(TransportError::Closed)));
+    }
+}
@@ -0,0 +1,394 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep_until, Instant};
+use crate::fallback_ping::fallback_ping;
+use crate::photon_region::PhotonRegion;
+use crate::ping_stats::PingStats;
+use crate::pinger::{ping_port, region_host, PingEndpointOptions, PingError, DEFAULT_PROBE_TIMEOUT, PING_BYTES};
+
+/// How a sweep over all regions is run
+#[derive(Debug, Clone)]
+pub struct SweepOptions {
+    /// Pings per region that count, a discarded warm-up ping is sent on top
+    pub samples: u32,
+    /// Maximum number of probes waiting for a reply at once, across all regions
+    pub concurrency: usize,
+    /// Minimum gap between two sends, so a burst doesn't queue up behind itself on slow uplinks
+    pub pacing: Duration,
+    /// How long a probe waits for its reply before it counts as lost
+    pub probe_timeout: Duration,
+    /// The whole sweep stops after this, unanswered and unsent probes count as lost
+    pub deadline: Duration,
+    /// Regions that answer no UDP probe at all are pinged over websocket or TCP instead
+    pub fallback: bool,
+    /// Which port and addresses of each region are pinged
+    pub endpoint: PingEndpointOptions,
+}
+
+impl Default for SweepOptions {
+    fn default() -> Self {
+        SweepOptions {
+            samples: 5,
+            concurrency: 32,
+            pacing: Duration::from_millis(2),
+            probe_timeout: DEFAULT_PROBE_TIMEOUT,
+            deadline: Duration::from_secs(5),
+            fallback: true,
+            endpoint: PingEndpointOptions::default(),
+        }
+    }
+}
+
+struct Target {
+    endpoint: SocketAddr,
+    samples: Vec<Duration>,
+    warmed_up: bool,
+}
+
+struct Probe {
+    target: usize,
+    sent_at: Instant,
+    warm_up: bool,
+}
+
+/// Pings every region from a single UDP socket without blocking the runtime.
+///
+/// Probes are sent round robin over the regions and replies are matched by their source address
+/// and id, so the whole sweep takes about as long as the slowest region instead of the sum of all.
+/// With [`PingEndpointOptions::all_addresses`] a region appears once per resolved address, and
+/// [`PingStats::endpoint`] tells them apart.
+pub async fn ping_regions(regions: &[PhotonRegion], options: &SweepOptions) -> Vec<(PhotonRegion, Result<PingStats, PingError>)> {
+    let mut resolved: Vec<(PhotonRegion, Result<SocketAddr, PingError>)> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match resolve(region, options.endpoint).await {
+            Ok(endpoints) => resolved.extend(endpoints.into_iter().map(|endpoint| (region.clone(), Ok(endpoint)))),
+            Err(e) => resolved.push((region.clone(), Err(e))),
+        }
+    }
+
+    let endpoints: Vec<SocketAddr> = resolved.iter().filter_map(|(_, endpoint)| endpoint.as_ref().ok().copied()).collect();
+    let mut swept = match sweep(&endpoints, options).await {
+        Ok(stats) => stats.into_iter().map(Ok).collect::<Vec<_>>(),
+        // The shared socket failed, which takes every region down with it
+        Err(e) => endpoints.iter().map(|_| Err(PingError::Socket(io::Error::new(e.kind(), e.to_string())))).collect(),
+    }
+    .into_iter();
+
+    let results: Vec<_> = resolved
+        .into_iter()
+        .map(|(region, endpoint)| {
+            let result = endpoint.and_then(|_| swept.next().expect("One sweep result per resolved endpoint"));
+            (region, result)
+        })
+        .collect();
+
+    if options.fallback {
+        with_fallbacks(results, options).await
+    } else {
+        results
+    }
+}
+
+/// Re-pings the regions UDP couldn't reach with the blocking fallback methods, all at once
+async fn with_fallbacks(results: Vec<(PhotonRegion, Result<PingStats, PingError>)>, options: &SweepOptions) -> Vec<(PhotonRegion, Result<PingStats, PingError>)> {
+    let pending: Vec<_> = results
+        .into_iter()
+        .map(|(region, result)| {
+            let fallback = match &result {
+                Ok(stats) if !stats.is_reachable() => {
+                    let (fallback_region, samples, timeout) = (region.clone(), options.samples, options.probe_timeout);
+                    Some(tokio::task::spawn_blocking(move || fallback_ping(&fallback_region, samples, timeout)))
+                }
+                _ => None,
+            };
+            (region, result, fallback)
+        })
+        .collect();
+
+    let mut results = Vec::with_capacity(pending.len());
+    for (region, udp, fallback) in pending {
+        let result = match fallback {
+            Some(handle) => match handle.await {
+                Ok(Ok(stats)) => Ok(stats),
+                // Nothing got through at all, so report the UDP losses
+                _ => udp,
+            },
+            None => udp,
+        };
+        results.push((region, result));
+    }
+    results
+}
+
+async fn resolve(region: &PhotonRegion, options: PingEndpointOptions) -> Result<Vec<SocketAddr>, PingError> {
+    let host = region_host(region)?;
+    let port = ping_port(region, options.port)?;
+    let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), port))
+        .await
+        .map_err(|e| PingError::Resolve(format!("{}: {}", host, e)))?
+        .collect();
+    if addresses.is_empty() {
+        return Err(PingError::Resolve(format!("{}: no addresses", host)));
+    }
+
+    let count = if options.all_addresses { addresses.len() } else { 1 };
+    Ok(addresses.into_iter().take(count).collect())
+}
+
+/// One socket for both address families. IPv4 endpoints are reached through their IPv4-mapped
+/// IPv6 form, which needs a dual-stack socket, so a plain IPv4 socket is used when there are no
+/// IPv6 endpoints to keep working on hosts with IPv6 disabled.
+fn bind_socket(dual_stack: bool) -> io::Result<UdpSocket> {
+    let socket = if dual_stack {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        socket
+    } else {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+        socket
+    };
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// How `endpoint` has to be addressed on the sweep socket
+fn socket_address(endpoint: SocketAddr, dual_stack: bool) -> SocketAddr {
+    match endpoint {
+        SocketAddr::V4(v4) if dual_stack => SocketAddr::new(IpAddr::V6(v4.ip().to_ipv6_mapped()), v4.port()),
+        _ => endpoint,
+    }
+}
+
+/// The endpoint a reply came from, with IPv4-mapped addresses turned back into IPv4
+fn canonical_address(from: SocketAddr) -> SocketAddr {
+    match from {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(v4) => SocketAddr::new(IpAddr::V4(v4), v6.port()),
+            None => from,
+        },
+        _ => from,
+    }
+}
+
+/// Runs the probes against already resolved endpoints, returning stats in the same order
+async fn sweep(endpoints: &[SocketAddr], options: &SweepOptions) -> io::Result<Vec<PingStats>> {
+    let dual_stack = endpoints.iter().any(SocketAddr::is_ipv6);
+    let socket = bind_socket(dual_stack)?;
+    let start = Instant::now();
+    let deadline = start + options.deadline;
+    // Ids only have to be unique among outstanding probes, which is at most 255 of them
+    let concurrency = options.concurrency.clamp(1, 255);
+
+    let mut targets: Vec<Target> = endpoints
+        .iter()
+        .map(|&endpoint| Target { endpoint, samples: Vec::new(), warmed_up: false })
+        .collect();
+
+    // Round robin, so every region gets its first sample in the first round
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for _ in 0..=options.samples {
+        queue.extend(0..targets.len());
+    }
+
+    let mut outstanding: HashMap<(SocketAddr, u8), Probe> = HashMap::new();
+    let mut next_id: u8 = thread_rng().gen_range(0, 255);
+    let mut next_send = start;
+    let mut buffer = [0u8; 64];
+
+    loop {
+        let now = Instant::now();
+        // Lost probes free their slot
+        outstanding.retain(|_, probe| now.duration_since(probe.sent_at) < options.probe_timeout);
+        if now >= deadline || (queue.is_empty() && outstanding.is_empty()) {
+            break;
+        }
+
+        if now >= next_send && outstanding.len() < concurrency && let Some(index) = queue.pop_front() {
+            let target = &mut targets[index];
+            while outstanding.contains_key(&(target.endpoint, next_id)) {
+                next_id = next_id.wrapping_add(1);
+            }
+
+            let mut probe = PING_BYTES;
+            probe[12] = next_id;
+            socket.send_to(&probe, socket_address(target.endpoint, dual_stack)).await?;
+
+            let warm_up = !target.warmed_up;
+            target.warmed_up = true;
+            outstanding.insert((target.endpoint, next_id), Probe { target: index, sent_at: Instant::now(), warm_up });
+            next_id = next_id.wrapping_add(1);
+            next_send = Instant::now() + options.pacing;
+            continue;
+        }
+
+        // Sleep until the next send is allowed, the oldest probe expires or the deadline
+        let mut wake = deadline;
+        if !queue.is_empty() && outstanding.len() < concurrency {
+            wake = wake.min(next_send);
+        }
+        if let Some(oldest) = outstanding.values().map(|probe| probe.sent_at).min() {
+            wake = wake.min(oldest + options.probe_timeout);
+        }
+
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => {
+                let (len, from) = match received {
+                    Ok(received) => received,
+                    // ICMP errors from unreachable regions surface here on some platforms
+                    Err(e) if e.kind() == io::ErrorKind::ConnectionRefused
+                        || e.kind() == io::ErrorKind::ConnectionReset => continue,
+                    Err(e) => return Err(e),
+                };
+                if len < 13 {
+                    continue;
+                }
+                // Anything that doesn't match an outstanding probe is a late reply, drop it
+                if let Some(probe) = outstanding.remove(&(canonical_address(from), buffer[12])) && !probe.warm_up {
+                    targets[probe.target].samples.push(probe.sent_at.elapsed());
+                }
+            }
+            _ = sleep_until(wake) => {}
+        }
+    }
+
+    Ok(targets
+        .into_iter()
+        .map(|target| PingStats {
+            endpoint: Some(target.endpoint),
+            ..PingStats::from_samples(&target.samples, options.samples)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Answers probes after `delay`, or never if `delay` is `None`
+    fn responder(delay: Option<Duration>) -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                let Some(delay) = delay else { continue };
+                let reply = buffer[..len].to_vec();
+                let socket = socket.try_clone().unwrap();
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    let _ = socket.send_to(&reply, from);
+                });
+            }
+        });
+        address
+    }
+
+    #[tokio::test]
+    async fn test_sweep_runs_regions_in_parallel() {
+        let delay = Duration::from_millis(100);
+        let endpoints = vec![responder(Some(delay)), responder(Some(delay)), responder(Some(delay))];
+        let options = SweepOptions { samples: 3, pacing: Duration::ZERO, fallback: false, ..SweepOptions::default() };
+
+        let start = std::time::Instant::now();
+        let stats = sweep(&endpoints, &options).await.unwrap();
+
+        // Twelve probes at 100ms each would take 1.2s one after another
+        assert!(start.elapsed() < Duration::from_millis(600), "{:?}", start.elapsed());
+        for stats in stats {
+            assert_eq!(stats.received, 3);
+            assert!(stats.min_us >= 100_000, "{:?}", stats);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_region_counts_losses() {
+        let endpoints = vec![responder(Some(Duration::ZERO)), responder(None)];
+        let options = SweepOptions {
+            samples: 2,
+            probe_timeout: Duration::from_millis(50),
+            fallback: false,
+            ..SweepOptions::default()
+        };
+
+        let stats = sweep(&endpoints, &options).await.unwrap();
+
+        assert_eq!(stats[0].received, 2);
+        assert_eq!(stats[1].received, 0);
+        assert_eq!(stats[1].loss_percent(), 100.0);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_stops_the_sweep() {
+        let endpoints = vec![responder(None)];
+        let options = SweepOptions {
+            samples: 10,
+            concurrency: 1,
+            deadline: Duration::from_millis(100),
+            fallback: false,
+            ..SweepOptions::default()
+        };
+
+        let start = std::time::Instant::now();
+        let stats = sweep(&endpoints, &options).await.unwrap();
+
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert_eq!(stats[0].sent, 10);
+        assert_eq!(stats[0].received, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sweep_mixes_address_families() {
+        // Not every test machine has IPv6 configured
+        let Ok(socket) = std::net::UdpSocket::bind("[::1]:0") else { return };
+        let v6 = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                let _ = socket.send_to(&buffer[..len], from);
+            }
+        });
+        let endpoints = vec![responder(Some(Duration::ZERO)), v6];
+        let options = SweepOptions { samples: 2, fallback: false, ..SweepOptions::default() };
+
+        let stats = sweep(&endpoints, &options).await.unwrap();
+
+        assert_eq!(stats[0].received, 2);
+        assert_eq!(stats[1].received, 2);
+        assert_eq!(stats[1].endpoint, Some(v6));
+    }
+
+    #[tokio::test]
+    async fn test_port_override_and_every_address() {
+        let endpoint = responder(Some(Duration::ZERO));
+        let regions = vec![PhotonRegion { short_name: "local".to_string(), address: "wss://127.0.0.1:19090".to_string() }];
+        let options = SweepOptions {
+            samples: 2,
+            fallback: false,
+            endpoint: PingEndpointOptions { port: Some(endpoint.port()), all_addresses: true },
+            ..SweepOptions::default()
+        };
+
+        let results = ping_regions(&regions, &options).await;
+
+        assert_eq!(results.len(), 1);
+        let stats = results[0].1.as_ref().unwrap();
+        assert_eq!(stats.endpoint, Some(endpoint));
+        assert_eq!(stats.received, 2);
+    }
+
+    #[tokio::test]
+    async fn test_unresolvable_region_is_an_error() {
+        let regions = vec![PhotonRegion { short_name: "bad".to_string(), address: "nope".to_string() }];
+        let results = ping_regions(&regions, &SweepOptions::default()).await;
+
+        assert!(matches!(results[0].1, Err(PingError::InvalidAddress(_))));
+    }
+}
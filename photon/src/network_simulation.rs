@@ -0,0 +1,423 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use rand::{thread_rng, Rng};
+use crate::capture::Direction;
+use crate::transport::{Transport, TransportError};
+
+/// How one direction of a [`SimulatedTransport`] degrades the frames passing through it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct SimulationSettings {
+    /// Added to every frame
+    pub lag: Duration,
+    /// Each frame is delayed by a further random amount between minus and plus this
+    pub jitter: Duration,
+    /// Share of frames that are never delivered, from 0 to 100
+    pub loss_percent: f64,
+    /// Share of frames that are delivered twice, from 0 to 100
+    pub duplicate_percent: f64,
+    /// Share of frames that are held back by a further `reorder_delay`, letting the frames
+    /// behind them overtake, from 0 to 100
+    pub reorder_percent: f64,
+    pub reorder_delay: Duration,
+}
+
+impl SimulationSettings {
+    /// Whether frames pass through untouched
+    pub fn is_passthrough(&self) -> bool {
+        self.lag.is_zero() && self.jitter.is_zero()
+            && self.loss_percent <= 0.0 && self.duplicate_percent <= 0.0 && self.reorder_percent <= 0.0
+    }
+}
+
+/// `lag` moved by a random amount between minus and plus `jitter`, but never below zero.
+/// Shared with the [`PingResponder`](crate::ping_responder::PingResponder).
+pub(crate) fn jittered(lag: Duration, jitter: Duration) -> Duration {
+    if jitter.is_zero() {
+        return lag;
+    }
+    let jitter = jitter.as_secs_f64();
+    let delay = lag.as_secs_f64() + thread_rng().gen_range(-jitter, jitter);
+    Duration::from_secs_f64(delay.max(0.0))
+}
+
+/// Whether something with a `percent` chance, from 0 to 100, happens this time
+pub(crate) fn chance(percent: f64) -> bool {
+    percent > 0.0 && thread_rng().gen_range(0.0, 100.0) < percent
+}
+
+/// Incoming and outgoing settings of a [`NetworkSimulator`], like PUN's `NetworkSimulationSet`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct NetworkSimulationSet {
+    /// Applied to frames from the server
+    pub incoming: SimulationSettings,
+    /// Applied to frames to the server
+    pub outgoing: SimulationSettings,
+}
+
+/// Counters of what a [`NetworkSimulator`] did to one direction
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SimulationStats {
+    /// Frames that entered the simulation
+    pub frames: u64,
+    /// Frames handed on, duplicates included
+    pub delivered: u64,
+    pub lost: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Total lag and jitter added to the delivered frames
+    pub added_delay: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetworkSimulationStats {
+    pub incoming: SimulationStats,
+    pub outgoing: SimulationStats,
+}
+
+#[derive(Debug, Default)]
+struct SimulatorState {
+    settings: NetworkSimulationSet,
+    stats: NetworkSimulationStats,
+}
+
+impl SimulatorState {
+    fn direction(&mut self, direction: Direction) -> (&SimulationSettings, &mut SimulationStats) {
+        match direction {
+            Direction::Inbound => (&self.settings.incoming, &mut self.stats.incoming),
+            Direction::Outbound => (&self.settings.outgoing, &mut self.stats.outgoing),
+        }
+    }
+}
+
+/// Settings and statistics shared with any number of [`SimulatedTransport`]s.
+///
+/// Clones are handles to the same simulator, so the settings can be changed while a connection
+/// is running. Changes apply to frames from then on; frames already held back keep their delay.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkSimulator {
+    state: Arc<Mutex<SimulatorState>>,
+}
+
+impl NetworkSimulator {
+    pub fn new(settings: NetworkSimulationSet) -> Self {
+        NetworkSimulator { state: Arc::new(Mutex::new(SimulatorState { settings, stats: NetworkSimulationStats::default() })) }
+    }
+
+    pub fn settings(&self) -> NetworkSimulationSet {
+        self.state.lock().unwrap().settings.clone()
+    }
+
+    pub fn set_settings(&self, settings: NetworkSimulationSet) {
+        self.state.lock().unwrap().settings = settings;
+    }
+
+    pub fn set_incoming(&self, settings: SimulationSettings) {
+        self.state.lock().unwrap().settings.incoming = settings;
+    }
+
+    pub fn set_outgoing(&self, settings: SimulationSettings) {
+        self.state.lock().unwrap().settings.outgoing = settings;
+    }
+
+    pub fn stats(&self) -> NetworkSimulationStats {
+        self.state.lock().unwrap().stats
+    }
+
+    pub fn reset_stats(&self) {
+        self.state.lock().unwrap().stats = NetworkSimulationStats::default();
+    }
+}
+
+/// Frames held back until they are due, in the order they are due and then in the order they
+/// were scheduled
+type Pending = BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>;
+
+/// A [`Transport`] that adds lag, jitter, loss, duplication and reordering to another one.
+///
+/// There are no background threads: frames held back in either direction are handed on from
+/// [`Transport::send_frame`] and [`Transport::receive_frame`], which the connection worker calls
+/// often enough. Outgoing frames still held back when the connection closes are lost.
+pub struct SimulatedTransport<T: Transport> {
+    inner: T,
+    simulator: NetworkSimulator,
+    incoming: Pending,
+    outgoing: Pending,
+    sequence: u64,
+    inner_closed: bool,
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, simulator: NetworkSimulator) -> Self {
+        SimulatedTransport {
+            inner,
+            simulator,
+            incoming: BinaryHeap::new(),
+            outgoing: BinaryHeap::new(),
+            sequence: 0,
+            inner_closed: false,
+        }
+    }
+
+    /// Decides the fate of a frame entering the simulation, queueing whatever copies of it survive
+    fn schedule(&mut self, direction: Direction, frame: Vec<u8>) {
+        let now = Instant::now();
+        let mut state = self.simulator.state.lock().unwrap();
+        let (settings, stats) = state.direction(direction);
+        stats.frames += 1;
+
+        if chance(settings.loss_percent) {
+            stats.lost += 1;
+            return;
+        }
+        let copies = if chance(settings.duplicate_percent) {
+            stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        let pending = match direction {
+            Direction::Inbound => &mut self.incoming,
+            Direction::Outbound => &mut self.outgoing,
+        };
+        for _ in 0..copies {
+            let mut delay = jittered(settings.lag, settings.jitter);
+            if chance(settings.reorder_percent) {
+                stats.reordered += 1;
+                delay += settings.reorder_delay;
+            }
+            stats.added_delay += delay;
+            self.sequence += 1;
+            pending.push(Reverse((now + delay, self.sequence, frame.clone())));
+        }
+    }
+
+    fn pop_due(&mut self, direction: Direction) -> Option<Vec<u8>> {
+        let pending = match direction {
+            Direction::Inbound => &mut self.incoming,
+            Direction::Outbound => &mut self.outgoing,
+        };
+        if pending.peek().is_none_or(|Reverse((due, _, _))| *due > Instant::now()) {
+            return None;
+        }
+        let Reverse((_, _, frame)) = pending.pop()?;
+
+        let mut state = self.simulator.state.lock().unwrap();
+        state.direction(direction).1.delivered += 1;
+        Some(frame)
+    }
+
+    fn flush_outgoing(&mut self) -> Result<(), TransportError> {
+        while let Some(frame) = self.pop_due(Direction::Outbound) {
+            self.inner.send_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    /// How long until the next held back frame is due, if any is
+    fn next_due(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.incoming.peek().into_iter().chain(self.outgoing.peek())
+            .map(|Reverse((due, _, _))| due.saturating_duration_since(now))
+            .min()
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        if self.inner_closed {
+            return Err(TransportError::Closed);
+        }
+        self.schedule(Direction::Outbound, frame.to_vec());
+        self.flush_outgoing()
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut polled = false;
+
+        loop {
+            self.flush_outgoing()?;
+            if let Some(frame) = self.pop_due(Direction::Inbound) {
+                return Ok(Some(frame));
+            }
+            // Frames the peer sent before closing still arrive, late as they may be
+            if self.inner_closed && self.incoming.is_empty() {
+                return Err(TransportError::Closed);
+            }
+            if polled && deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                return Ok(None);
+            }
+
+            let mut wait = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+            if let Some(due) = self.next_due() {
+                wait = Some(wait.map_or(due, |wait| wait.min(due)));
+            }
+
+            if self.inner_closed {
+                thread::sleep(wait.unwrap_or_default());
+            } else {
+                match self.inner.receive_frame(wait) {
+                    Ok(Some(frame)) => self.schedule(Direction::Inbound, frame),
+                    Ok(None) => {}
+                    Err(TransportError::Closed) => self.inner_closed = true,
+                    Err(e) => return Err(e),
+                }
+            }
+            polled = true;
+        }
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        self.outgoing.clear();
+        self.inner.close()
+    }
+
+    /// The inner transport's estimate plus the lag added each way
+    fn rtt(&self) -> Option<Duration> {
+        let settings = self.simulator.settings();
+        self.inner.rtt().map(|rtt| rtt + settings.incoming.lag + settings.outgoing.lag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(200));
+
+    fn simulated(settings: NetworkSimulationSet) -> (SimulatedTransport<MemoryTransport>, MemoryTransport, NetworkSimulator) {
+        let (client, server) = MemoryTransport::pair();
+        let simulator = NetworkSimulator::new(settings);
+        (SimulatedTransport::new(client, simulator.clone()), server, simulator)
+    }
+
+    #[test]
+    fn test_jitter_stays_in_bounds() {
+        assert_eq!(jittered(Duration::from_millis(10), Duration::ZERO), Duration::from_millis(10));
+        for _ in 0..100 {
+            let delay = jittered(Duration::from_millis(10), Duration::from_millis(5));
+            assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(15));
+            assert!(jittered(Duration::ZERO, Duration::from_millis(5)) <= Duration::from_millis(5));
+        }
+    }
+
+    #[test]
+    fn test_chance_extremes() {
+        assert!((0..100).all(|_| !chance(0.0)));
+        assert!((0..100).all(|_| chance(100.0)));
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let (mut client, mut server, simulator) = simulated(NetworkSimulationSet::default());
+
+        client.send_frame(&[243, 2]).unwrap();
+        server.send_frame(&[243, 3]).unwrap();
+
+        assert_eq!(server.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 2]));
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 3]));
+        let stats = simulator.stats();
+        assert_eq!((stats.outgoing.frames, stats.outgoing.delivered), (1, 1));
+        assert_eq!((stats.incoming.frames, stats.incoming.delivered), (1, 1));
+        assert!(simulator.settings().incoming.is_passthrough());
+    }
+
+    #[test]
+    fn test_lag_in_each_direction() {
+        let settings = NetworkSimulationSet {
+            incoming: SimulationSettings { lag: Duration::from_millis(40), ..Default::default() },
+            outgoing: SimulationSettings { lag: Duration::from_millis(30), ..Default::default() },
+        };
+        let (mut client, mut server, simulator) = simulated(settings);
+
+        let started = Instant::now();
+        client.send_frame(&[243, 2]).unwrap();
+        assert_eq!(server.receive_frame(Some(Duration::from_millis(10))).unwrap(), None);
+        // Held back frames only go out while the client uses the transport
+        assert_eq!(client.receive_frame(Some(Duration::from_millis(50))).unwrap(), None);
+        assert_eq!(server.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 2]));
+        assert!(started.elapsed() >= Duration::from_millis(30));
+
+        let started = Instant::now();
+        server.send_frame(&[243, 3]).unwrap();
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 3]));
+        assert!(started.elapsed() >= Duration::from_millis(40));
+
+        assert_eq!(simulator.stats().incoming.added_delay, Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_loss_and_duplication() {
+        let settings = NetworkSimulationSet {
+            incoming: SimulationSettings { duplicate_percent: 100.0, ..Default::default() },
+            outgoing: SimulationSettings { loss_percent: 100.0, ..Default::default() },
+        };
+        let (mut client, mut server, simulator) = simulated(settings);
+
+        client.send_frame(&[243, 2]).unwrap();
+        server.send_frame(&[243, 3]).unwrap();
+
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 3]));
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 3]));
+        assert_eq!(server.receive_frame(Some(Duration::from_millis(20))).unwrap(), None);
+        let stats = simulator.stats();
+        assert_eq!((stats.outgoing.frames, stats.outgoing.lost, stats.outgoing.delivered), (1, 1, 0));
+        assert_eq!((stats.incoming.frames, stats.incoming.duplicated, stats.incoming.delivered), (1, 1, 2));
+    }
+
+    #[test]
+    fn test_reorder_lets_later_frames_overtake() {
+        let (mut client, mut server, simulator) = simulated(NetworkSimulationSet::default());
+        simulator.set_incoming(SimulationSettings {
+            reorder_percent: 100.0,
+            reorder_delay: Duration::from_millis(50),
+            ..Default::default()
+        });
+
+        server.send_frame(&[243, 1]).unwrap();
+        assert_eq!(client.receive_frame(Some(Duration::from_millis(10))).unwrap(), None);
+        // Changed while the first frame is still held back
+        simulator.set_incoming(SimulationSettings::default());
+        server.send_frame(&[243, 2]).unwrap();
+
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 2]));
+        assert_eq!(client.receive_frame(TIMEOUT).unwrap(), Some(vec![243, 1]));
+        assert_eq!(simulator.stats().incoming.reordered, 1);
+
+        simulator.reset_stats();
+        assert_eq!(simulator.stats(), NetworkSimulationStats::default());
+    }
+
+    #[test]
+    fn test_held_back_frames_arrive_before_close() {
+        let settings = NetworkSimulationSet {
+            incoming: SimulationSettings { lag: Duration::from_millis(20), ..Default::default() },
+            ..Default::default()
+        };
+        let (mut client, mut server, _) = simulated(settings);
+
+        server.send_frame(&[243, 3]).unwrap();
+        server.close().unwrap();
+
+        assert_eq!(client.receive_frame(None).unwrap(), Some(vec![243, 3]));
+        assert!(matches!(client.receive_frame(TIMEOUT), Err(TransportError::Closed)));
+        assert!(matches!(client.send_frame(&[243, 2]), Err(TransportError::Closed)));
+    }
+
+    #[test]
+    fn test_rtt_includes_lag() {
+        let settings = NetworkSimulationSet {
+            incoming: SimulationSettings { lag: Duration::from_millis(20), ..Default::default() },
+            outgoing: SimulationSettings { lag: Duration::from_millis(30), ..Default::default() },
+        };
+        let (mut client, _server, _) = simulated(settings);
+        client.inner.set_rtt(Some(Duration::from_millis(10)));
+
+        assert_eq!(client.rtt(), Some(Duration::from_millis(60)));
+    }
+}
@@ -0,0 +1,8 @@
+use crate::parameter_dictionary::ParameterDictionary;
+
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct OperationRequest {
+    pub operation_code: u8,
+    pub parameters: ParameterDictionary
+}
@@ -1,9 +1,11 @@
 use std::collections::HashMap;
 use std::collections::hash_map::{Iter, IntoIter};
 use std::ops::{Index, IndexMut};
+use serde::{Deserialize, Serialize};
 
 /// An enum representing the different types of values that can be stored in a ParameterDictionary
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value")]
 pub enum Value {
     Boolean(bool),
     Byte(u8),
@@ -132,7 +134,7 @@ mod tests {
         dict.set(3, Value::String("hello".to_string()));
 
         match dict.get(1) {
-            Some(Value::Boolean(val)) => assert_eq!(*val, true),
+            Some(Value::Boolean(val)) => assert!(*val),
             _ => panic!("Expected Boolean(true)"),
         }
 
@@ -155,7 +157,7 @@ mod tests {
         dict.set(1, Value::Boolean(true));
 
         match dict[1] {
-            Value::Boolean(val) => assert_eq!(val, true),
+            Value::Boolean(val) => assert!(val),
             _ => panic!("Expected Boolean(true)"),
         }
 
@@ -189,7 +191,7 @@ mod tests {
 
         let removed = dict.remove(1);
         match removed {
-            Some(Value::Boolean(val)) => assert_eq!(val, true),
+            Some(Value::Boolean(val)) => assert!(val),
             _ => panic!("Expected Boolean(true)"),
         }
 
@@ -220,7 +222,7 @@ mod tests {
             count += 1;
             match *key {
                 1 => match value {
-                    Value::Boolean(val) => assert_eq!(*val, true),
+                    Value::Boolean(val) => assert!(*val),
                     _ => panic!("Expected Boolean(true)"),
                 },
                 2 => match value {
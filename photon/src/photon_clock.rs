@@ -0,0 +1,113 @@
+use std::time::{Duration, Instant};
+use crate::round_trip_time::{local_timestamp, timestamp_diff};
+
+/// A local view of Photon's `ServerTimestamp`.
+///
+/// Every client connected to the same Photon server sees (approximately) the same timestamp, so
+/// it can be used as a shared time base that doesn't depend on the machines' wall clocks agreeing.
+/// Each server counts from its own start though, so two clocks are only comparable if their
+/// [`server`](Self::server) is the same. Timestamps are wrapping 32 bit milliseconds, exactly as
+/// Photon sends them.
+#[derive(Debug, Clone)]
+pub struct PhotonClock {
+    anchor: Instant,
+    anchor_server_timestamp: i32,
+    uncertainty: Duration,
+    server: String,
+}
+
+impl PhotonClock {
+    /// Builds a clock from an offset between [`local_timestamp`] and the timestamp of `server`, and
+    /// the round trip time of the sample the offset came from. [`clock`](crate::clock) is the
+    /// usual way to get one; this is for clocks synced some other way.
+    pub fn from_offset(server_time_offset: i32, round_trip_time: i32, server: &str) -> Self {
+        let anchor = Instant::now();
+        PhotonClock {
+            anchor,
+            anchor_server_timestamp: local_timestamp().wrapping_add(server_time_offset),
+            uncertainty: Duration::from_millis((round_trip_time.max(0) / 2) as u64),
+            server: server.to_string(),
+        }
+    }
+
+    /// The estimated current server timestamp
+    pub fn server_timestamp(&self) -> i32 {
+        self.timestamp_at(Instant::now())
+    }
+
+    /// The server timestamp at a local instant
+    pub fn timestamp_at(&self, instant: Instant) -> i32 {
+        let millis = match instant.checked_duration_since(self.anchor) {
+            Some(after) => after.as_millis() as i32,
+            None => -(self.anchor.duration_since(instant).as_millis() as i32),
+        };
+        self.anchor_server_timestamp.wrapping_add(millis)
+    }
+
+    /// The local instant at which the server timestamp will read `server_timestamp`.
+    ///
+    /// Targets are interpreted relative to now, so they have to be within ~24 days either way.
+    pub fn instant_at(&self, server_timestamp: i32) -> Instant {
+        let millis = timestamp_diff(server_timestamp, self.anchor_server_timestamp);
+        let offset = Duration::from_millis(millis.unsigned_abs() as u64);
+        if millis >= 0 {
+            self.anchor + offset
+        } else {
+            self.anchor.checked_sub(offset).unwrap_or(self.anchor)
+        }
+    }
+
+    /// How long until the server timestamp reads `server_timestamp`, zero if it already has
+    pub fn until(&self, server_timestamp: i32) -> Duration {
+        self.instant_at(server_timestamp).saturating_duration_since(Instant::now())
+    }
+
+    /// Upper bound on how far this clock can be from the real server timestamp
+    pub fn uncertainty(&self) -> Duration {
+        self.uncertainty
+    }
+
+    /// The address of the Photon server the timestamps came from
+    pub fn server(&self) -> &str {
+        &self.server
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instant_and_timestamp_are_inverse() {
+        let clock = PhotonClock::from_offset(5000, 40, "10.0.0.1:443");
+        let now = clock.server_timestamp();
+
+        let later = clock.instant_at(now.wrapping_add(250));
+        assert_eq!(timestamp_diff(clock.timestamp_at(later), now), 250);
+
+        let earlier = clock.instant_at(now.wrapping_sub(250));
+        assert!(earlier < later);
+        assert_eq!(clock.uncertainty(), Duration::from_millis(20));
+        assert_eq!(clock.server(), "10.0.0.1:443");
+    }
+
+    #[test]
+    fn test_instant_at_across_wraparound() {
+        let anchor = Instant::now();
+        let clock = PhotonClock {
+            anchor,
+            anchor_server_timestamp: i32::MAX - 100,
+            uncertainty: Duration::ZERO,
+            server: "10.0.0.1:443".to_string(),
+        };
+
+        assert_eq!(clock.instant_at(i32::MIN + 99), anchor + Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_until_is_zero_for_past_targets() {
+        let clock = PhotonClock::from_offset(0, 0, "10.0.0.1:443");
+        assert_eq!(clock.until(clock.server_timestamp().wrapping_sub(1000)), Duration::ZERO);
+        assert!(clock.until(clock.server_timestamp().wrapping_add(1000)) > Duration::from_millis(900));
+    }
+}
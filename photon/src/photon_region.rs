@@ -4,4 +4,45 @@ use serde::{Deserialize, Serialize};
 pub struct PhotonRegion {
     pub short_name: String,
     pub address: String,
-}
\ No newline at end of file
+}
+
+impl PhotonRegion {
+    /// The region code without the cluster, `eu` for `eu/cluster1`
+    pub fn region_code(&self) -> &str {
+        self.short_name.split('/').next().unwrap_or(&self.short_name)
+    }
+
+    /// The cluster suffix, if the name server listed one
+    pub fn cluster(&self) -> Option<&str> {
+        self.short_name.split_once('/').map(|(_, cluster)| cluster)
+    }
+
+    /// Whether `code` names this region. A plain region code matches every cluster of the region,
+    /// a full `region/cluster` name only that cluster. Photon's codes are case insensitive.
+    pub fn matches(&self, code: &str) -> bool {
+        if code.contains('/') {
+            self.short_name.eq_ignore_ascii_case(code)
+        } else {
+            self.region_code().eq_ignore_ascii_case(code)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cluster_suffix() {
+        let region = PhotonRegion { short_name: "eu/cluster1".to_string(), address: String::new() };
+        assert_eq!(region.region_code(), "eu");
+        assert_eq!(region.cluster(), Some("cluster1"));
+        assert!(region.matches("EU"));
+        assert!(region.matches("eu/cluster1"));
+        assert!(!region.matches("eu/cluster2"));
+
+        let region = PhotonRegion { short_name: "us".to_string(), address: String::new() };
+        assert_eq!(region.cluster(), None);
+        assert!(!region.matches("usw"));
+    }
+}
@@ -0,0 +1,220 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crate::network_simulation::{chance, jittered};
+use crate::photon_region::PhotonRegion;
+use crate::pinger::PING_BYTES;
+
+/// How often the responder checks whether it was stopped while no replies are due
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How a [`PingResponder`] degrades its answers
+#[derive(Debug, Clone, Default)]
+pub struct PingResponderOptions {
+    /// Added to every reply
+    pub delay: Duration,
+    /// Random spread around `delay`, so replies may overtake each other
+    pub jitter: Duration,
+    /// Share of pings that are never answered, from 0 to 100
+    pub drop_percent: f64,
+}
+
+/// Counters of what a [`PingResponder`] has done
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PingResponderStats {
+    /// Photon pings received
+    pub received: u64,
+    pub answered: u64,
+    pub dropped: u64,
+    /// Packets that weren't Photon pings
+    pub ignored: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    answered: AtomicU64,
+    dropped: AtomicU64,
+    ignored: AtomicU64,
+}
+
+/// Answers Photon's UDP pings like a region's game server would, with configurable delay,
+/// jitter and loss.
+///
+/// It lets region ping logic be tested offline and can stand in for fake regions on a LAN. Use
+/// [`PingResponder::region`] to get a [`PhotonRegion`] that pings it.
+pub struct PingResponder {
+    address: SocketAddr,
+    counters: Arc<Counters>,
+    stopped: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl PingResponder {
+    /// Starts the responder on a free port on the loopback interface
+    pub fn start(options: PingResponderOptions) -> io::Result<Self> {
+        Self::bind("127.0.0.1:0", options)
+    }
+
+    pub fn bind<A: ToSocketAddrs>(address: A, options: PingResponderOptions) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        let address = socket.local_addr()?;
+        let counters = Arc::new(Counters::default());
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread_counters = counters.clone();
+        let thread_stopped = stopped.clone();
+        let thread = thread::spawn(move || {
+            if let Err(e) = respond(&socket, &options, &thread_counters, &thread_stopped) {
+                println!("Ping responder on {} stopped: {}", address, e);
+            }
+        });
+
+        Ok(PingResponder { address, counters, stopped, thread: Some(thread) })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// A region whose pings go to this responder
+    pub fn region(&self, short_name: &str) -> PhotonRegion {
+        PhotonRegion { short_name: short_name.to_string(), address: format!("udp://{}", self.address) }
+    }
+
+    pub fn stats(&self) -> PingResponderStats {
+        PingResponderStats {
+            received: self.counters.received.load(Ordering::Relaxed),
+            answered: self.counters.answered.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            ignored: self.counters.ignored.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Stops answering. Replies that are still delayed are not sent.
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for PingResponder {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Whether `packet` is a Photon ping: twelve 0x7d bytes and an id
+fn is_ping(packet: &[u8]) -> bool {
+    packet.len() == PING_BYTES.len() && packet[..12] == PING_BYTES[..12]
+}
+
+fn respond(socket: &UdpSocket, options: &PingResponderOptions, counters: &Counters, stopped: &AtomicBool) -> io::Result<()> {
+    // Delayed replies, earliest first
+    let mut pending: BinaryHeap<Reverse<(Instant, SocketAddr, Vec<u8>)>> = BinaryHeap::new();
+    let mut buffer = [0u8; 64];
+
+    while !stopped.load(Ordering::Relaxed) {
+        while let Some(Reverse((due, _, _))) = pending.peek() && *due <= Instant::now() {
+            let Some(Reverse((_, to, reply))) = pending.pop() else { break };
+            // The client may have gone away, that's no reason to stop answering others
+            if socket.send_to(&reply, to).is_ok() {
+                counters.answered.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        let timeout = match pending.peek() {
+            Some(Reverse((due, _, _))) => due.saturating_duration_since(Instant::now()).clamp(Duration::from_millis(1), POLL_INTERVAL),
+            None => POLL_INTERVAL,
+        };
+        socket.set_read_timeout(Some(timeout))?;
+
+        let (len, from) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            // An ICMP port unreachable for an earlier reply, on platforms that report them
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+            Err(e) => return Err(e),
+        };
+
+        if !is_ping(&buffer[..len]) {
+            counters.ignored.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        counters.received.fetch_add(1, Ordering::Relaxed);
+
+        if chance(options.drop_percent) {
+            counters.dropped.fetch_add(1, Ordering::Relaxed);
+            continue;
+        }
+        // Jitter may reorder replies, as it would on a real network
+        let delay = jittered(options.delay, options.jitter);
+        pending.push(Reverse((Instant::now() + delay, from, buffer[..len].to_vec())));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pinger::Pinger;
+
+    #[test]
+    fn test_answers_pings() {
+        let responder = PingResponder::start(PingResponderOptions::default()).unwrap();
+
+        let stats = Pinger::new(&responder.region("local")).unwrap().start_ping(3).unwrap();
+
+        assert_eq!(stats.received, 3);
+        assert_eq!(stats.endpoint, Some(responder.address()));
+        // Including the warm-up ping
+        let responder_stats = responder.stats();
+        assert_eq!(responder_stats.received, 4);
+        assert_eq!(responder_stats.dropped, 0);
+    }
+
+    #[test]
+    fn test_delay() {
+        let options = PingResponderOptions { delay: Duration::from_millis(30), ..Default::default() };
+        let responder = PingResponder::start(options).unwrap();
+
+        let stats = Pinger::new(&responder.region("local")).unwrap().start_ping(2).unwrap();
+
+        assert_eq!(stats.received, 2);
+        assert!(stats.min_us >= 30_000);
+    }
+
+    #[test]
+    fn test_drops_everything() {
+        let options = PingResponderOptions { drop_percent: 100.0, ..Default::default() };
+        let responder = PingResponder::start(options).unwrap();
+        let mut pinger = Pinger::new(&responder.region("local")).unwrap();
+        pinger.set_timeout(Duration::from_millis(20));
+
+        let stats = pinger.start_ping(2).unwrap();
+
+        assert_eq!(stats.received, 0);
+        assert_eq!(responder.stats().dropped, 3);
+    }
+
+    #[test]
+    fn test_ignores_other_packets() {
+        let responder = PingResponder::start(PingResponderOptions::default()).unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+
+        socket.send_to(b"hello", responder.address()).unwrap();
+
+        let mut buffer = [0u8; 64];
+        assert!(socket.recv_from(&mut buffer).is_err());
+        assert_eq!(responder.stats().ignored, 1);
+    }
+}
@@ -0,0 +1,169 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+/// How a region's latency was measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PingMethod {
+    /// Photon's UDP ping on port 5055
+    #[default]
+    Udp,
+    /// Photon's internal PING operation over a wss connection on port 443
+    WebSocket,
+    /// Time to establish a TCP connection on port 443
+    TcpConnect,
+}
+
+/// Summary of one ping run against a region, all times in microseconds
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PingStats {
+    /// Where the samples came from
+    #[serde(default)]
+    pub method: PingMethod,
+    /// The address that was pinged, if known
+    #[serde(default)]
+    pub endpoint: Option<SocketAddr>,
+    /// Round trip of every answered ping, in the order they were sent
+    pub samples_us: Vec<u64>,
+    pub min_us: u64,
+    pub max_us: u64,
+    pub mean_us: u64,
+    pub median_us: u64,
+    pub p95_us: u64,
+    pub std_dev_us: u64,
+    /// Mean absolute difference between consecutive samples, as in RFC 3550
+    pub jitter_us: u64,
+    /// Pings sent, not counting the discarded warm-up ping
+    pub sent: u32,
+    /// Pings that were answered
+    pub received: u32,
+}
+
+impl PingStats {
+    /// Computes the statistics of the answered UDP pings out of `sent`
+    pub fn from_samples(samples: &[Duration], sent: u32) -> Self {
+        Self::from_method_samples(PingMethod::Udp, samples, sent)
+    }
+
+    /// Computes the statistics of answered pings measured with `method`
+    pub fn from_method_samples(method: PingMethod, samples: &[Duration], sent: u32) -> Self {
+        let samples_us: Vec<u64> = samples.iter().map(|sample| sample.as_micros() as u64).collect();
+        let received = samples_us.len() as u32;
+        if samples_us.is_empty() {
+            return PingStats { method, sent, ..PingStats::default() };
+        }
+
+        let mut sorted = samples_us.clone();
+        sorted.sort_unstable();
+        let count = sorted.len();
+
+        let mean = sorted.iter().sum::<u64>() as f64 / count as f64;
+        let variance = sorted.iter().map(|&sample| (sample as f64 - mean).powi(2)).sum::<f64>() / count as f64;
+        let median = if count.is_multiple_of(2) {
+            (sorted[count / 2 - 1] + sorted[count / 2]) / 2
+        } else {
+            sorted[count / 2]
+        };
+        let jitter = if count > 1 {
+            samples_us.windows(2).map(|pair| pair[0].abs_diff(pair[1])).sum::<u64>() / (count as u64 - 1)
+        } else {
+            0
+        };
+
+        PingStats {
+            method,
+            endpoint: None,
+            min_us: sorted[0],
+            max_us: sorted[count - 1],
+            mean_us: mean.round() as u64,
+            median_us: median,
+            p95_us: percentile(&sorted, 95),
+            std_dev_us: variance.sqrt().round() as u64,
+            jitter_us: jitter,
+            sent: sent.max(received),
+            received,
+            samples_us,
+        }
+    }
+
+    /// Percentage of pings that weren't answered
+    pub fn loss_percent(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        (self.sent - self.received) as f64 * 100.0 / self.sent as f64
+    }
+
+    /// Whether any ping was answered at all
+    pub fn is_reachable(&self) -> bool {
+        self.received > 0
+    }
+
+    /// The mean in whole milliseconds, rounded, for code that still deals in milliseconds
+    pub fn mean_ms(&self) -> u128 {
+        ((self.mean_us + 500) / 1000) as u128
+    }
+
+    /// The 95th percentile in whole milliseconds, rounded up
+    pub fn p95_ms(&self) -> u128 {
+        self.p95_us.div_ceil(1000) as u128
+    }
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], percent: usize) -> u64 {
+    let rank = (percent * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn micros(samples: &[u64]) -> Vec<Duration> {
+        samples.iter().map(|&sample| Duration::from_micros(sample)).collect()
+    }
+
+    #[test]
+    fn test_from_samples() {
+        let stats = PingStats::from_samples(&micros(&[1000, 3000, 2000, 4000]), 5);
+
+        assert_eq!(stats.min_us, 1000);
+        assert_eq!(stats.max_us, 4000);
+        assert_eq!(stats.mean_us, 2500);
+        assert_eq!(stats.median_us, 2500);
+        assert_eq!(stats.p95_us, 4000);
+        assert_eq!(stats.std_dev_us, 1118);
+        // |1000-3000| + |3000-2000| + |2000-4000| over 3
+        assert_eq!(stats.jitter_us, 1666);
+        assert_eq!(stats.received, 4);
+        assert_eq!(stats.loss_percent(), 20.0);
+        assert_eq!(stats.mean_ms(), 3);
+        assert_eq!(stats.p95_ms(), 4);
+    }
+
+    #[test]
+    fn test_sub_millisecond_precision() {
+        let stats = PingStats::from_samples(&micros(&[150, 250, 200]), 3);
+
+        assert_eq!(stats.median_us, 200);
+        assert_eq!(stats.mean_us, 200);
+        assert_eq!(stats.loss_percent(), 0.0);
+    }
+
+    #[test]
+    fn test_percentile_nearest_rank() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 95), 95);
+        assert_eq!(percentile(&sorted[..1], 95), 1);
+        assert_eq!(percentile(&sorted[..10], 95), 10);
+    }
+
+    #[test]
+    fn test_no_answers() {
+        let stats = PingStats::from_samples(&[], 4);
+
+        assert!(!stats.is_reachable());
+        assert_eq!(stats.loss_percent(), 100.0);
+    }
+}
@@ -1,59 +1,326 @@
+use std::fmt;
+use std::io;
 use std::net::{SocketAddr, UdpSocket};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use dns_lookup::lookup_host;
-use websocket::url::Url;
+use websocket::url::{Host, Url};
 use rand::{thread_rng, Rng};
 use crate::photon_region::PhotonRegion;
+use crate::ping_stats::PingStats;
+
+/// How long a single probe waits for its reply before it counts as lost
+pub(crate) const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_millis(1000);
+/// The UDP port Photon servers answer pings on
+pub const PING_PORT: u16 = 5055;
+/// A Photon ping is twelve 0x7d bytes followed by an id the server echoes back
+pub(crate) const PING_BYTES: [u8; 13] = [0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x00];
+
+/// Errors that prevent a region from being pinged at all. Lost probes aren't errors, they are
+/// counted in [`PingStats`].
+#[derive(Debug)]
+pub enum PingError {
+    /// The region's address isn't a URL with a host
+    InvalidAddress(String),
+    /// The host name couldn't be resolved
+    Resolve(String),
+    /// The UDP socket couldn't be set up or used
+    Socket(io::Error),
+    /// The websocket connection or its Photon handshake failed
+    WebSocket(String),
+}
+
+impl fmt::Display for PingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PingError::InvalidAddress(e) => write!(f, "invalid region address: {}", e),
+            PingError::Resolve(e) => write!(f, "failed to resolve region host: {}", e),
+            PingError::Socket(e) => write!(f, "socket error: {}", e),
+            PingError::WebSocket(e) => write!(f, "websocket error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PingError {}
+
+impl From<io::Error> for PingError {
+    fn from(e: io::Error) -> Self {
+        PingError::Socket(e)
+    }
+}
+
+/// Which addresses and port a region is pinged on
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PingEndpointOptions {
+    /// Overrides the port, see [`ping_port`] for what is used otherwise
+    pub port: Option<u16>,
+    /// Ping every address the host resolves to instead of only the first one
+    pub all_addresses: bool,
+}
 
 pub struct Pinger {
     endpoint: SocketAddr,
     ping_bytes: [u8; 13],
-    name: String
+    name: String,
+    timeout: Duration,
 }
 
 impl Pinger {
-    pub fn new(photon_region: &PhotonRegion) -> Self {
-        let url = Url::parse(&*photon_region.address).unwrap();
-        let host = url.host_str().unwrap();
-        let ips = lookup_host(host).unwrap();
-        
+    pub fn new(photon_region: &PhotonRegion) -> Result<Self, PingError> {
+        Pinger::with_port(photon_region, None)
+    }
+
+    /// Pings the first address of the region on `port`, or on [`ping_port`] if `None`
+    pub fn with_port(photon_region: &PhotonRegion, port: Option<u16>) -> Result<Self, PingError> {
+        let options = PingEndpointOptions { port, all_addresses: false };
+        let endpoint = resolve_ping_endpoints(photon_region, options)?[0];
+        Ok(Pinger::with_endpoint(endpoint, &photon_region.short_name))
+    }
+
+    /// One pinger per endpoint of the region, so dual-stack hosts can be compared address by address
+    pub fn for_endpoints(photon_region: &PhotonRegion, options: PingEndpointOptions) -> Result<Vec<Self>, PingError> {
+        Ok(resolve_ping_endpoints(photon_region, options)?
+            .into_iter()
+            .map(|endpoint| Pinger::with_endpoint(endpoint, &photon_region.short_name))
+            .collect())
+    }
+
+    /// The address and port the probes are sent to
+    pub fn endpoint(&self) -> SocketAddr {
+        self.endpoint
+    }
+
+    fn with_endpoint(endpoint: SocketAddr, name: &str) -> Self {
         Pinger {
-            endpoint: SocketAddr::new(ips[0], 5055),
-            ping_bytes: [0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x7d, 0x00],
-            name: photon_region.short_name.clone()
+            endpoint,
+            ping_bytes: PING_BYTES,
+            name: name.to_string(),
+            timeout: DEFAULT_PROBE_TIMEOUT,
         }
     }
-    
+
+    /// Sets how long each probe waits for its reply
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
     fn gen_random_cur_id() -> u8 {
         thread_rng().gen_range(0, 255)
     }
-    
-    fn ping(&self, id: u8, socket: &UdpSocket) -> u128 {
-        let mut temp_ping_bytes = self.ping_bytes.clone();
+
+    /// Sends one probe and waits for the reply carrying the same id. Returns `Ok(None)` if it
+    /// didn't arrive within the timeout. Late replies to earlier probes are skipped.
+    fn ping(&self, id: u8, socket: &UdpSocket) -> Result<Option<Duration>, PingError> {
+        let mut temp_ping_bytes = self.ping_bytes;
         temp_ping_bytes[12] = id;
-        
+
         let start_time = Instant::now();
-        socket.send(&temp_ping_bytes).unwrap();
-        socket.recv(&mut temp_ping_bytes).unwrap();
-        
-        if id != temp_ping_bytes[12] {
-            panic!("{}: cur_id mismatch", self.name);
+        socket.send(&temp_ping_bytes)?;
+
+        let mut reply = [0u8; 64];
+        loop {
+            let remaining = match self.timeout.checked_sub(start_time.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => return Ok(None),
+            };
+            socket.set_read_timeout(Some(remaining))?;
+
+            match socket.recv(&mut reply) {
+                Ok(len) if len >= 13 && reply[12] == id => return Ok(Some(start_time.elapsed())),
+                Ok(_) => {
+                    if cfg!(debug_assertions) {
+                        println!("{}: discarding stale ping reply", self.name);
+                    }
+                }
+                Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => return Ok(None),
+                // An ICMP port unreachable from an earlier probe, the probe itself is lost
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
         }
-        
-        start_time.elapsed().as_millis()
-    }
-    
-    pub fn start_ping(&self, sample_size: i32) -> u128 {
-        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-        socket.connect(&self.endpoint).unwrap();
-        
-        let mut samples: Vec<u128> = Vec::with_capacity(sample_size as usize);
+    }
+
+    /// Pings the region `sample_size` times. One extra warm-up ping is sent first and discarded,
+    /// as it also pays for ARP/route lookups and waking up the network stack.
+    pub fn start_ping(&self, sample_size: i32) -> Result<PingStats, PingError> {
+        let socket = if self.endpoint.is_ipv6() {
+            UdpSocket::bind("[::]:0")?
+        } else {
+            UdpSocket::bind("0.0.0.0:0")?
+        };
+        socket.connect(self.endpoint)?;
+
+        // Consecutive ids, so a late reply to one probe can't be mistaken for the next
+        let mut id = Pinger::gen_random_cur_id();
+        self.ping(id, &socket)?;
+
+        let mut samples: Vec<Duration> = Vec::with_capacity(sample_size.max(0) as usize);
         for _ in 0..sample_size {
-            let random_id = Pinger::gen_random_cur_id();
-            samples.push(self.ping(random_id, &socket));
+            id = id.wrapping_add(1);
+            if let Some(sample) = self.ping(id, &socket)? {
+                samples.push(sample);
+            }
         }
-        
-        let avg = samples.iter().sum::<u128>() / sample_size as u128;
-        avg
+
+        let mut stats = PingStats::from_samples(&samples, sample_size.max(0) as u32);
+        stats.endpoint = Some(self.endpoint);
+        Ok(stats)
+    }
+}
+
+/// The result of pinging one of a region's endpoints
+pub type EndpointPing = (SocketAddr, Result<PingStats, PingError>);
+
+/// Pings every endpoint of a region one after another and reports each one separately
+pub fn ping_each_endpoint(photon_region: &PhotonRegion, options: PingEndpointOptions, sample_size: i32) -> Result<Vec<EndpointPing>, PingError> {
+    Ok(Pinger::for_endpoints(photon_region, options)?
+        .into_iter()
+        .map(|pinger| (pinger.endpoint(), pinger.start_ping(sample_size)))
+        .collect())
+}
+
+/// The UDP port a region is pinged on: the override if there is one, the port of a
+/// `udp://host:port` address, or [`PING_PORT`]. The port of a ws/wss address is the websocket
+/// port, which Photon doesn't answer UDP pings on.
+pub fn ping_port(photon_region: &PhotonRegion, port: Option<u16>) -> Result<u16, PingError> {
+    if let Some(port) = port {
+        return Ok(port);
     }
-}
\ No newline at end of file
+
+    let url = parse_address(photon_region)?;
+    Ok(match url.scheme() {
+        "udp" => url.port().unwrap_or(PING_PORT),
+        _ => PING_PORT,
+    })
+}
+
+/// Resolves the endpoints a region is pinged on, in the order the resolver returned them
+pub fn resolve_ping_endpoints(photon_region: &PhotonRegion, options: PingEndpointOptions) -> Result<Vec<SocketAddr>, PingError> {
+    let host = region_host(photon_region)?;
+    let port = ping_port(photon_region, options.port)?;
+    let ips = lookup_host(&host).map_err(|e| PingError::Resolve(format!("{}: {}", host, e)))?;
+    if ips.is_empty() {
+        return Err(PingError::Resolve(format!("{}: no addresses", host)));
+    }
+
+    let count = if options.all_addresses { ips.len() } else { 1 };
+    Ok(ips.into_iter().take(count).map(|ip| SocketAddr::new(ip, port)).collect())
+}
+
+fn parse_address(photon_region: &PhotonRegion) -> Result<Url, PingError> {
+    Url::parse(&photon_region.address)
+        .map_err(|e| PingError::InvalidAddress(format!("{}: {}", photon_region.address, e)))
+}
+
+/// The host of a region's address, with IPv6 literals unbracketed so they can be resolved
+pub(crate) fn region_host(photon_region: &PhotonRegion) -> Result<String, PingError> {
+    match parse_address(photon_region)?.host() {
+        Some(Host::Domain(domain)) => Ok(domain.to_string()),
+        Some(Host::Ipv4(ip)) => Ok(ip.to_string()),
+        Some(Host::Ipv6(ip)) => Ok(ip.to_string()),
+        None => Err(PingError::InvalidAddress(format!("{}: no host", photon_region.address))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    /// Answers probes like a Photon server, except that it drops the ones whose id is in `drop`
+    /// and sends a stale reply before every answer
+    fn responder(drop: Vec<u8>) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                let id = buffer[12];
+                if drop.contains(&id) {
+                    continue;
+                }
+                let mut stale = buffer;
+                stale[12] = id.wrapping_sub(100);
+                let _ = socket.send_to(&stale[..len], from);
+                let _ = socket.send_to(&buffer[..len], from);
+            }
+        });
+        address
+    }
+
+    fn region(address: &str) -> PhotonRegion {
+        PhotonRegion { short_name: "local".to_string(), address: address.to_string() }
+    }
+
+    #[test]
+    fn test_ping_port() {
+        assert_eq!(ping_port(&region("wss://eu.example:19090"), None).unwrap(), PING_PORT);
+        assert_eq!(ping_port(&region("udp://eu.example:5058"), None).unwrap(), 5058);
+        assert_eq!(ping_port(&region("wss://eu.example:19090"), Some(27000)).unwrap(), 27000);
+    }
+
+    #[test]
+    fn test_resolve_ipv6_literal() {
+        let endpoints = resolve_ping_endpoints(&region("wss://[::1]:19090"), PingEndpointOptions::default()).unwrap();
+        assert_eq!(endpoints, vec!["[::1]:5055".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_ping_ipv6_endpoint() {
+        // Not every test machine has IPv6 configured
+        let Ok(socket) = UdpSocket::bind("[::1]:0") else { return };
+        let address = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buffer = [0u8; 64];
+            while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                let _ = socket.send_to(&buffer[..len], from);
+            }
+        });
+
+        let pinger = Pinger::with_port(&region("udp://[::1]"), Some(address.port())).unwrap();
+        let stats = pinger.start_ping(2).unwrap();
+
+        assert_eq!(stats.received, 2);
+        assert_eq!(stats.endpoint, Some(address));
+    }
+
+    #[test]
+    fn test_ping_each_endpoint() {
+        let address = responder(Vec::new());
+        let options = PingEndpointOptions { port: Some(address.port()), all_addresses: true };
+
+        let results = ping_each_endpoint(&region("udp://127.0.0.1"), options, 2).unwrap();
+
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].0, address);
+        assert_eq!(results[0].1.as_ref().unwrap().received, 2);
+    }
+
+    #[test]
+    fn test_invalid_address() {
+        let region = PhotonRegion { short_name: "eu".to_string(), address: "not a url".to_string() };
+        assert!(matches!(Pinger::new(&region), Err(PingError::InvalidAddress(_))));
+    }
+
+    #[test]
+    fn test_stale_replies_are_discarded() {
+        let pinger = Pinger::with_endpoint(responder(Vec::new()), "local");
+        let stats = pinger.start_ping(5).unwrap();
+
+        assert_eq!(stats.sent, 5);
+        assert_eq!(stats.received, 5);
+    }
+
+    #[test]
+    fn test_lost_probes_time_out() {
+        // Every id is dropped, so nothing ever comes back
+        let mut pinger = Pinger::with_endpoint(responder((0..=255).collect()), "local");
+        pinger.set_timeout(Duration::from_millis(20));
+
+        let start = Instant::now();
+        let stats = pinger.start_ping(3).unwrap();
+
+        assert_eq!(stats.received, 0);
+        assert_eq!(stats.loss_percent(), 100.0);
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -1,25 +1,43 @@
+use std::collections::HashMap;
+use crate::decode::DecodeError;
 use crate::gp_type::{GpType};
+use crate::disconnect_message::DisconnectMessage;
+use crate::operation_request::OperationRequest;
 use crate::operation_response::OperationResponse;
 use crate::parameter_dictionary::{ParameterDictionary, Value};
 use crate::stream_buffer::StreamBuffer;
 
-fn read_byte(stream: &mut StreamBuffer) -> u8 {
-    stream.read_byte()
+/// The type each parameter had on the wire, by code. The [`Value`] alone doesn't tell e.g. an
+/// `Int1` from a `CompressedInt`.
+pub(crate) type WireTypes = HashMap<u8, GpType>;
+
+fn read_byte(stream: &mut StreamBuffer) -> Result<u8, DecodeError> {
+    stream.try_read_byte()
+        .ok_or_else(|| DecodeError::Malformed(format!("ends at byte {} where more was expected", stream.position())))
+}
+
+fn read_bytes(stream: &mut StreamBuffer, count: usize) -> Result<Vec<u8>, DecodeError> {
+    if stream.remaining() < count {
+        return Err(DecodeError::Malformed(format!(
+            "needs {} bytes at byte {}, but only {} are left", count, stream.position(), stream.remaining()
+        )));
+    }
+    Ok(stream.read(count))
 }
 
-fn read_int16(stream: &mut StreamBuffer) -> i16 {
-    let byte1 = stream.read_byte();
-    let byte2 = stream.read_byte();
+fn read_int16(stream: &mut StreamBuffer) -> Result<i16, DecodeError> {
+    let byte1 = read_byte(stream)?;
+    let byte2 = read_byte(stream)?;
 
     // Combine the bytes to form a 16-bit integer (little-endian)
     // byte1 is the low byte, byte2 is the high byte
-    (byte1 as i16) | ((byte2 as i16) << 8)
+    Ok((byte1 as i16) | ((byte2 as i16) << 8))
 }
 
 fn write_byte(stream: &mut StreamBuffer, value: u8, write_type: bool) {
     if write_type {
         if value == 0 {
-            stream.write_gp_type(GpType::FloatZero);
+            stream.write_gp_type(GpType::ByteZero);
             return;
         }
         stream.write_gp_type(GpType::Byte);
@@ -110,55 +128,131 @@ fn write_int_length(stream: &mut StreamBuffer, value: usize) {
     write_compressed_uint32_to_stream(stream, value as u32);
 }
 
-fn write_string(stream: &mut StreamBuffer, value: &str, write_type: bool) {
-    if write_type { 
-        stream.write_gp_type(GpType::String);
-    }
-    
+fn write_string(stream: &mut StreamBuffer, value: &str, write_type: bool) -> Result<(), DecodeError> {
     let count = value.len();
     if count > 32767 {
-        panic!("String length exceeds maximum allowed length");
+        return Err(DecodeError::Unsupported(format!("strings of {} bytes, the most is 32767", count)));
+    }
+
+    if write_type { 
+        stream.write_gp_type(GpType::String);
     }
     write_int_length(stream, count);
     stream.write(value.as_bytes());
+    Ok(())
+}
+
+fn write_string_array(stream: &mut StreamBuffer, value: &[String], write_type: bool) -> Result<(), DecodeError> {
+    if write_type {
+        stream.write_gp_type(GpType::StringArray);
+    }
+
+    write_int_length(stream, value.len());
+    for s in value {
+        write_string(stream, s, false)?;
+    }
+    Ok(())
 }
 
-fn write(stream: &mut StreamBuffer, value: &Value, write_type: bool) {
+/// Writes a value, or fails for the types we can read but not write
+fn write(stream: &mut StreamBuffer, value: &Value, write_type: bool) -> Result<(), DecodeError> {
     match value {
         Value::Int(value) => {
             write_compressed_int(stream, *value, write_type);
         }
         Value::String(value) => {
-            write_string(stream, value, write_type);
+            write_string(stream, value, write_type)?;
+        }
+        Value::StringArray(value) => {
+            write_string_array(stream, value, write_type)?;
+        }
+        Value::Null => {
+            stream.write_gp_type(GpType::Null);
+        }
+        Value::Boolean(value) => {
+            if write_type {
+                stream.write_gp_type(if *value { GpType::BooleanTrue } else { GpType::BooleanFalse });
+            } else {
+                stream.write_byte(*value as u8);
+            }
+        }
+        Value::Byte(value) => {
+            write_byte(stream, *value, write_type);
+        }
+        Value::Short(value) => {
+            if write_type {
+                stream.write_gp_type(GpType::Short);
+            }
+            write_ushort(stream, *value as u16);
+        }
+        Value::ByteArray(value) => {
+            if write_type {
+                stream.write_gp_type(GpType::ByteArray);
+            }
+            write_int_length(stream, value.len());
+            stream.write(value);
+        }
+        Value::Long(_) | Value::Float(_) | Value::Double(_) => {
+            return Err(DecodeError::Unsupported(format!("encoding {:?}", value)));
         }
-        _ => {panic!("Not implemented");}
     }
+    Ok(())
 }
 
-fn write_parameter_table(stream: &mut StreamBuffer, parameters: ParameterDictionary) {
+fn write_parameter_table(stream: &mut StreamBuffer, parameters: &ParameterDictionary) -> Result<(), DecodeError> {
     let parameters_length = parameters.iter().len() as u8;
     if parameters_length == 0 {
         write_byte(stream, 0, false);
-        return;
+        return Ok(());
     }
 
     write_byte(stream, parameters_length, false);
     for parameter in parameters.iter() {
         stream.write_byte(*parameter.0);
-        write(stream, parameter.1, true);
+        write(stream, parameter.1, true).map_err(|e| match e {
+            DecodeError::Unsupported(what) => DecodeError::Unsupported(format!("parameter {}: {}", parameter.0, what)),
+            other => other,
+        })?;
     }
+    Ok(())
 }
 
-pub fn serialize_operation_request(stream: &mut StreamBuffer, opcode: u8, parameters: ParameterDictionary, set_type: bool) {
+/// Fails with [`DecodeError::Unsupported`] for parameters we can't write, leaving `stream` part written
+pub fn serialize_operation_request(stream: &mut StreamBuffer, opcode: u8, parameters: ParameterDictionary, set_type: bool) -> Result<(), DecodeError> {
     if set_type {
         stream.write_gp_type(GpType::OperationRequest);
     }
 
     stream.write_byte(opcode);
-    write_parameter_table(stream, parameters);
+    write_parameter_table(stream, &parameters)
+}
+
+/// Fails with [`DecodeError::Unsupported`] for parameters we can't write, leaving `stream` part written
+pub fn serialize_operation_response(stream: &mut StreamBuffer, response: &OperationResponse, set_type: bool) -> Result<(), DecodeError> {
+    if set_type {
+        stream.write_gp_type(GpType::OperationResponse);
+    }
+
+    stream.write_byte(response.operation_code);
+    write_ushort(stream, response.return_code as u16);
+    match &response.debug_message {
+        Some(message) => write_string(stream, message, true)?,
+        None => stream.write_gp_type(GpType::Null),
+    }
+    write_parameter_table(stream, &response.payload)
 }
 
-fn read_compressed_uint32(stream: &mut StreamBuffer) -> u32 {
+/// Fails with [`DecodeError::Unsupported`] for parameters we can't write, leaving `stream` part written
+pub fn serialize_disconnect_message(stream: &mut StreamBuffer, message: &DisconnectMessage) -> Result<(), DecodeError> {
+    write_ushort(stream, message.code as u16);
+    match &message.debug_message {
+        Some(debug_message) => write_string(stream, debug_message, true)?,
+        None => stream.write_gp_type(GpType::Null),
+    }
+    write_parameter_table(stream, &message.parameters)
+}
+
+fn read_compressed_uint32(stream: &mut StreamBuffer) -> Result<u32, DecodeError> {
     let mut num1: u32 = 0;
     let mut num2: i32 = 0;
 
@@ -170,15 +264,14 @@ fn read_compressed_uint32(stream: &mut StreamBuffer) -> u32 {
 
     while num2 != 35 {
         if position >= stream_length {
-            // Update the stream position before panicking
+            // Update the stream position before failing
             stream.seek(stream_length);
 
             // Format the error message to match the C# implementation
-            let error_msg = format!(
+            return Err(DecodeError::Malformed(format!(
                 "Failed to read full uint. offset: {} stream.Length: {} data.Length: {} stream.Available: {}",
                 position, stream_length, buffer_len, stream_length - position
-            );
-            panic!("{}", error_msg);
+            )));
         }
 
         let num4 = buffer[position];
@@ -193,92 +286,135 @@ fn read_compressed_uint32(stream: &mut StreamBuffer) -> u32 {
 
     // Update the stream position
     stream.seek(position);
-    num1
+    Ok(num1)
 }
 
-fn read_compressed_int32(stream: &mut StreamBuffer) -> i32 {
-    decode_zigzag32(read_compressed_uint32(stream))
+fn read_compressed_int32(stream: &mut StreamBuffer) -> Result<i32, DecodeError> {
+    Ok(decode_zigzag32(read_compressed_uint32(stream)?))
 }
 
-fn read_string_array(stream: &mut StreamBuffer) -> Vec<String> {
-    let length = read_compressed_uint32(stream) as usize;
-    let mut strings = Vec::with_capacity(length);
+fn read_string_data(stream: &mut StreamBuffer) -> Result<String, DecodeError> {
+    let length = read_compressed_uint32(stream)? as usize;
+    let bytes = read_bytes(stream, length)?;
+    String::from_utf8(bytes).map_err(|_| DecodeError::Malformed("invalid UTF-8 string data".to_string()))
+}
+
+fn read_string_array(stream: &mut StreamBuffer) -> Result<Vec<String>, DecodeError> {
+    let length = read_compressed_uint32(stream)? as usize;
+    // Not with_capacity(length), the length is whatever the sender says it is
+    let mut strings = Vec::new();
     for _ in 0..length {
-        let length = read_compressed_uint32(stream) as usize;
-        let bytes = stream.read(length);
-        match String::from_utf8(bytes) {
-            Ok(s) => strings.push(s),
-            Err(_) => panic!("Invalid UTF-8 string data"),
-        }
+        strings.push(read_string_data(stream)?);
     }
-    strings
+    Ok(strings)
 }
 
-fn read(stream: &mut StreamBuffer, gp_type: u8) -> Value {
-    if gp_type >= 128 && gp_type <= 228 {
-        // Custom type
-        panic!("Custom types not implemented")
+fn read_gp_type(stream: &mut StreamBuffer) -> Result<GpType, DecodeError> {
+    let gp_type = read_byte(stream)?;
+    if (128..=228).contains(&gp_type) {
+        return Err(DecodeError::Unsupported(format!("custom type {}", gp_type)));
     }
+    GpType::try_from(gp_type).map_err(|_| DecodeError::Malformed(format!("unknown type {}", gp_type)))
+}
 
-    match GpType::try_from(gp_type).unwrap() {
-        GpType::Int1 => Value::Int(read_byte(stream) as i32),
-        GpType::Byte => Value::Byte(read_byte(stream)),
-        GpType::Short => Value::Int(read_int16(stream) as i32),
-        GpType::String => {
-            // Read string length as a compressed int
-            let length = read_compressed_uint32(stream) as usize;
-            if length == 0 {
-                return Value::String(String::new());
-            }
-
-            // Read string data
-            let bytes = stream.read(length);
-            match String::from_utf8(bytes) {
-                Ok(s) => Value::String(s),
-                Err(_) => panic!("Invalid UTF-8 string data"),
-            }
-        }
+fn read(stream: &mut StreamBuffer, gp_type: GpType) -> Result<Value, DecodeError> {
+    Ok(match gp_type {
+        GpType::Int1 => Value::Int(read_byte(stream)? as i32),
+        GpType::Int1_ => Value::Int(-(read_byte(stream)? as i32)),
+        GpType::Int2 => Value::Int(read_int16(stream)? as u16 as i32),
+        GpType::Int2_ => Value::Int(-(read_int16(stream)? as u16 as i32)),
+        GpType::Byte => Value::Byte(read_byte(stream)?),
+        GpType::Short => Value::Short(read_int16(stream)?),
+        GpType::String => Value::String(read_string_data(stream)?),
         GpType::Null => Value::Null, // Null type
-        GpType::CompressedInt => Value::Int(read_compressed_int32(stream)),
+        GpType::CompressedInt => Value::Int(read_compressed_int32(stream)?),
         GpType::IntZero => Value::Int(0),
-        GpType::StringArray => Value::StringArray(read_string_array(stream)),
-        _ => {panic!("Not implemented: {}", gp_type);}
-    }
+        GpType::StringArray => Value::StringArray(read_string_array(stream)?),
+        GpType::Boolean => Value::Boolean(read_byte(stream)? != 0),
+        GpType::BooleanFalse => Value::Boolean(false),
+        GpType::BooleanTrue => Value::Boolean(true),
+        GpType::ByteZero => Value::Byte(0),
+        GpType::ShortZero => Value::Short(0),
+        GpType::ByteArray => {
+            let length = read_compressed_uint32(stream)? as usize;
+            Value::ByteArray(read_bytes(stream, length)?)
+        }
+        other => return Err(DecodeError::Unsupported(format!("values of type {:?}", other))),
+    })
 }
 
-fn read_parameter_dictionary(stream: &mut StreamBuffer) -> ParameterDictionary {
-    let capacity = read_byte(stream) as usize;
+fn read_parameter_dictionary(stream: &mut StreamBuffer, types: &mut WireTypes) -> Result<ParameterDictionary, DecodeError> {
+    let capacity = read_byte(stream)? as usize;
     let mut parameters = ParameterDictionary::with_capacity(capacity);
 
     for _ in 0..capacity {
-        let code = stream.read_byte();
-        let gp_type = stream.read_byte();
-        let value = read(stream, gp_type);
+        let code = read_byte(stream)?;
+        let gp_type = read_gp_type(stream)?;
+        let value = read(stream, gp_type)?;
+        types.insert(code, gp_type);
         parameters.set(code, value);
     }
 
-    parameters
+    Ok(parameters)
 }
 
-pub fn deserialize_operation_response(stream: &mut StreamBuffer) -> OperationResponse {
-    let operation_code = read_byte(stream);
-    let return_code = read_int16(stream);
-
-    // Read the debug message type first, then pass it to the read function
-    let debug_message_type = read_byte(stream);
-    let debug_message = match read(stream, debug_message_type) {
+fn read_debug_message(stream: &mut StreamBuffer) -> Result<Option<String>, DecodeError> {
+    let debug_message_type = read_gp_type(stream)?;
+    Ok(match read(stream, debug_message_type)? {
         Value::String(value) => Some(value),
         _ => None,
-    };
+    })
+}
+
+pub fn deserialize_operation_request(stream: &mut StreamBuffer) -> Result<OperationRequest, DecodeError> {
+    deserialize_operation_request_with_types(stream, &mut WireTypes::new())
+}
+
+/// Like [`deserialize_operation_request`], also noting the wire type of each parameter in `types`
+pub(crate) fn deserialize_operation_request_with_types(stream: &mut StreamBuffer, types: &mut WireTypes) -> Result<OperationRequest, DecodeError> {
+    let operation_code = read_byte(stream)?;
+    let parameters = read_parameter_dictionary(stream, types)?;
+
+    Ok(OperationRequest {
+        operation_code,
+        parameters
+    })
+}
+
+pub fn deserialize_operation_response(stream: &mut StreamBuffer) -> Result<OperationResponse, DecodeError> {
+    deserialize_operation_response_with_types(stream, &mut WireTypes::new())
+}
 
-    let payload = read_parameter_dictionary(stream);
+/// Like [`deserialize_operation_response`], also noting the wire type of each parameter in `types`
+pub(crate) fn deserialize_operation_response_with_types(stream: &mut StreamBuffer, types: &mut WireTypes) -> Result<OperationResponse, DecodeError> {
+    let operation_code = read_byte(stream)?;
+    let return_code = read_int16(stream)?;
+    let debug_message = read_debug_message(stream)?;
+    let payload = read_parameter_dictionary(stream, types)?;
 
-    OperationResponse {
+    Ok(OperationResponse {
         operation_code,
         return_code,
         debug_message,
         payload
-    }
+    })
+}
+
+pub fn deserialize_disconnect_message(stream: &mut StreamBuffer) -> Result<DisconnectMessage, DecodeError> {
+    deserialize_disconnect_message_with_types(stream, &mut WireTypes::new())
+}
+
+/// Like [`deserialize_disconnect_message`], also noting the wire type of each parameter in `types`
+pub(crate) fn deserialize_disconnect_message_with_types(stream: &mut StreamBuffer, types: &mut WireTypes) -> Result<DisconnectMessage, DecodeError> {
+    let code = read_int16(stream)?;
+    let debug_message = read_debug_message(stream)?;
+    let parameters = read_parameter_dictionary(stream, types)?;
+
+    Ok(DisconnectMessage {
+        code,
+        debug_message,
+        parameters
+    })
 }
 
 #[cfg(test)]
@@ -308,7 +444,7 @@ mod tests {
         buffer.read_byte();
 
         // Read the string
-        match read(&mut buffer, 7) {
+        match read(&mut buffer, GpType::String).unwrap() {
             Value::String(s) => assert_eq!(s, "hello"),
             _ => panic!("Expected String value"),
         }
@@ -344,7 +480,7 @@ mod tests {
         buffer.reset_position();
 
         // Deserialize the operation response
-        let response = deserialize_operation_response(&mut buffer);
+        let response = deserialize_operation_response(&mut buffer).unwrap();
 
         assert_eq!(response.operation_code, 1);
         assert_eq!(response.return_code, 0);
@@ -372,7 +508,7 @@ mod tests {
         buffer.reset_position();
 
         // Deserialize the operation response
-        let response = deserialize_operation_response(&mut buffer);
+        let response = deserialize_operation_response(&mut buffer).unwrap();
 
         assert_eq!(response.operation_code, 1);
         assert_eq!(response.return_code, 0);
@@ -380,6 +516,141 @@ mod tests {
         assert_eq!(response.payload.count(), 0);
     }
 
+    #[test]
+    fn test_serialize_operation_response_round_trip() {
+        let mut payload = ParameterDictionary::new();
+        payload.set(210, Value::StringArray(vec!["eu".to_string(), "us".to_string()]));
+        payload.set(1, Value::Int(-300));
+        let response = OperationResponse {
+            operation_code: 220,
+            return_code: -2,
+            debug_message: Some("hello".to_string()),
+            payload,
+        };
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        serialize_operation_response(&mut buffer, &response, false).unwrap();
+        buffer.reset_position();
+
+        let decoded = deserialize_operation_response(&mut buffer).unwrap();
+        assert_eq!(decoded.operation_code, 220);
+        assert_eq!(decoded.return_code, -2);
+        assert_eq!(decoded.debug_message, Some("hello".to_string()));
+        assert_eq!(decoded.payload, response.payload);
+    }
+
+    #[test]
+    fn test_boolean_byte_and_byte_array_round_trip() {
+        let mut parameters = ParameterDictionary::new();
+        parameters.set(1, Value::Boolean(true));
+        parameters.set(2, Value::Boolean(false));
+        parameters.set(3, Value::Byte(0));
+        parameters.set(4, Value::Byte(200));
+        parameters.set(5, Value::ByteArray(vec![1, 2, 3]));
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        serialize_operation_request(&mut buffer, 1, parameters.clone(), false).unwrap();
+        buffer.reset_position();
+
+        assert_eq!(deserialize_operation_request(&mut buffer).unwrap().parameters, parameters);
+    }
+
+    #[test]
+    fn test_short_round_trip() {
+        let mut parameters = ParameterDictionary::new();
+        parameters.set(1, Value::Short(-2));
+        parameters.set(2, Value::Short(0));
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        serialize_operation_request(&mut buffer, 1, parameters.clone(), false).unwrap();
+        buffer.reset_position();
+        assert_eq!(deserialize_operation_request(&mut buffer).unwrap().parameters, parameters);
+
+        let mut buffer = StreamBuffer::new([1, 1, 1, GpType::ShortZero as u8]);
+        assert_eq!(deserialize_operation_request(&mut buffer).unwrap().parameters.get(1), Some(&Value::Short(0)));
+    }
+
+    #[test]
+    fn test_unreadable_input_is_an_error() {
+        // Cut off after the operation code
+        let mut buffer = StreamBuffer::new([220]);
+        assert!(matches!(deserialize_operation_response(&mut buffer), Err(DecodeError::Malformed(_))));
+
+        // A string longer than what is left
+        let mut buffer = StreamBuffer::new([1, 1, 1, GpType::String as u8, 10, b'a']);
+        assert!(matches!(deserialize_operation_request(&mut buffer), Err(DecodeError::Malformed(_))));
+
+        // A type tag that doesn't exist, and one we can't read
+        let mut buffer = StreamBuffer::new([1, 1, 1, 99]);
+        assert!(matches!(deserialize_operation_request(&mut buffer), Err(DecodeError::Malformed(_))));
+        let mut buffer = StreamBuffer::new([1, 1, 1, GpType::Hashtable as u8]);
+        assert!(matches!(deserialize_operation_request(&mut buffer), Err(DecodeError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_serialize_operation_request_round_trip() {
+        let mut parameters = ParameterDictionary::new();
+        parameters.set(1, Value::Int(123456));
+        parameters.set(224, Value::String("app".to_string()));
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        serialize_operation_request(&mut buffer, 220, parameters.clone(), false).unwrap();
+        buffer.reset_position();
+
+        let decoded = deserialize_operation_request(&mut buffer).unwrap();
+        assert_eq!(decoded.operation_code, 220);
+        assert_eq!(decoded.parameters, parameters);
+    }
+
+    #[test]
+    fn test_serializing_unwritable_values_is_an_error() {
+        for value in [Value::Long(1), Value::Float(1.0), Value::Double(1.0), Value::String("x".repeat(32768))] {
+            let mut parameters = ParameterDictionary::new();
+            parameters.set(7, value);
+            let mut buffer = StreamBuffer::with_capacity(0);
+            assert!(matches!(serialize_operation_request(&mut buffer, 1, parameters, false), Err(DecodeError::Unsupported(_))));
+        }
+
+        let response = OperationResponse { operation_code: 1, return_code: 0, debug_message: None, payload: ParameterDictionary::new() };
+        let mut payload = ParameterDictionary::new();
+        payload.set(2, Value::Long(1));
+        let mut buffer = StreamBuffer::with_capacity(0);
+        let result = serialize_operation_response(&mut buffer, &OperationResponse { payload, ..response }, false);
+        assert!(matches!(result, Err(DecodeError::Unsupported(what)) if what.contains("parameter 2")));
+    }
+
+    #[test]
+    fn test_deserialize_disconnect_message() {
+        let mut buffer = StreamBuffer::with_capacity(0);
+        write_ushort(&mut buffer, 1041);
+        write_string(&mut buffer, "timeout", true).unwrap();
+        buffer.write_byte(0);
+        buffer.reset_position();
+
+        let message = deserialize_disconnect_message(&mut buffer).unwrap();
+        assert_eq!(message.code, 1041);
+        assert_eq!(message.debug_message, Some("timeout".to_string()));
+        assert_eq!(message.parameters.count(), 0);
+    }
+
+    #[test]
+    fn test_serialize_disconnect_message_round_trip() {
+        let message = DisconnectMessage {
+            code: 32767,
+            debug_message: Some("bad app".to_string()),
+            parameters: ParameterDictionary::new(),
+        };
+
+        let mut buffer = StreamBuffer::with_capacity(0);
+        serialize_disconnect_message(&mut buffer, &message).unwrap();
+        buffer.reset_position();
+
+        let decoded = deserialize_disconnect_message(&mut buffer).unwrap();
+        assert_eq!(decoded.code, 32767);
+        assert_eq!(decoded.debug_message, Some("bad app".to_string()));
+        assert_eq!(decoded.parameters.count(), 0);
+    }
+
     #[test]
     fn test_write_ushort() {
         let mut buffer = StreamBuffer::with_capacity(2);
@@ -401,7 +672,7 @@ mod tests {
         buffer.reset_position();
 
         // Read it back using read_int16
-        let value = read_int16(&mut buffer);
+        let value = read_int16(&mut buffer).unwrap();
 
         // Verify that the value is read correctly
         assert_eq!(value, 0x1234);
@@ -462,15 +733,15 @@ mod tests {
         let mut buffer = [0u8; 5];
         let bytes_written = write_compressed_uint32(&mut buffer, 128);
         assert_eq!(bytes_written, 2);
-        assert_eq!(buffer[0], 128 | 0);
+        assert_eq!(buffer[0], 128);
         assert_eq!(buffer[1], 1);
 
         // Test larger values
         let mut buffer = [0u8; 5];
         let bytes_written = write_compressed_uint32(&mut buffer, 0x4000);
         assert_eq!(bytes_written, 3);
-        assert_eq!(buffer[0], 128 | 0);
-        assert_eq!(buffer[1], 128 | 0);
+        assert_eq!(buffer[0], 128);
+        assert_eq!(buffer[1], 128);
         assert_eq!(buffer[2], 1);
     }
 
@@ -480,22 +751,22 @@ mod tests {
         let mut buffer = StreamBuffer::with_capacity(5);
         buffer.write_byte(1); // Value 1, no continuation bit
         buffer.reset_position();
-        assert_eq!(read_compressed_uint32(&mut buffer), 1);
+        assert_eq!(read_compressed_uint32(&mut buffer).unwrap(), 1);
 
         // Test medium values (2 bytes)
         let mut buffer = StreamBuffer::with_capacity(5);
-        buffer.write_byte(128 | 0); // First byte with continuation bit
+        buffer.write_byte(128); // First byte with continuation bit
         buffer.write_byte(1);       // Second byte without continuation bit
         buffer.reset_position();
-        assert_eq!(read_compressed_uint32(&mut buffer), 128);
+        assert_eq!(read_compressed_uint32(&mut buffer).unwrap(), 128);
 
         // Test larger values (3 bytes)
         let mut buffer = StreamBuffer::with_capacity(5);
-        buffer.write_byte(128 | 0); // First byte with continuation bit
-        buffer.write_byte(128 | 0); // Second byte with continuation bit
+        buffer.write_byte(128); // First byte with continuation bit
+        buffer.write_byte(128); // Second byte with continuation bit
         buffer.write_byte(1);       // Third byte without continuation bit
         buffer.reset_position();
-        assert_eq!(read_compressed_uint32(&mut buffer), 0x4000);
+        assert_eq!(read_compressed_uint32(&mut buffer).unwrap(), 0x4000);
     }
 
     #[test]
@@ -504,33 +775,33 @@ mod tests {
         let mut buffer = StreamBuffer::with_capacity(5);
         buffer.write_byte(0); // Encoded value for 0
         buffer.reset_position();
-        assert_eq!(read_compressed_int32(&mut buffer), 0);
+        assert_eq!(read_compressed_int32(&mut buffer).unwrap(), 0);
 
         // Test positive value
         let mut buffer = StreamBuffer::with_capacity(5);
         buffer.write_byte(2); // Encoded value for 1
         buffer.reset_position();
-        assert_eq!(read_compressed_int32(&mut buffer), 1);
+        assert_eq!(read_compressed_int32(&mut buffer).unwrap(), 1);
 
         // Test negative value
         let mut buffer = StreamBuffer::with_capacity(5);
         buffer.write_byte(1); // Encoded value for -1
         buffer.reset_position();
-        assert_eq!(read_compressed_int32(&mut buffer), -1);
+        assert_eq!(read_compressed_int32(&mut buffer).unwrap(), -1);
 
         // Test round-trip for a larger value
         let original = 12345;
         let mut buffer = StreamBuffer::with_capacity(10);
         write_compressed_int(&mut buffer, original, false);
         buffer.reset_position();
-        assert_eq!(read_compressed_int32(&mut buffer), original);
+        assert_eq!(read_compressed_int32(&mut buffer).unwrap(), original);
 
         // Test round-trip for a negative value
         let original = -12345;
         let mut buffer = StreamBuffer::with_capacity(10);
         write_compressed_int(&mut buffer, original, false);
         buffer.reset_position();
-        assert_eq!(read_compressed_int32(&mut buffer), original);
+        assert_eq!(read_compressed_int32(&mut buffer).unwrap(), original);
     }
 
     #[test]
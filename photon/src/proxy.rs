@@ -0,0 +1,629 @@
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use websocket::{ClientBuilder, OwnedMessage};
+use websocket::native_tls::{TlsConnector, TlsStream};
+use websocket::sync::{Client, Reader, Writer};
+use websocket::sync::server::IntoWs;
+use websocket::url::Url;
+use crate::SUBPROTOCOL;
+use crate::capture::{to_hex, Direction};
+use crate::decode::{decode_message, encode_message, DecodedMessage, DecodedParameter};
+use crate::message_type::EgMessageType;
+
+/// How often the accept loop checks whether the proxy was stopped
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long a new client has to send its websocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long the TLS bridge of a wss upstream waits when neither side has anything
+const BRIDGE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Changes to a message made by [`RuleAction::Rewrite`]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Rewrite {
+    /// Added, or replaced if the message already has them
+    #[serde(default)]
+    pub set_parameters: Vec<DecodedParameter>,
+    #[serde(default)]
+    pub remove_parameters: Vec<u8>,
+    #[serde(default)]
+    pub return_code: Option<i16>,
+    #[serde(default)]
+    pub debug_message: Option<String>,
+}
+
+impl Rewrite {
+    fn apply(&self, message: &mut DecodedMessage) {
+        message.parameters.retain(|p| !self.remove_parameters.contains(&p.code) && !self.set_parameters.iter().any(|s| s.code == p.code));
+        message.parameters.extend(self.set_parameters.iter().cloned());
+        message.parameters.sort_by_key(|p| p.code);
+        if self.return_code.is_some() {
+            message.return_code = self.return_code;
+        }
+        if self.debug_message.is_some() {
+            message.debug_message = self.debug_message.clone();
+        }
+    }
+}
+
+/// What a [`ProxyRule`] does to the messages it matches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    /// Holds the message back. Later messages in the same direction wait behind it, as they
+    /// would on a slow TCP connection.
+    Delay { ms: u64 },
+    Drop,
+    Rewrite(Rewrite),
+}
+
+/// Applies `action` to the messages that match every condition that is set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProxyRule {
+    #[serde(default)]
+    pub direction: Option<Direction>,
+    #[serde(default)]
+    pub message_type: Option<EgMessageType>,
+    #[serde(default)]
+    pub operation_code: Option<u8>,
+    pub action: RuleAction,
+}
+
+impl ProxyRule {
+    fn matches(&self, direction: Direction, message: &DecodedMessage) -> bool {
+        self.direction.is_none_or(|d| d == direction)
+            && self.message_type.is_none_or(|t| t == message.message_type)
+            && self.operation_code.is_none_or(|code| message.operation_code == Some(code))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProxyOptions {
+    /// Where client connections are forwarded to, e.g. a [`crate::MockNameServer`]'s url
+    pub upstream: String,
+    /// Applied in order, every matching rule takes effect
+    pub rules: Vec<ProxyRule>,
+    /// File the JSON log lines are appended to, stdout if not set
+    pub log_path: Option<PathBuf>,
+}
+
+/// What the proxy did with a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyAction {
+    Forwarded,
+    Delayed,
+    Rewritten,
+    Dropped,
+}
+
+/// One line of the proxy's log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProxyLogEntry {
+    /// Microseconds since the unix epoch
+    pub timestamp_us: u64,
+    pub connection: u64,
+    pub direction: Direction,
+    pub action: ProxyAction,
+    /// The frame as received, in hex
+    pub data: String,
+    /// The frame as received, decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<DecodedMessage>,
+    /// Why the frame couldn't be decoded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+type LogSink = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// The sockets of each open connection by its number, so stop() can unblock the pumps
+type Streams = Arc<Mutex<HashMap<u64, Vec<TcpStream>>>>;
+
+/// A websocket proxy for GpBinaryV18 traffic that decodes and logs every frame in both
+/// directions, and can delay, drop or rewrite operations on the way.
+///
+/// Point a client at [`Proxy::url`] instead of its name server to see what it actually sends.
+pub struct Proxy {
+    address: SocketAddr,
+    stopped: Arc<AtomicBool>,
+    streams: Streams,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Proxy {
+    pub fn start<A: ToSocketAddrs>(address: A, options: ProxyOptions) -> io::Result<Self> {
+        let log: LogSink = match &options.log_path {
+            Some(path) => Arc::new(Mutex::new(Box::new(OpenOptions::new().create(true).append(true).open(path)?))),
+            None => Arc::new(Mutex::new(Box::new(io::stdout()))),
+        };
+
+        let listener = TcpListener::bind(address)?;
+        // Non-blocking so the accept loop notices when it is stopped
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let streams: Streams = Arc::new(Mutex::new(HashMap::new()));
+        let options = Arc::new(options);
+
+        let thread_stopped = stopped.clone();
+        let thread_streams = streams.clone();
+        let thread = thread::spawn(move || {
+            let mut connections = 0;
+            while !thread_stopped.load(Ordering::Relaxed) {
+                let stream = match listener.accept() {
+                    Ok((stream, _)) => stream,
+                    Err(_) => {
+                        thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                };
+                connections += 1;
+
+                // The handshakes happen on the connection's own thread, so a slow client or
+                // upstream doesn't hold up everyone connecting after it
+                let context = PumpContext { connection: connections, options: options.clone(), log: log.clone(), streams: thread_streams.clone() };
+                let stopped = thread_stopped.clone();
+                thread::spawn(move || open_connection(stream, context, &stopped));
+            }
+        });
+
+        Ok(Proxy { address, stopped, streams, thread: Some(thread) })
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// The address clients should connect to, e.g. `ws://127.0.0.1:45678`
+    pub fn url(&self) -> String {
+        format!("ws://{}", self.address)
+    }
+
+    /// Stops accepting connections and cuts the open ones
+    pub fn stop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        for stream in self.streams.lock().unwrap().drain().flat_map(|(_, streams)| streams) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+struct PumpContext {
+    connection: u64,
+    options: Arc<ProxyOptions>,
+    log: LogSink,
+    streams: Streams,
+}
+
+/// Accepts the client's websocket, connects it to the upstream and starts the pumps between them
+fn open_connection(stream: TcpStream, context: PumpContext, stopped: &AtomicBool) {
+    // Accepted sockets don't inherit the listener's non-blocking mode everywhere
+    let _ = stream.set_nonblocking(false);
+    let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+    let client = match stream.into_ws() {
+        Ok(upgrade) => match upgrade.use_protocol(SUBPROTOCOL).accept() {
+            Ok(client) => client,
+            Err((_, e)) => {
+                eprintln!("Proxy handshake failed: {}", e);
+                return;
+            }
+        },
+        Err((_, _, _, e)) => {
+            eprintln!("Proxy handshake failed: {}", e);
+            return;
+        }
+    };
+    let _ = client.stream_ref().set_read_timeout(None);
+
+    let upstream = match connect_upstream(&context.options.upstream) {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            eprintln!("Proxy could not reach {}: {}", context.options.upstream, e);
+            let _ = client.shutdown();
+            return;
+        }
+    };
+
+    // Errors go to stderr, stdout may be the log. The streams are kept so stop() can unblock the
+    // pumps, unless it already ran.
+    let streams: Vec<TcpStream> = [client.stream_ref().try_clone(), upstream.stream_ref().try_clone()].into_iter().flatten().collect();
+    {
+        let mut open = context.streams.lock().unwrap();
+        if stopped.load(Ordering::Relaxed) {
+            for stream in streams {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            return;
+        }
+        open.insert(context.connection, streams);
+    }
+
+    let (Ok((client_reader, client_writer)), Ok((upstream_reader, upstream_writer))) = (client.split(), upstream.split()) else {
+        context.streams.lock().unwrap().remove(&context.connection);
+        return;
+    };
+    let context = Arc::new(context);
+    let outbound = context.clone();
+    thread::spawn(move || pump(client_reader, upstream_writer, Direction::Outbound, &outbound));
+    thread::spawn(move || pump(upstream_reader, client_writer, Direction::Inbound, &context));
+}
+
+/// Opens the websocket to the upstream. A wss one goes through a TLS bridge, so the websocket
+/// itself is on a plain TcpStream that splits into a reader and a writer like the client's.
+fn connect_upstream(address: &str) -> Result<Client<TcpStream>, String> {
+    let url = Url::parse(address).map_err(|e| e.to_string())?;
+    let mut builder = ClientBuilder::from_url(&url).add_protocol(SUBPROTOCOL);
+    if url.scheme() != "wss" {
+        return builder.connect_insecure().map_err(|e| e.to_string());
+    }
+
+    let host = url.host_str().ok_or_else(|| format!("{} has no host", address))?;
+    let port = url.port_or_known_default().unwrap_or(443);
+    let stream = TcpStream::connect((host, port)).map_err(|e| e.to_string())?;
+    let connector = TlsConnector::new().map_err(|e| e.to_string())?;
+    let tls = connector.connect(host, stream).map_err(|e| e.to_string())?;
+    let bridged = bridge(tls).map_err(|e| e.to_string())?;
+    builder.connect_on(bridged).map_err(|e| e.to_string())
+}
+
+/// A stream the bridge can poll without blocking
+trait BridgeStream: Read + Write + Send + 'static {
+    fn set_nonblocking(&self) -> io::Result<()>;
+    fn close(&mut self);
+}
+
+impl BridgeStream for TcpStream {
+    fn set_nonblocking(&self) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, true)
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown(Shutdown::Both);
+    }
+}
+
+impl BridgeStream for TlsStream<TcpStream> {
+    fn set_nonblocking(&self) -> io::Result<()> {
+        self.get_ref().set_nonblocking(true)
+    }
+
+    fn close(&mut self) {
+        let _ = self.shutdown();
+    }
+}
+
+/// Carries the bytes of `remote` over a loopback connection and returns its other end. The
+/// bridge ends when either side closes.
+fn bridge<S: BridgeStream>(mut remote: S) -> io::Result<TcpStream> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let bridged = TcpStream::connect(listener.local_addr()?)?;
+    let (mut local, _) = listener.accept()?;
+
+    // One thread does both directions, a TLS stream can't be read and written from two
+    remote.set_nonblocking()?;
+    BridgeStream::set_nonblocking(&local)?;
+    thread::spawn(move || {
+        let mut buffer = vec![0; 16 * 1024];
+        loop {
+            match (forward(&mut remote, &mut local, &mut buffer), forward(&mut local, &mut remote, &mut buffer)) {
+                (Ok(false), Ok(false)) => thread::sleep(BRIDGE_POLL_INTERVAL),
+                (Ok(_), Ok(_)) => {}
+                _ => break,
+            }
+        }
+        remote.close();
+        local.close();
+    });
+    Ok(bridged)
+}
+
+/// Moves whatever `from` has ready to `to`. Ok(false) if it had nothing, Err once either is gone.
+fn forward(from: &mut impl Read, to: &mut impl Write, buffer: &mut [u8]) -> io::Result<bool> {
+    let count = match from.read(buffer) {
+        Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+        Ok(count) => count,
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    let mut written = 0;
+    while written < count {
+        match to.write(&buffer[written..count]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted) => thread::sleep(BRIDGE_POLL_INTERVAL),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+/// Forwards frames from `reader` to `writer` until either side goes away, then closes both
+fn pump(mut reader: Reader<TcpStream>, mut writer: Writer<TcpStream>, direction: Direction, context: &PumpContext) {
+    for message in reader.incoming_messages() {
+        let frame = match message {
+            Ok(OwnedMessage::Binary(frame)) => frame,
+            Ok(OwnedMessage::Close(_)) | Err(_) => break,
+            Ok(other) => {
+                if writer.send_message(&other).is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        if let Some(frame) = process(frame, direction, context)
+            && writer.send_message(&OwnedMessage::Binary(frame)).is_err() {
+            break;
+        }
+    }
+
+    let _ = writer.send_message(&OwnedMessage::Close(None));
+    let _ = writer.shutdown_all();
+    let _ = reader.shutdown_all();
+    // Both sides are down now, so stop() has nothing left to cut
+    context.streams.lock().unwrap().remove(&context.connection);
+}
+
+/// Logs `frame` and applies the matching rules, returning what to forward
+fn process(frame: Vec<u8>, direction: Direction, context: &PumpContext) -> Option<Vec<u8>> {
+    let decoded = decode_message(&frame);
+    let mut entry = ProxyLogEntry {
+        timestamp_us: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO).as_micros() as u64,
+        connection: context.connection,
+        direction,
+        action: ProxyAction::Forwarded,
+        data: to_hex(&frame),
+        message: decoded.as_ref().ok().cloned(),
+        error: decoded.as_ref().err().map(|e| e.to_string()),
+    };
+
+    // Frames we can't decode can't match a rule either, so they pass through untouched
+    let Ok(mut message) = decoded else {
+        log(context, &entry);
+        return Some(frame);
+    };
+
+    let mut delay = Duration::ZERO;
+    let mut rewritten = false;
+    // Matched against the message as received, so a rewrite can't change which rules apply
+    let matching: Vec<&ProxyRule> = context.options.rules.iter().filter(|rule| rule.matches(direction, &message)).collect();
+    for rule in matching {
+        match &rule.action {
+            RuleAction::Drop => {
+                entry.action = ProxyAction::Dropped;
+                log(context, &entry);
+                return None;
+            }
+            RuleAction::Delay { ms } => delay += Duration::from_millis(*ms),
+            RuleAction::Rewrite(rewrite) => {
+                rewrite.apply(&mut message);
+                rewritten = true;
+            }
+        }
+    }
+
+    let forwarded = if rewritten {
+        match encode_message(&message) {
+            Ok(encoded) => {
+                entry.action = ProxyAction::Rewritten;
+                encoded
+            }
+            Err(e) => {
+                entry.error = Some(format!("rewrite failed, forwarding the original: {}", e));
+                frame
+            }
+        }
+    } else {
+        frame
+    };
+    if !delay.is_zero() && entry.action == ProxyAction::Forwarded {
+        entry.action = ProxyAction::Delayed;
+    }
+    log(context, &entry);
+
+    thread::sleep(delay);
+    Some(forwarded)
+}
+
+fn log(context: &PumpContext, entry: &ProxyLogEntry) {
+    let Ok(mut line) = serde_json::to_string(entry) else { return };
+    line.push('\n');
+    let mut log = context.log.lock().unwrap();
+    let _ = log.write_all(line.as_bytes());
+    let _ = log.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Instant;
+    use crate::mock_name_server::{MockNameServer, MockNameServerOptions};
+    use crate::parameter_dictionary::Value;
+    use crate::photon_codes;
+    use crate::transport::{Transport, WebSocketTransport};
+    use crate::round_trip_time::local_timestamp;
+    use crate::{get_regions, ping_message};
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(2));
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("photon_proxy_{}_{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn start(name: &str, rules: Vec<ProxyRule>) -> (MockNameServer, Proxy, PathBuf, WebSocketTransport) {
+        let server = MockNameServer::start(MockNameServerOptions::default()).unwrap();
+        let log_path = temp_path(name);
+        let options = ProxyOptions { upstream: server.url(), rules, log_path: Some(log_path.clone()) };
+        let proxy = Proxy::start("127.0.0.1:0", options).unwrap();
+
+        let mut transport = WebSocketTransport::connect(&proxy.url(), SUBPROTOCOL).unwrap();
+        let init = transport.receive_frame(TIMEOUT).unwrap().unwrap();
+        assert_eq!(init, vec![243, EgMessageType::InitResponse as u8]);
+        (server, proxy, log_path, transport)
+    }
+
+    fn read_log(path: &PathBuf) -> Vec<ProxyLogEntry> {
+        fs::read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_forwards_and_logs_both_directions() {
+        let (_server, _proxy, log_path, mut transport) = start("forward", Vec::new());
+
+        transport.send_frame(&get_regions()).unwrap();
+        let response = decode_message(&transport.receive_frame(TIMEOUT).unwrap().unwrap()).unwrap();
+        assert_eq!(response.operation.as_deref(), Some("GetRegions"));
+
+        let entries = read_log(&log_path);
+        let summary: Vec<_> = entries.iter()
+            .map(|e| (e.direction, e.message.as_ref().unwrap().message_type, e.action))
+            .collect();
+        assert_eq!(summary, vec![
+            (Direction::Inbound, EgMessageType::InitResponse, ProxyAction::Forwarded),
+            (Direction::Outbound, EgMessageType::Operation, ProxyAction::Forwarded),
+            (Direction::Inbound, EgMessageType::OperationResponse, ProxyAction::Forwarded),
+        ]);
+        assert!(entries.iter().all(|e| e.connection == 1));
+        fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_drop_rule() {
+        let rules = vec![ProxyRule {
+            direction: Some(Direction::Outbound),
+            message_type: Some(EgMessageType::InternalOperationRequest),
+            operation_code: Some(photon_codes::PING),
+            action: RuleAction::Drop,
+        }];
+        let (_server, _proxy, log_path, mut transport) = start("drop", rules);
+
+        transport.send_frame(&ping_message(local_timestamp())).unwrap();
+        transport.send_frame(&get_regions()).unwrap();
+
+        // The ping never reached the server, so the first answer is the region list
+        let response = decode_message(&transport.receive_frame(TIMEOUT).unwrap().unwrap()).unwrap();
+        assert_eq!(response.operation_code, Some(220));
+        assert!(read_log(&log_path).iter().any(|e| e.action == ProxyAction::Dropped));
+        fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_rewrite_and_delay_rules() {
+        let rewrite = Rewrite {
+            set_parameters: vec![DecodedParameter { code: 210, name: None, gp_type: None, value: Value::StringArray(vec!["xx".to_string()]) }],
+            remove_parameters: vec![230],
+            return_code: Some(-3),
+            debug_message: None,
+        };
+        let rules = vec![
+            ProxyRule { direction: Some(Direction::Inbound), message_type: None, operation_code: Some(220), action: RuleAction::Rewrite(rewrite) },
+            ProxyRule { direction: Some(Direction::Outbound), message_type: None, operation_code: Some(220), action: RuleAction::Delay { ms: 100 } },
+        ];
+        let (_server, _proxy, log_path, mut transport) = start("rewrite", rules);
+
+        let sent = Instant::now();
+        transport.send_frame(&get_regions()).unwrap();
+        let response = decode_message(&transport.receive_frame(TIMEOUT).unwrap().unwrap()).unwrap();
+
+        assert!(sent.elapsed() >= Duration::from_millis(100));
+        assert_eq!(response.return_code, Some(-3));
+        assert_eq!(response.parameters.len(), 1);
+        assert_eq!(response.parameters[0].value, Value::StringArray(vec!["xx".to_string()]));
+
+        let actions: Vec<_> = read_log(&log_path).iter().map(|e| e.action).collect();
+        assert!(actions.contains(&ProxyAction::Delayed));
+        assert!(actions.contains(&ProxyAction::Rewritten));
+        fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_closed_connections_are_forgotten() {
+        let (_server, proxy, log_path, mut transport) = start("forget", Vec::new());
+        assert_eq!(proxy.streams.lock().unwrap().len(), 1);
+
+        transport.close().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !proxy.streams.lock().unwrap().is_empty() {
+            assert!(Instant::now() < deadline, "the connection's streams were kept");
+            thread::sleep(Duration::from_millis(10));
+        }
+        fs::remove_file(&log_path).unwrap();
+    }
+
+    #[test]
+    fn test_silent_client_does_not_block_others() {
+        let server = MockNameServer::start(MockNameServerOptions::default()).unwrap();
+        let options = ProxyOptions { upstream: server.url(), rules: Vec::new(), log_path: Some(temp_path("silent")) };
+        let proxy = Proxy::start("127.0.0.1:0", options).unwrap();
+
+        // Connects, but never sends its handshake
+        let _silent = TcpStream::connect(proxy.address()).unwrap();
+        thread::sleep(POLL_INTERVAL * 2);
+
+        let mut transport = WebSocketTransport::connect(&proxy.url(), SUBPROTOCOL).unwrap();
+        assert_eq!(transport.receive_frame(TIMEOUT).unwrap(), Some(vec![243, EgMessageType::InitResponse as u8]));
+        let _ = fs::remove_file(temp_path("silent"));
+    }
+
+    #[test]
+    fn test_bridge_carries_both_directions() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut peer, _) = listener.accept().unwrap();
+        let mut bridged = bridge(remote).unwrap();
+        bridged.set_read_timeout(TIMEOUT).unwrap();
+        peer.set_read_timeout(TIMEOUT).unwrap();
+
+        bridged.write_all(b"ping").unwrap();
+        let mut received = [0; 4];
+        peer.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"ping");
+
+        peer.write_all(b"pong").unwrap();
+        bridged.read_exact(&mut received).unwrap();
+        assert_eq!(&received, b"pong");
+
+        // Closing one side closes the other
+        drop(peer);
+        assert_eq!(bridged.read(&mut received).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_rules_from_json() {
+        let json = r#"[
+            {"direction": "outbound", "operation_code": 220, "action": {"delay": {"ms": 250}}},
+            {"message_type": "InternalOperationRequest", "action": "drop"},
+            {"operation_code": 220, "action": {"rewrite": {"return_code": -2}}}
+        ]"#;
+        let rules: Vec<ProxyRule> = serde_json::from_str(json).unwrap();
+
+        assert_eq!(rules[0].action, RuleAction::Delay { ms: 250 });
+        assert_eq!(rules[1].action, RuleAction::Drop);
+        assert_eq!(rules[2].action, RuleAction::Rewrite(Rewrite { return_code: Some(-2), ..Default::default() }));
+    }
+}
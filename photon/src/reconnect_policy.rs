@@ -0,0 +1,111 @@
+use std::time::Duration;
+use rand::{thread_rng, Rng};
+
+/// How the name server connection recovers after it drops
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReconnectPolicy {
+    /// Delay before the first reconnect attempt
+    pub initial_delay: Duration,
+    /// Upper bound for the delay between attempts
+    pub max_delay: Duration,
+    /// Factor the delay grows by after every failed attempt
+    pub multiplier: f64,
+    /// Random spread applied to every delay, as a fraction of it (0.2 = ±20%)
+    pub jitter: f64,
+    /// Attempts before giving up, `None` to retry forever. `Some(0)` disables reconnecting.
+    pub max_attempts: Option<u32>,
+    /// Keep requests that were pending when the connection dropped and re-issue them once it is
+    /// back, instead of failing them straight away. Photon rooms aren't supported by this crate,
+    /// so for now that covers the region list.
+    pub rejoin: bool,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_attempts: Some(10),
+            rejoin: true,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// A policy that never reconnects
+    pub fn disabled() -> Self {
+        ReconnectPolicy {
+            max_attempts: Some(0),
+            rejoin: false,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    /// Whether the given (1-based) reconnect attempt may be made
+    pub fn allows_attempt(&self, attempt: u32) -> bool {
+        self.max_attempts.is_none_or(|max| attempt <= max)
+    }
+
+    /// The delay before the given (1-based) attempt
+    pub fn delay_for_attempt(&self, attempt: u32) -> Duration {
+        self.delay_with_random(attempt, thread_rng().gen_range(0.0, 1.0))
+    }
+
+    /// [`Self::delay_for_attempt`] with the random value in `[0, 1)` passed in
+    fn delay_with_random(&self, attempt: u32, random: f64) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = self.initial_delay.as_secs_f64() * self.multiplier.max(1.0).powi(exponent);
+        let base = base.min(self.max_delay.as_secs_f64());
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = 1.0 + jitter * (2.0 * random - 1.0);
+        Duration::from_secs_f64((base * factor).min(self.max_delay.as_secs_f64()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn without_jitter() -> ReconnectPolicy {
+        ReconnectPolicy {
+            jitter: 0.0,
+            ..ReconnectPolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_delay_grows_exponentially() {
+        let policy = without_jitter();
+        assert_eq!(policy.delay_for_attempt(1), Duration::from_millis(500));
+        assert_eq!(policy.delay_for_attempt(2), Duration::from_millis(1000));
+        assert_eq!(policy.delay_for_attempt(4), Duration::from_millis(4000));
+    }
+
+    #[test]
+    fn test_delay_is_capped() {
+        let policy = without_jitter();
+        assert_eq!(policy.delay_for_attempt(20), Duration::from_secs(30));
+        assert_eq!(policy.delay_for_attempt(u32::MAX), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_jitter_bounds() {
+        let policy = ReconnectPolicy::default();
+        assert_eq!(policy.delay_with_random(2, 0.0), Duration::from_millis(800));
+        assert_eq!(policy.delay_with_random(2, 0.5), Duration::from_millis(1000));
+        assert!(policy.delay_with_random(2, 0.999_999) <= Duration::from_millis(1200));
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = ReconnectPolicy::default();
+        assert!(policy.allows_attempt(10));
+        assert!(!policy.allows_attempt(11));
+
+        assert!(!ReconnectPolicy::disabled().allows_attempt(1));
+        assert!(ReconnectPolicy { max_attempts: None, ..ReconnectPolicy::default() }.allows_attempt(u32::MAX));
+    }
+}
@@ -0,0 +1,226 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use crate::disconnect_reason::DisconnectInfo;
+use crate::photon_region::PhotonRegion;
+
+/// Where a region list came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionSource {
+    /// Just fetched from the name server
+    NameServer,
+    /// Read from the cache file
+    Cache,
+}
+
+/// A region list together with how much it can be trusted
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedRegions {
+    pub regions: Vec<PhotonRegion>,
+    pub source: RegionSource,
+    /// When the list was fetched from the name server
+    pub fetched_at: SystemTime,
+    /// The list is older than the cache's TTL, because the name server couldn't be reached
+    pub stale: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    /// Milliseconds since the unix epoch
+    fetched_at: u64,
+    regions: Vec<PhotonRegion>,
+}
+
+/// Keeps the last region list from the name server on disk, so it survives restarts and can
+/// stand in while the name server is unreachable
+#[derive(Debug, Clone)]
+pub struct RegionCache {
+    path: PathBuf,
+    ttl: Duration,
+}
+
+impl RegionCache {
+    pub fn new<P: AsRef<Path>>(path: P, ttl: Duration) -> Self {
+        RegionCache {
+            path: path.as_ref().to_path_buf(),
+            ttl,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Reads the cache file. Missing or unreadable files are treated as an empty cache.
+    pub fn load(&self) -> Option<CachedRegions> {
+        let contents = fs::read_to_string(&self.path).ok()?;
+        let file: CacheFile = match serde_json::from_str(&contents) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Ignoring corrupt region cache {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        let fetched_at = UNIX_EPOCH + Duration::from_millis(file.fetched_at);
+        Some(CachedRegions {
+            regions: file.regions,
+            source: RegionSource::Cache,
+            fetched_at,
+            stale: self.is_expired(fetched_at),
+        })
+    }
+
+    /// Writes `regions` to the cache file, stamped with the current time
+    pub fn store(&self, regions: &[PhotonRegion]) -> io::Result<()> {
+        let fetched_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_millis() as u64;
+        let file = CacheFile {
+            fetched_at,
+            regions: regions.to_vec(),
+        };
+
+        if let Some(parent) = self.path.parent() && !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // Write to a temporary file first so a crash can't leave a half written cache behind
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, serde_json::to_string_pretty(&file)?)?;
+        fs::rename(&temp_path, &self.path)
+    }
+
+    /// Returns the cached list while it is within the TTL. Otherwise asks `fetch` for a new one and
+    /// caches it, falling back to the expired cache if that fails.
+    ///
+    /// Photon's regional servers are only known from the name server, so without a cache there is
+    /// nothing to fall back on and the fetch error is returned.
+    pub fn get_or_fetch<F>(&self, fetch: F) -> Result<CachedRegions, DisconnectInfo>
+    where
+        F: FnOnce() -> Result<Vec<PhotonRegion>, DisconnectInfo>,
+    {
+        let cached = self.load();
+        if let Some(cached) = &cached && !cached.stale && !cached.regions.is_empty() {
+            return Ok(cached.clone());
+        }
+
+        match fetch() {
+            Ok(regions) if !regions.is_empty() => {
+                if let Err(e) = self.store(&regions) {
+                    println!("Could not write region cache {}: {}", self.path.display(), e);
+                }
+                Ok(CachedRegions {
+                    regions,
+                    source: RegionSource::NameServer,
+                    fetched_at: SystemTime::now(),
+                    stale: false,
+                })
+            }
+            result => {
+                if let Err(e) = &result {
+                    println!("Could not fetch regions, using fallback: {}", e);
+                }
+
+                match cached {
+                    Some(cached) if !cached.regions.is_empty() => Ok(CachedRegions { stale: true, ..cached }),
+                    // Nothing better than what the name server said, even if that was nothing
+                    _ => result.map(|regions| CachedRegions {
+                        regions,
+                        source: RegionSource::NameServer,
+                        fetched_at: SystemTime::now(),
+                        stale: false,
+                    }),
+                }
+            }
+        }
+    }
+
+    fn is_expired(&self, fetched_at: SystemTime) -> bool {
+        match SystemTime::now().duration_since(fetched_at) {
+            Ok(age) => age > self.ttl,
+            // Fetched in the future, so the clock has been changed. Don't trust it.
+            Err(_) => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disconnect_reason::DisconnectReason;
+
+    fn temp_cache(name: &str, ttl: Duration) -> RegionCache {
+        let path = std::env::temp_dir().join(format!("photon_region_cache_{}_{}.json", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        RegionCache::new(path, ttl)
+    }
+
+    fn regions() -> Vec<PhotonRegion> {
+        vec![PhotonRegion { short_name: "eu".to_string(), address: "wss://eu.example:19090".to_string() }]
+    }
+
+    fn unreachable() -> Result<Vec<PhotonRegion>, DisconnectInfo> {
+        Err(DisconnectInfo::new(DisconnectReason::ConnectFailed, None))
+    }
+
+    #[test]
+    fn test_fetch_stores_and_then_serves_from_cache() {
+        let cache = temp_cache("fetch", Duration::from_secs(3600));
+
+        let fetched = cache.get_or_fetch(|| Ok(regions())).unwrap();
+        assert_eq!(fetched.source, RegionSource::NameServer);
+        assert!(!fetched.stale);
+
+        let cached = cache.get_or_fetch(|| panic!("Should not fetch while the cache is fresh")).unwrap();
+        assert_eq!(cached.source, RegionSource::Cache);
+        assert_eq!(cached.regions, regions());
+        assert!(!cached.stale);
+
+        let _ = fs::remove_file(cache.path());
+    }
+
+    #[test]
+    fn test_expired_cache_is_refetched_or_flagged_stale() {
+        let cache = temp_cache("expired", Duration::ZERO);
+        cache.store(&regions()).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let fallback = cache.get_or_fetch(unreachable).unwrap();
+        assert_eq!(fallback.source, RegionSource::Cache);
+        assert_eq!(fallback.regions, regions());
+        assert!(fallback.stale);
+
+        let refetched = cache.get_or_fetch(|| Ok(regions())).unwrap();
+        assert_eq!(refetched.source, RegionSource::NameServer);
+
+        let _ = fs::remove_file(cache.path());
+    }
+
+    #[test]
+    fn test_fails_without_cache_or_name_server() {
+        let cache = temp_cache("nothing", Duration::from_secs(3600));
+
+        let error = cache.get_or_fetch(unreachable).unwrap_err();
+        assert_eq!(error.reason, DisconnectReason::ConnectFailed);
+        assert!(cache.load().is_none());
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_ignored() {
+        let cache = temp_cache("corrupt", Duration::from_secs(3600));
+        fs::write(cache.path(), "not json").unwrap();
+
+        assert!(cache.load().is_none());
+        assert_eq!(cache.get_or_fetch(|| Ok(regions())).unwrap().source, RegionSource::NameServer);
+
+        let _ = fs::remove_file(cache.path());
+    }
+}
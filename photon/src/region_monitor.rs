@@ -0,0 +1,432 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use crate::multi_pinger::{ping_regions, SweepOptions};
+use crate::photon_region::PhotonRegion;
+use crate::ping_stats::PingStats;
+use crate::pinger::PingError;
+
+/// How the [`RegionMonitor`] pings and when it considers a region degraded
+#[derive(Debug, Clone)]
+pub struct MonitorOptions {
+    /// Time between the start of two sweeps
+    pub interval: Duration,
+    /// Sweeps kept per region
+    pub history: usize,
+    pub sweep: SweepOptions,
+    /// Another region only becomes the best one if it is this much faster than the current best
+    pub tolerance_ms: f64,
+    /// A region is degraded when its latest median is this much above its usual median...
+    pub degraded_increase_ms: f64,
+    /// ...or when it lost at least this share of its latest pings
+    pub degraded_loss_percent: f64,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        MonitorOptions {
+            interval: Duration::from_secs(60),
+            history: 60,
+            sweep: SweepOptions::default(),
+            tolerance_ms: 10.0,
+            degraded_increase_ms: 30.0,
+            degraded_loss_percent: 20.0,
+        }
+    }
+}
+
+/// Something subscribers of a [`RegionMonitor`] are told about
+#[derive(Debug, Clone, PartialEq)]
+pub enum MonitorEvent {
+    /// The fastest region changed, `previous` is `None` after the first sweep
+    BestRegionChanged { previous: Option<String>, current: String, median_ms: f64 },
+    /// A region got noticeably slower than usual or started losing pings
+    RegionDegraded { region: String, usual_ms: f64, median_ms: f64, loss_percent: f64 },
+    /// A degraded region is back to normal
+    RegionRecovered { region: String, median_ms: f64 },
+}
+
+/// One sweep's result for a region
+#[derive(Debug, Clone)]
+pub struct RegionSample {
+    pub at: Instant,
+    pub stats: PingStats,
+}
+
+/// Summary of a region's recent history, all latencies are medians of a sweep in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RegionTrend {
+    /// Sweeps the region answered in
+    pub samples: usize,
+    pub latest_ms: f64,
+    pub mean_ms: f64,
+    /// Standard deviation of the medians, lower is more stable
+    pub std_dev_ms: f64,
+    /// Least squares slope of the medians, positive means getting slower
+    pub slope_ms_per_minute: f64,
+    /// Share of all pings in the history that were lost
+    pub loss_percent: f64,
+}
+
+/// Rolling time series of a region's sweeps
+#[derive(Debug, Clone, Default)]
+pub struct RegionHistory {
+    samples: VecDeque<RegionSample>,
+}
+
+impl RegionHistory {
+    fn push(&mut self, sample: RegionSample, capacity: usize) {
+        self.samples.push_back(sample);
+        while self.samples.len() > capacity.max(1) {
+            self.samples.pop_front();
+        }
+    }
+
+    pub fn samples(&self) -> impl Iterator<Item = &RegionSample> {
+        self.samples.iter()
+    }
+
+    pub fn latest(&self) -> Option<&RegionSample> {
+        self.samples.back()
+    }
+
+    /// Trend and stability metrics, `None` until the region has answered at least once
+    pub fn trend(&self) -> Option<RegionTrend> {
+        let reachable: Vec<(f64, f64)> = self
+            .samples
+            .iter()
+            .filter(|sample| sample.stats.is_reachable())
+            .map(|sample| (sample.at.duration_since(self.samples[0].at).as_secs_f64() / 60.0, median_ms(&sample.stats)))
+            .collect();
+        let latest = reachable.last()?.1;
+
+        let count = reachable.len() as f64;
+        let mean_time = reachable.iter().map(|(time, _)| time).sum::<f64>() / count;
+        let mean = reachable.iter().map(|(_, median)| median).sum::<f64>() / count;
+        let variance = reachable.iter().map(|(_, median)| (median - mean).powi(2)).sum::<f64>() / count;
+        let time_variance = reachable.iter().map(|(time, _)| (time - mean_time).powi(2)).sum::<f64>();
+        let slope = if time_variance > 0.0 {
+            reachable.iter().map(|(time, median)| (time - mean_time) * (median - mean)).sum::<f64>() / time_variance
+        } else {
+            0.0
+        };
+
+        let sent: u32 = self.samples.iter().map(|sample| sample.stats.sent).sum();
+        let received: u32 = self.samples.iter().map(|sample| sample.stats.received).sum();
+        let loss_percent = if sent == 0 { 0.0 } else { (sent - received.min(sent)) as f64 * 100.0 / sent as f64 };
+
+        Some(RegionTrend {
+            samples: reachable.len(),
+            latest_ms: latest,
+            mean_ms: mean,
+            std_dev_ms: variance.sqrt(),
+            slope_ms_per_minute: slope,
+            loss_percent,
+        })
+    }
+
+    /// The median of the medians before the latest sweep, what the region is usually like
+    fn usual_ms(&self) -> Option<f64> {
+        let mut medians: Vec<f64> = self
+            .samples
+            .iter()
+            .take(self.samples.len().saturating_sub(1))
+            .filter(|sample| sample.stats.is_reachable())
+            .map(|sample| median_ms(&sample.stats))
+            .collect();
+        if medians.is_empty() {
+            return None;
+        }
+        medians.sort_by(f64::total_cmp);
+        Some(medians[medians.len() / 2])
+    }
+}
+
+fn median_ms(stats: &PingStats) -> f64 {
+    stats.median_us as f64 / 1000.0
+}
+
+/// The histories and the conclusions drawn from them, separate from the thread so it can be fed
+/// results directly
+#[derive(Debug, Default)]
+struct MonitorState {
+    histories: HashMap<String, RegionHistory>,
+    best_region: Option<String>,
+    degraded: HashMap<String, bool>,
+}
+
+impl MonitorState {
+    fn record(&mut self, results: Vec<(PhotonRegion, Result<PingStats, PingError>)>, at: Instant, options: &MonitorOptions) -> Vec<MonitorEvent> {
+        let mut events = Vec::new();
+
+        for (region, result) in results {
+            let stats = match result {
+                Ok(stats) => stats,
+                Err(e) => {
+                    println!("Monitor could not ping region {}: {}", region.short_name, e);
+                    continue;
+                }
+            };
+            let history = self.histories.entry(region.short_name.clone()).or_default();
+            history.push(RegionSample { at, stats: stats.clone() }, options.history);
+
+            let median = median_ms(&stats);
+            let usual = history.usual_ms();
+            let degraded = stats.loss_percent() >= options.degraded_loss_percent
+                || usual.is_some_and(|usual| median > usual + options.degraded_increase_ms);
+
+            let was_degraded = self.degraded.insert(region.short_name.clone(), degraded).unwrap_or(false);
+            if degraded && !was_degraded {
+                events.push(MonitorEvent::RegionDegraded {
+                    region: region.short_name.clone(),
+                    usual_ms: usual.unwrap_or(median),
+                    median_ms: median,
+                    loss_percent: stats.loss_percent(),
+                });
+            } else if !degraded && was_degraded {
+                events.push(MonitorEvent::RegionRecovered { region: region.short_name.clone(), median_ms: median });
+            }
+        }
+
+        if let Some(event) = self.update_best_region(options) {
+            events.push(event);
+        }
+        events
+    }
+
+    fn update_best_region(&mut self, options: &MonitorOptions) -> Option<MonitorEvent> {
+        let latest = |name: &str| {
+            self.histories
+                .get(name)
+                .and_then(RegionHistory::latest)
+                .filter(|sample| sample.stats.is_reachable())
+                .map(|sample| median_ms(&sample.stats))
+        };
+
+        let (fastest, fastest_ms) = self
+            .histories
+            .keys()
+            .filter_map(|name| latest(name).map(|median| (name.clone(), median)))
+            .min_by(|a, b| a.1.total_cmp(&b.1))?;
+
+        // Keep the current best unless the new one is clearly faster, so it doesn't flip between neighbours
+        if let Some(current) = &self.best_region
+            && let Some(current_ms) = latest(current)
+            && current_ms <= fastest_ms + options.tolerance_ms
+        {
+            return None;
+        }
+        if self.best_region.as_ref() == Some(&fastest) {
+            return None;
+        }
+
+        let previous = self.best_region.replace(fastest.clone());
+        Some(MonitorEvent::BestRegionChanged { previous, current: fastest, median_ms: fastest_ms })
+    }
+}
+
+/// Re-pings a set of regions in the background and keeps their recent history.
+///
+/// The sweeps run on their own thread, so the monitor can be used with or without a tokio
+/// runtime. It stops when [`RegionMonitor::stop`] is called or it is dropped.
+pub struct RegionMonitor {
+    state: Arc<Mutex<MonitorState>>,
+    subscribers: Arc<Mutex<Vec<Sender<MonitorEvent>>>>,
+    stop: Sender<()>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RegionMonitor {
+    /// Starts sweeping straight away. Events of the first sweep may be over before anyone can
+    /// [`subscribe`](Self::subscribe), use [`RegionMonitor::start_with_subscriber`] to see them.
+    pub fn start(regions: Vec<PhotonRegion>, options: MonitorOptions) -> Self {
+        Self::start_with_subscribers(regions, options, Vec::new())
+    }
+
+    /// Like [`RegionMonitor::start`], with a receiver that is subscribed before the first sweep,
+    /// so it gets every event including the initial [`MonitorEvent::BestRegionChanged`]
+    pub fn start_with_subscriber(regions: Vec<PhotonRegion>, options: MonitorOptions) -> (Self, Receiver<MonitorEvent>) {
+        let (tx, rx) = unbounded();
+        (Self::start_with_subscribers(regions, options, vec![tx]), rx)
+    }
+
+    fn start_with_subscribers(regions: Vec<PhotonRegion>, options: MonitorOptions, subscribers: Vec<Sender<MonitorEvent>>) -> Self {
+        let state = Arc::new(Mutex::new(MonitorState::default()));
+        let subscribers = Arc::new(Mutex::new(subscribers));
+        let (stop, stopped) = unbounded();
+
+        let thread_state = state.clone();
+        let thread_subscribers = subscribers.clone();
+        let thread = thread::spawn(move || {
+            let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    println!("Could not start region monitor: {}", e);
+                    return;
+                }
+            };
+
+            loop {
+                let started = Instant::now();
+                let results = runtime.block_on(ping_regions(&regions, &options.sweep));
+                let events = thread_state.lock().unwrap().record(results, started, &options);
+
+                // Drop subscribers whose receiver is gone
+                thread_subscribers.lock().unwrap().retain(|subscriber| {
+                    events.iter().all(|event| subscriber.send(event.clone()).is_ok())
+                });
+
+                match stopped.recv_timeout(options.interval.saturating_sub(started.elapsed())) {
+                    Err(RecvTimeoutError::Timeout) => {}
+                    _ => return,
+                }
+            }
+        });
+
+        RegionMonitor { state, subscribers, stop, thread: Some(thread) }
+    }
+
+    /// A receiver for every event from now on
+    pub fn subscribe(&self) -> Receiver<MonitorEvent> {
+        let (tx, rx) = unbounded();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// The region with the lowest latency, once a sweep has completed
+    pub fn best_region(&self) -> Option<String> {
+        self.state.lock().unwrap().best_region.clone()
+    }
+
+    pub fn history(&self, region: &str) -> Option<RegionHistory> {
+        self.state.lock().unwrap().histories.get(region).cloned()
+    }
+
+    /// Trend metrics of every region that has answered
+    pub fn trends(&self) -> HashMap<String, RegionTrend> {
+        self.state
+            .lock()
+            .unwrap()
+            .histories
+            .iter()
+            .filter_map(|(name, history)| history.trend().map(|trend| (name.clone(), trend)))
+            .collect()
+    }
+
+    /// Stops the background thread, waiting for a running sweep to finish
+    pub fn stop(&mut self) {
+        let _ = self.stop.send(());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for RegionMonitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(name: &str) -> PhotonRegion {
+        PhotonRegion { short_name: name.to_string(), address: format!("wss://{}.example:19090", name) }
+    }
+
+    fn stats(median_ms: u64, received: u32) -> PingStats {
+        let samples: Vec<Duration> = (0..received).map(|_| Duration::from_millis(median_ms)).collect();
+        PingStats::from_samples(&samples, 5)
+    }
+
+    fn sweep(state: &mut MonitorState, at: Instant, pings: &[(&str, u64, u32)]) -> Vec<MonitorEvent> {
+        let results = pings.iter().map(|&(name, median, received)| (region(name), Ok(stats(median, received)))).collect();
+        state.record(results, at, &MonitorOptions::default())
+    }
+
+    #[test]
+    fn test_best_region_changes_with_hysteresis() {
+        let mut state = MonitorState::default();
+        let start = Instant::now();
+
+        let events = sweep(&mut state, start, &[("eu", 30, 5), ("us", 40, 5)]);
+        assert_eq!(events, vec![MonitorEvent::BestRegionChanged { previous: None, current: "eu".to_string(), median_ms: 30.0 }]);
+
+        // us is faster now, but not by more than the tolerance
+        assert!(sweep(&mut state, start, &[("eu", 30, 5), ("us", 25, 5)]).is_empty());
+
+        let events = sweep(&mut state, start, &[("eu", 30, 5), ("us", 15, 5)]);
+        assert_eq!(events, vec![MonitorEvent::BestRegionChanged {
+            previous: Some("eu".to_string()),
+            current: "us".to_string(),
+            median_ms: 15.0,
+        }]);
+    }
+
+    #[test]
+    fn test_degradation_and_recovery() {
+        let mut state = MonitorState::default();
+        let start = Instant::now();
+        sweep(&mut state, start, &[("eu", 30, 5)]);
+        sweep(&mut state, start, &[("eu", 32, 5)]);
+
+        let events = sweep(&mut state, start, &[("eu", 90, 5)]);
+        assert!(matches!(&events[0], MonitorEvent::RegionDegraded { usual_ms, median_ms, .. } if *usual_ms == 32.0 && *median_ms == 90.0));
+
+        // Losing pings counts too, and only the change is reported
+        assert!(sweep(&mut state, start, &[("eu", 30, 3)]).is_empty());
+
+        let events = sweep(&mut state, start, &[("eu", 31, 5)]);
+        assert_eq!(events, vec![MonitorEvent::RegionRecovered { region: "eu".to_string(), median_ms: 31.0 }]);
+    }
+
+    #[test]
+    fn test_trend_metrics() {
+        let mut history = RegionHistory::default();
+        let start = Instant::now();
+        for minute in 0..5 {
+            let at = start + Duration::from_secs(60 * minute);
+            history.push(RegionSample { at, stats: stats(20 + 2 * minute, 5) }, 4);
+        }
+
+        let trend = history.trend().unwrap();
+        // The oldest sweep fell out of the history
+        assert_eq!(trend.samples, 4);
+        assert_eq!(trend.latest_ms, 28.0);
+        assert_eq!(trend.mean_ms, 25.0);
+        assert!((trend.slope_ms_per_minute - 2.0).abs() < 1e-9);
+        assert!((trend.std_dev_ms - 5.0f64.sqrt()).abs() < 1e-9);
+        assert_eq!(trend.loss_percent, 0.0);
+    }
+
+    #[test]
+    fn test_subscriber_sees_first_sweep() {
+        let responder = crate::PingResponder::start(crate::PingResponderOptions::default()).unwrap();
+        let options = MonitorOptions {
+            interval: Duration::from_secs(3600),
+            sweep: SweepOptions { samples: 2, fallback: false, ..SweepOptions::default() },
+            ..MonitorOptions::default()
+        };
+
+        let (mut monitor, events) = RegionMonitor::start_with_subscriber(vec![responder.region("local")], options);
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(event, MonitorEvent::BestRegionChanged { previous: None, ref current, .. } if current == "local"), "{:?}", event);
+        monitor.stop();
+    }
+
+    #[test]
+    fn test_monitor_stops() {
+        let options = MonitorOptions { interval: Duration::from_secs(3600), ..MonitorOptions::default() };
+        let mut monitor = RegionMonitor::start(Vec::new(), options);
+        let _events = monitor.subscribe();
+
+        let start = Instant::now();
+        monitor.stop();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert_eq!(monitor.best_region(), None);
+    }
+}
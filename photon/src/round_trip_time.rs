@@ -0,0 +1,187 @@
+use std::time::Instant;
+use once_cell::sync::Lazy;
+
+static START_TIME: Lazy<Instant> = Lazy::new(Instant::now);
+
+/// Milliseconds since the process started, truncated to the 32 bits Photon puts on the wire.
+///
+/// The value wraps around roughly every 49.7 days, so it must only ever be compared with
+/// [`timestamp_diff`], never with `<`/`>` or by casting to a wider type.
+pub(crate) fn local_timestamp() -> i32 {
+    START_TIME.elapsed().as_millis() as u32 as i32
+}
+
+/// Milliseconds from `earlier` to `later`, correct across the 32 bit wraparound
+pub(crate) fn timestamp_diff(later: i32, earlier: i32) -> i32 {
+    later.wrapping_sub(earlier)
+}
+
+/// A snapshot of the round trip statistics of the Photon connection, all in milliseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RoundTripStats {
+    /// Smoothed round trip time, like PUN's `RoundTripTime`
+    pub round_trip_time: i32,
+    /// Smoothed variance of the round trip time, like PUN's `RoundTripTimeVariance`
+    pub round_trip_time_variance: i32,
+    /// The most recent raw sample
+    pub last_round_trip_time: i32,
+    /// The lowest smoothed round trip time seen on this connection
+    pub lowest_round_trip_time: i32,
+    /// The highest smoothed variance seen on this connection
+    pub highest_round_trip_time_variance: i32,
+    /// Number of ping results that went into these numbers
+    pub samples: u32,
+}
+
+/// Tracks the round trip time and the server time offset from internal PING results
+#[derive(Debug, Clone, Default)]
+pub struct RoundTripTime {
+    stats: Option<RoundTripStats>,
+    server_time_offset: Option<i32>,
+    offset_round_trip_time: i32,
+}
+
+impl RoundTripTime {
+    pub const fn new() -> Self {
+        RoundTripTime {
+            stats: None,
+            server_time_offset: None,
+            offset_round_trip_time: 0,
+        }
+    }
+
+    /// Feeds one PING result: the client timestamp we sent (parameter 1), the server's
+    /// timestamp (parameter 2) and our local timestamp when the response arrived.
+    pub fn add_sample(&mut self, client_sent: i32, server_sent: i32, now: i32) {
+        let last = timestamp_diff(now, client_sent);
+        if last < 0 {
+            // A response to a timestamp from the future can only be garbage
+            return;
+        }
+
+        match &mut self.stats {
+            Some(stats) => {
+                // Same integer update rule as PUN's UpdateRoundTripTimeAndVariance
+                stats.round_trip_time_variance -= stats.round_trip_time_variance / 4;
+                if last >= stats.round_trip_time {
+                    stats.round_trip_time += (last - stats.round_trip_time) / 8;
+                    stats.round_trip_time_variance += (last - stats.round_trip_time) / 4;
+                } else {
+                    stats.round_trip_time += (last - stats.round_trip_time) / 8;
+                    stats.round_trip_time_variance -= (last - stats.round_trip_time) / 4;
+                }
+
+                stats.last_round_trip_time = last;
+                stats.lowest_round_trip_time = stats.lowest_round_trip_time.min(stats.round_trip_time);
+                stats.highest_round_trip_time_variance = stats.highest_round_trip_time_variance.max(stats.round_trip_time_variance);
+                stats.samples += 1;
+            }
+            None => self.stats = Some(RoundTripStats {
+                round_trip_time: last,
+                round_trip_time_variance: last / 2,
+                last_round_trip_time: last,
+                lowest_round_trip_time: last,
+                highest_round_trip_time_variance: last / 2,
+                samples: 1,
+            }),
+        }
+
+        // The server stamped its reply somewhere inside our round trip, so assuming it is in
+        // the middle the error is at most half the sample. Only tighter samples improve on that.
+        if self.server_time_offset.is_none() || last <= self.offset_round_trip_time {
+            self.server_time_offset = Some(timestamp_diff(server_sent.wrapping_add(last / 2), now));
+            self.offset_round_trip_time = last;
+        } else {
+            // Let the bound relax slowly so the offset can follow clock drift on long connections
+            self.offset_round_trip_time += 1;
+        }
+    }
+
+    pub fn stats(&self) -> Option<RoundTripStats> {
+        self.stats
+    }
+
+    /// Milliseconds to add to [`local_timestamp`] to get the server's timestamp
+    pub fn server_time_offset(&self) -> Option<i32> {
+        self.server_time_offset
+    }
+
+    /// Round trip time of the sample the current server time offset was taken from
+    pub fn offset_round_trip_time(&self) -> Option<i32> {
+        self.server_time_offset.map(|_| self.offset_round_trip_time)
+    }
+
+    /// The server's current timestamp, estimated from the local clock
+    pub fn server_timestamp(&self, now: i32) -> Option<i32> {
+        self.server_time_offset.map(|offset| now.wrapping_add(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timestamp_diff_wraps() {
+        assert_eq!(timestamp_diff(10, 5), 5);
+        assert_eq!(timestamp_diff(i32::MIN + 4, i32::MAX - 5), 10);
+        assert_eq!(timestamp_diff(i32::MAX - 5, i32::MIN + 4), -10);
+    }
+
+    #[test]
+    fn test_first_sample_initialises_stats() {
+        let mut rtt = RoundTripTime::new();
+        assert_eq!(rtt.stats(), None);
+        assert_eq!(rtt.server_time_offset(), None);
+
+        rtt.add_sample(1000, 50_000, 1040);
+
+        let stats = rtt.stats().unwrap();
+        assert_eq!(stats.round_trip_time, 40);
+        assert_eq!(stats.round_trip_time_variance, 20);
+        assert_eq!(stats.samples, 1);
+        // Server stamped 50000 at our 1020
+        assert_eq!(rtt.server_time_offset(), Some(50_000 + 20 - 1040));
+        assert_eq!(rtt.server_timestamp(2000), Some(50_000 + 20 + 960));
+    }
+
+    #[test]
+    fn test_smoothing_follows_samples() {
+        let mut rtt = RoundTripTime::new();
+        rtt.add_sample(0, 0, 40);
+        for i in 1..100 {
+            let sent = i * 1000;
+            rtt.add_sample(sent, 0, sent + 80);
+        }
+
+        let stats = rtt.stats().unwrap();
+        assert!((70..=80).contains(&stats.round_trip_time), "{:?}", stats);
+        assert!(stats.round_trip_time_variance < 10, "{:?}", stats);
+        assert_eq!(stats.lowest_round_trip_time, 40);
+        assert_eq!(stats.last_round_trip_time, 80);
+        assert_eq!(stats.samples, 100);
+    }
+
+    #[test]
+    fn test_offset_prefers_tighter_samples() {
+        let mut rtt = RoundTripTime::new();
+        rtt.add_sample(0, 10_000, 100);
+        assert_eq!(rtt.server_time_offset(), Some(10_000 + 50 - 100));
+
+        // Slower sample doesn't move the offset
+        rtt.add_sample(1000, 11_000, 1300);
+        assert_eq!(rtt.server_time_offset(), Some(10_000 + 50 - 100));
+
+        // Faster one does
+        rtt.add_sample(2000, 12_000, 2020);
+        assert_eq!(rtt.server_time_offset(), Some(12_000 + 10 - 2020));
+    }
+
+    #[test]
+    fn test_sample_across_wraparound() {
+        let mut rtt = RoundTripTime::new();
+        rtt.add_sample(i32::MAX - 10, 500, i32::MIN + 9);
+
+        assert_eq!(rtt.stats().unwrap().round_trip_time, 20);
+    }
+}
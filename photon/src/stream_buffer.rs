@@ -92,9 +92,7 @@ impl StreamBuffer {
     pub fn write(&mut self, data: &[u8]) {
         self.ensure_capacity(data.len());
 
-        for i in 0..data.len() {
-            self.buf[self.pos + i] = data[i];
-        }
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
 
         self.pos += data.len();
 
@@ -123,6 +121,7 @@ impl StreamBuffer {
         self.len
     }
 
+    #[cfg(test)]
     pub fn reset_position(&mut self) {
         self.pos = 0;
     }
@@ -147,7 +146,7 @@ mod tests {
     #[test]
     fn test_new() {
         let initial = [1, 2, 3, 4, 5];
-        let buffer = StreamBuffer::new(&initial);
+        let buffer = StreamBuffer::new(initial);
 
         assert_eq!(buffer.length(), 5);
         assert_eq!(buffer.position(), 0);
@@ -219,7 +218,7 @@ mod tests {
 
     #[test]
     fn test_position_and_remaining() {
-        let mut buffer = StreamBuffer::new(&[1, 2, 3, 4, 5]);
+        let mut buffer = StreamBuffer::new([1, 2, 3, 4, 5]);
 
         assert_eq!(buffer.position(), 0);
         assert_eq!(buffer.remaining(), 5);
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::message_type::EgMessageType;
+use crate::transport::{Transport, TransportError};
+
+/// How many of the most recent RTT samples a snapshot keeps
+const RTT_SAMPLE_LIMIT: usize = 64;
+
+/// A snapshot of the traffic on one name server connection, like PUN's `TrafficStats`
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct TrafficStats {
+    /// The connection these numbers belong to, see [`crate::CapturedFrame::connection`]
+    pub connection: u64,
+    /// How long the numbers have been collected for, since the connection opened or the last reset
+    pub elapsed: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub messages_in: u64,
+    pub messages_out: u64,
+    /// Operations we sent, by op code. Internal operations such as PING are included.
+    pub operations_out: BTreeMap<u8, u64>,
+    /// Operation responses we received, by op code
+    pub responses_in: BTreeMap<u8, u64>,
+    /// Events we received, by event code
+    pub events_in: BTreeMap<u8, u64>,
+    pub largest_message_in: usize,
+    pub largest_message_out: usize,
+    /// The longest time between two received messages
+    pub longest_receive_gap: Duration,
+    /// Total time spent handling received messages
+    pub dispatch_time: Duration,
+    pub longest_dispatch: Duration,
+    /// The most recent PING round trip times in milliseconds, oldest first
+    pub rtt_samples: Vec<i32>,
+}
+
+/// Collects [`TrafficStats`] as frames pass through a [`TrafficTransport`]
+#[derive(Debug)]
+pub struct TrafficRecorder {
+    stats: TrafficStats,
+    since: Instant,
+    last_received: Option<Instant>,
+}
+
+impl Default for TrafficRecorder {
+    fn default() -> Self {
+        TrafficRecorder { stats: TrafficStats::default(), since: Instant::now(), last_received: None }
+    }
+}
+
+impl TrafficRecorder {
+    /// Starts over for a new connection
+    pub fn start_connection(&mut self, connection: u64) {
+        *self = TrafficRecorder::default();
+        self.stats.connection = connection;
+    }
+
+    /// Clears the numbers, keeping the connection they belong to
+    pub fn reset(&mut self) {
+        self.start_connection(self.stats.connection);
+    }
+
+    pub fn snapshot(&self) -> TrafficStats {
+        TrafficStats { elapsed: self.since.elapsed(), ..self.stats.clone() }
+    }
+
+    pub fn record_sent(&mut self, frame: &[u8]) {
+        self.stats.bytes_out += frame.len() as u64;
+        self.stats.messages_out += 1;
+        self.stats.largest_message_out = self.stats.largest_message_out.max(frame.len());
+
+        if let Some((message_type, code)) = header(frame)
+            && matches!(message_type, EgMessageType::Operation | EgMessageType::InternalOperationRequest) {
+            *self.stats.operations_out.entry(code).or_default() += 1;
+        }
+    }
+
+    pub fn record_received(&mut self, frame: &[u8]) {
+        let now = Instant::now();
+        if let Some(last) = self.last_received {
+            self.stats.longest_receive_gap = self.stats.longest_receive_gap.max(now - last);
+        }
+        self.last_received = Some(now);
+
+        self.stats.bytes_in += frame.len() as u64;
+        self.stats.messages_in += 1;
+        self.stats.largest_message_in = self.stats.largest_message_in.max(frame.len());
+
+        match header(frame) {
+            Some((EgMessageType::OperationResponse | EgMessageType::InternalOperationResponse, code)) => {
+                *self.stats.responses_in.entry(code).or_default() += 1;
+            }
+            Some((EgMessageType::Event, code)) => *self.stats.events_in.entry(code).or_default() += 1,
+            _ => {}
+        }
+    }
+
+    pub fn record_dispatch(&mut self, duration: Duration) {
+        self.stats.dispatch_time += duration;
+        self.stats.longest_dispatch = self.stats.longest_dispatch.max(duration);
+    }
+
+    pub fn record_rtt(&mut self, rtt: i32) {
+        if self.stats.rtt_samples.len() == RTT_SAMPLE_LIMIT {
+            self.stats.rtt_samples.remove(0);
+        }
+        self.stats.rtt_samples.push(rtt);
+    }
+}
+
+/// The message type and the op or event code right after it, if the frame has them
+fn header(frame: &[u8]) -> Option<(EgMessageType, u8)> {
+    if frame.len() < 3 || (frame[0] != 243 && frame[0] != 253) || frame[1] & 0x80 != 0 {
+        return None;
+    }
+    EgMessageType::try_from(frame[1]).ok().map(|message_type| (message_type, frame[2]))
+}
+
+/// A [`Transport`] that counts every frame that passes through it
+pub struct TrafficTransport<T: Transport> {
+    inner: T,
+    recorder: Arc<Mutex<TrafficRecorder>>,
+}
+
+impl<T: Transport> TrafficTransport<T> {
+    pub fn new(inner: T, recorder: Arc<Mutex<TrafficRecorder>>) -> Self {
+        TrafficTransport { inner, recorder }
+    }
+}
+
+impl<T: Transport> Transport for TrafficTransport<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        self.inner.send_frame(frame)?;
+        self.recorder.lock().unwrap().record_sent(frame);
+        Ok(())
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        let frame = self.inner.receive_frame(timeout)?;
+        if let Some(frame) = &frame {
+            self.recorder.lock().unwrap().record_received(frame);
+        }
+        Ok(frame)
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        self.inner.close()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.inner.rtt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::transport::MemoryTransport;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_millis(100));
+
+    #[test]
+    fn test_counts_frames_by_direction_and_code() {
+        let recorder = Arc::new(Mutex::new(TrafficRecorder::default()));
+        let (client, mut server) = MemoryTransport::pair();
+        let mut client = TrafficTransport::new(client, recorder.clone());
+
+        client.send_frame(&[243, EgMessageType::Operation as u8, 220, 0]).unwrap();
+        client.send_frame(&[243, EgMessageType::InternalOperationRequest as u8, 1, 0]).unwrap();
+        client.send_frame(&[243, EgMessageType::InternalOperationRequest as u8, 1, 0]).unwrap();
+        server.send_frame(&[243, EgMessageType::InitResponse as u8]).unwrap();
+        server.send_frame(&[243, EgMessageType::OperationResponse as u8, 220, 0, 0, 8, 0]).unwrap();
+        server.send_frame(&[243, EgMessageType::Event as u8, 42, 0]).unwrap();
+        for _ in 0..3 {
+            client.receive_frame(TIMEOUT).unwrap().unwrap();
+        }
+
+        let stats = recorder.lock().unwrap().snapshot();
+        assert_eq!((stats.messages_out, stats.bytes_out, stats.largest_message_out), (3, 12, 4));
+        assert_eq!((stats.messages_in, stats.bytes_in, stats.largest_message_in), (3, 13, 7));
+        assert_eq!(stats.operations_out, BTreeMap::from([(1, 2), (220, 1)]));
+        assert_eq!(stats.responses_in, BTreeMap::from([(220, 1)]));
+        assert_eq!(stats.events_in, BTreeMap::from([(42, 1)]));
+    }
+
+    #[test]
+    fn test_longest_receive_gap() {
+        let mut recorder = TrafficRecorder::default();
+
+        recorder.record_received(&[243, 1]);
+        thread::sleep(Duration::from_millis(30));
+        recorder.record_received(&[243, 1]);
+        recorder.record_received(&[243, 1]);
+
+        assert!(recorder.snapshot().longest_receive_gap >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_dispatch_and_rtt_samples() {
+        let mut recorder = TrafficRecorder::default();
+
+        recorder.record_dispatch(Duration::from_millis(2));
+        recorder.record_dispatch(Duration::from_millis(5));
+        for rtt in 0..RTT_SAMPLE_LIMIT as i32 + 10 {
+            recorder.record_rtt(rtt);
+        }
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.dispatch_time, Duration::from_millis(7));
+        assert_eq!(stats.longest_dispatch, Duration::from_millis(5));
+        assert_eq!(stats.rtt_samples.len(), RTT_SAMPLE_LIMIT);
+        assert_eq!(stats.rtt_samples[0], 10);
+    }
+
+    #[test]
+    fn test_reset_keeps_connection() {
+        let mut recorder = TrafficRecorder::default();
+        recorder.start_connection(3);
+        recorder.record_sent(&[243, EgMessageType::Operation as u8, 220]);
+
+        recorder.reset();
+
+        let stats = recorder.snapshot();
+        assert_eq!(stats.connection, 3);
+        assert_eq!(stats.messages_out, 0);
+        assert!(stats.operations_out.is_empty());
+    }
+}
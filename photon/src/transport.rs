@@ -0,0 +1,273 @@
+use std::fmt;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use crossbeam_channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use websocket::{ClientBuilder, CloseData, OwnedMessage};
+use websocket::sync::Writer;
+
+/// Errors surfaced by a [`Transport`]
+#[derive(Debug)]
+pub enum TransportError {
+    /// The connection could not be established
+    Connect(String),
+    /// A frame could not be written to the connection
+    Send(String),
+    /// The connection has been closed, either by the peer or locally
+    Closed,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::Connect(e) => write!(f, "failed to connect: {}", e),
+            TransportError::Send(e) => write!(f, "failed to send frame: {}", e),
+            TransportError::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+/// A bidirectional, frame oriented connection to a Photon peer.
+///
+/// Every frame is one complete Photon message (starting with the 243/253 header), so the
+/// dispatcher never has to care whether it arrived over a websocket or an in-memory channel.
+pub trait Transport: Send {
+    /// Sends a single frame to the peer
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError>;
+
+    /// Waits for the next frame. Returns `Ok(None)` if `timeout` elapsed before a frame arrived,
+    /// and blocks indefinitely if no timeout is given.
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError>;
+
+    /// Closes the connection. Further sends fail with [`TransportError::Closed`].
+    fn close(&mut self) -> Result<(), TransportError>;
+
+    /// The transport's own round trip estimate, if it has one
+    fn rtt(&self) -> Option<Duration>;
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        (**self).send_frame(frame)
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        (**self).receive_frame(timeout)
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        (**self).close()
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        (**self).rtt()
+    }
+}
+
+enum Incoming {
+    Frame(Vec<u8>),
+    Closed,
+}
+
+/// A [`Transport`] over a websocket connection, as used by Photon's "GpBinaryV18" subprotocol
+pub struct WebSocketTransport {
+    writer: Arc<Mutex<Writer<TcpStream>>>,
+    incoming: Receiver<Incoming>,
+    rtt: Option<Duration>,
+    peer: Option<SocketAddr>,
+    closed: bool,
+}
+
+impl WebSocketTransport {
+    pub fn connect(url: &str, protocol: &str) -> Result<Self, TransportError> {
+        let start = Instant::now();
+        let client = ClientBuilder::new(url)
+            .map_err(|e| TransportError::Connect(e.to_string()))?
+            .add_protocol(protocol)
+            .connect_insecure()
+            .map_err(|e| TransportError::Connect(e.to_string()))?;
+
+        // The TCP + upgrade handshake takes two round trips, which is a reasonable first estimate
+        let rtt = Some(start.elapsed() / 2);
+        let peer = client.peer_addr().ok();
+
+        let (mut receiver, writer) = client.split().map_err(|e| TransportError::Connect(e.to_string()))?;
+        let writer = Arc::new(Mutex::new(writer));
+        let (tx, rx) = unbounded();
+
+        let reader_writer = writer.clone();
+        thread::spawn(move || {
+            for message in receiver.incoming_messages() {
+                match message {
+                    Ok(OwnedMessage::Binary(data)) => {
+                        if tx.send(Incoming::Frame(data)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(OwnedMessage::Ping(data)) => {
+                        let _ = reader_writer.lock().unwrap().send_message(&OwnedMessage::Pong(data));
+                    }
+                    Ok(OwnedMessage::Close(_)) => break,
+                    Ok(msg) => {
+                        println!("Received non-binary message: {:?}", msg);
+                    }
+                    Err(e) => {
+                        println!("Error receiving message: {:?}", e);
+                        break;
+                    }
+                }
+            }
+            let _ = tx.send(Incoming::Closed);
+        });
+
+        Ok(WebSocketTransport {
+            writer,
+            incoming: rx,
+            rtt,
+            peer,
+            closed: false,
+        })
+    }
+
+    /// The address the connection went to, which tells apart servers behind the same host name
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer
+    }
+}
+
+impl Transport for WebSocketTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        if self.closed {
+            return Err(TransportError::Closed);
+        }
+
+        self.writer.lock().unwrap()
+            .send_message(&OwnedMessage::Binary(frame.to_vec()))
+            .map_err(|e| TransportError::Send(e.to_string()))
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        let incoming = match timeout {
+            Some(timeout) => match self.incoming.recv_timeout(timeout) {
+                Ok(incoming) => incoming,
+                Err(RecvTimeoutError::Timeout) => return Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Incoming::Closed,
+            },
+            None => self.incoming.recv().unwrap_or(Incoming::Closed),
+        };
+
+        match incoming {
+            Incoming::Frame(data) => Ok(Some(data)),
+            Incoming::Closed => {
+                self.closed = true;
+                Err(TransportError::Closed)
+            }
+        }
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        if self.closed {
+            return Ok(());
+        }
+        self.closed = true;
+
+        // 1000 is a normal closure, so the server doesn't treat us as having dropped out
+        let mut writer = self.writer.lock().unwrap();
+        let _ = writer.send_message(&OwnedMessage::Close(Some(CloseData::new(1000, "Client disconnect".to_string()))));
+        writer.shutdown_all().map_err(|e| TransportError::Send(e.to_string()))
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+/// One end of an in-memory [`Transport`]. Frames sent on one end are received by the other,
+/// which makes it possible to drive the whole protocol stack without any sockets.
+pub struct MemoryTransport {
+    tx: Option<Sender<Vec<u8>>>,
+    rx: Receiver<Vec<u8>>,
+    rtt: Option<Duration>,
+}
+
+impl MemoryTransport {
+    /// Creates two connected ends
+    pub fn pair() -> (MemoryTransport, MemoryTransport) {
+        let (a_tx, b_rx) = unbounded();
+        let (b_tx, a_rx) = unbounded();
+
+        (
+            MemoryTransport { tx: Some(a_tx), rx: a_rx, rtt: None },
+            MemoryTransport { tx: Some(b_tx), rx: b_rx, rtt: None },
+        )
+    }
+
+    /// Sets the round trip time this end reports through [`Transport::rtt`]
+    pub fn set_rtt(&mut self, rtt: Option<Duration>) {
+        self.rtt = rtt;
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn send_frame(&mut self, frame: &[u8]) -> Result<(), TransportError> {
+        match &self.tx {
+            Some(tx) => tx.send(frame.to_vec()).map_err(|_| TransportError::Closed),
+            None => Err(TransportError::Closed),
+        }
+    }
+
+    fn receive_frame(&mut self, timeout: Option<Duration>) -> Result<Option<Vec<u8>>, TransportError> {
+        match timeout {
+            Some(timeout) => match self.rx.recv_timeout(timeout) {
+                Ok(frame) => Ok(Some(frame)),
+                Err(RecvTimeoutError::Timeout) => Ok(None),
+                Err(RecvTimeoutError::Disconnected) => Err(TransportError::Closed),
+            },
+            None => self.rx.recv().map(Some).map_err(|_| TransportError::Closed),
+        }
+    }
+
+    fn close(&mut self) -> Result<(), TransportError> {
+        // Dropping our sender is what the other end observes as the close
+        self.tx = None;
+        Ok(())
+    }
+
+    fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_pair_round_trip() {
+        let (mut a, mut b) = MemoryTransport::pair();
+
+        a.send_frame(&[243, 1]).unwrap();
+        assert_eq!(b.receive_frame(Some(Duration::from_millis(100))).unwrap(), Some(vec![243, 1]));
+
+        b.send_frame(&[1, 2, 3]).unwrap();
+        assert_eq!(a.receive_frame(None).unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_memory_receive_timeout() {
+        let (mut a, _b) = MemoryTransport::pair();
+        assert_eq!(a.receive_frame(Some(Duration::from_millis(10))).unwrap(), None);
+    }
+
+    #[test]
+    fn test_memory_close() {
+        let (mut a, mut b) = MemoryTransport::pair();
+        a.close().unwrap();
+
+        assert!(matches!(a.send_frame(&[0]), Err(TransportError::Closed)));
+        assert!(matches!(b.receive_frame(None), Err(TransportError::Closed)));
+    }
+}
@@ -0,0 +1,33 @@
+//! Drives the real connection worker against the mock name server. Lives in its own test binary
+//! because the connection is global to the process.
+
+use std::time::Duration;
+use photon::{MockNameServer, MockNameServerOptions, PhotonRegion};
+
+#[test]
+fn test_get_regions_from_mock_name_server() {
+    let regions = vec![
+        PhotonRegion { short_name: "eu".to_string(), address: "wss://eu.example:19090".to_string() },
+        PhotonRegion { short_name: "us".to_string(), address: "wss://us.example:19090".to_string() },
+    ];
+    let options = MockNameServerOptions {
+        regions: regions.clone(),
+        reply_delay: Duration::from_millis(20),
+        malformed_frames: true,
+        ..Default::default()
+    };
+    let server = MockNameServer::start(options).unwrap();
+
+    photon::set_name_server_address(&server.url());
+    let received = photon::get_regions_timeout(Duration::from_secs(5)).unwrap();
+
+    assert_eq!(received, regions);
+    assert_eq!(server.connections(), 1);
+
+    let traffic = photon::traffic_stats();
+    assert_eq!(traffic.connection, 1);
+    assert_eq!(traffic.operations_out.get(&220), Some(&1));
+    assert_eq!(traffic.responses_in.get(&220), Some(&1));
+    assert!(traffic.bytes_in > 0 && traffic.bytes_out > 0);
+    photon::disconnect();
+}
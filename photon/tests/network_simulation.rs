@@ -0,0 +1,28 @@
+//! Drives the real connection worker through a simulated network. Lives in its own test binary
+//! because the connection is global to the process.
+
+use std::time::{Duration, Instant};
+use photon::{MockNameServer, MockNameServerOptions, NetworkSimulationSet, NetworkSimulator, SimulationSettings};
+
+#[test]
+fn test_get_regions_through_simulated_lag() {
+    let server = MockNameServer::start(MockNameServerOptions::default()).unwrap();
+    let simulator = NetworkSimulator::new(NetworkSimulationSet {
+        incoming: SimulationSettings { lag: Duration::from_millis(100), ..Default::default() },
+        outgoing: SimulationSettings { lag: Duration::from_millis(50), ..Default::default() },
+    });
+
+    photon::set_name_server_address(&server.url());
+    photon::simulate_network(&simulator);
+    let started = Instant::now();
+    let received = photon::get_regions_timeout(Duration::from_secs(5)).unwrap();
+
+    assert!(!received.is_empty());
+    // The server's init response comes in, the region request goes out and its answer comes in
+    assert!(started.elapsed() >= Duration::from_millis(250));
+    let stats = simulator.stats();
+    assert!(stats.outgoing.delivered >= 2);
+    assert!(stats.incoming.delivered >= 2);
+    assert_eq!(stats.incoming.lost + stats.outgoing.lost, 0);
+    photon::disconnect();
+}
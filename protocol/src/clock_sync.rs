@@ -0,0 +1,228 @@
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+
+/// How many of the most recent samples an estimator keeps
+const DEFAULT_WINDOW: usize = 32;
+
+/// Only this share of the samples with the lowest round trip is trusted, as in NTP's clock
+/// filter. Long round trips are usually one-sided queueing, which skews the offset.
+const FILTER_SHARE: usize = 4;
+
+/// Nor is a sample whose round trip is more than this over twice the shortest one
+const FILTER_SLACK_US: u64 = 1_000;
+
+/// Fewer filtered samples than this, or less time between them, and drift isn't estimated
+const DRIFT_MIN_SAMPLES: usize = 3;
+const DRIFT_MIN_SPAN_US: u64 = 10_000_000;
+
+/// Now on this machine's wall clock, in microseconds since the Unix epoch
+pub fn unix_time_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).expect("Time went backwards").as_micros() as u64
+}
+
+/// One request/response exchange, reduced to what it says about the two clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockSample {
+    /// How far the server's clock is ahead of ours
+    pub offset_us: i64,
+    /// The round trip, not counting the time the server held the request
+    pub delay_us: u64,
+    /// When the response arrived, on our clock
+    pub at_us: u64,
+}
+
+impl ClockSample {
+    /// From the four NTP timestamps: request sent (`t0`) and response received (`t3`) on our
+    /// clock, request received (`t1`) and response sent (`t2`) on the server's
+    pub fn from_timestamps(t0: u64, t1: u64, t2: u64, t3: u64) -> Self {
+        let (t0, t1, t2, t3) = (t0 as i64, t1 as i64, t2 as i64, t3 as i64);
+        ClockSample {
+            offset_us: ((t1 - t0) + (t2 - t3)) / 2,
+            delay_us: ((t3 - t0) - (t2 - t1)).max(0) as u64,
+            at_us: t3 as u64,
+        }
+    }
+}
+
+/// What a [`ClockEstimator`] believes about the server's clock
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ClockEstimate {
+    /// How far the server's clock is ahead of ours: server time = our time + offset
+    pub offset_us: i64,
+    /// The offset is off by at most about this much
+    pub uncertainty_us: u64,
+    /// How fast the offset changes, in microseconds per second. Zero until there is enough data.
+    pub drift_ppm: f64,
+    /// The shortest round trip seen, which bounds the uncertainty
+    pub round_trip_us: u64,
+    pub samples: usize,
+}
+
+impl ClockEstimate {
+    /// Our time converted to the server's clock
+    pub fn to_server_time(&self, local_us: u64) -> u64 {
+        local_us.saturating_add_signed(self.offset_us)
+    }
+
+    /// The server's time converted to our clock
+    pub fn to_local_time(&self, server_us: u64) -> u64 {
+        server_us.saturating_add_signed(-self.offset_us)
+    }
+}
+
+/// Estimates the offset between our clock and the server's from [`ClockSample`]s.
+///
+/// Only the samples with the shortest round trips are used. The offset is taken from the best of
+/// them and carried forward to the present with the drift, which is fitted over all of them.
+#[derive(Debug, Clone)]
+pub struct ClockEstimator {
+    samples: VecDeque<ClockSample>,
+    window: usize,
+}
+
+impl Default for ClockEstimator {
+    fn default() -> Self {
+        ClockEstimator::new(DEFAULT_WINDOW)
+    }
+}
+
+impl ClockEstimator {
+    /// Keeps the latest `window` samples
+    pub fn new(window: usize) -> Self {
+        ClockEstimator { samples: VecDeque::with_capacity(window), window: window.max(1) }
+    }
+
+    pub fn add(&mut self, sample: ClockSample) {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn sample_count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// The estimate as of `now_us` on our clock, or `None` before the first sample
+    pub fn estimate(&self, now_us: u64) -> Option<ClockEstimate> {
+        let mut filtered: Vec<ClockSample> = self.samples.iter().copied().collect();
+        filtered.sort_by_key(|sample| sample.delay_us);
+        filtered.truncate(filtered.len().div_ceil(FILTER_SHARE));
+        let best = *filtered.first()?;
+        filtered.retain(|sample| sample.delay_us <= best.delay_us * 2 + FILTER_SLACK_US);
+
+        let drift = drift(&filtered);
+        let age_s = now_us.saturating_sub(best.at_us) as f64 / 1e6;
+        let offset_us = best.offset_us + (drift * age_s).round() as i64;
+
+        // Half the round trip is how asymmetric the path could have been, plus how much the
+        // trusted samples disagree with where the drift says they should be
+        let spread = filtered.iter()
+            .map(|sample| {
+                let expected = best.offset_us as f64 + drift * (sample.at_us as f64 - best.at_us as f64) / 1e6;
+                (sample.offset_us as f64 - expected).abs()
+            })
+            .fold(0.0, f64::max);
+        let uncertainty_us = best.delay_us / 2 + spread.round() as u64;
+
+        Some(ClockEstimate {
+            offset_us,
+            uncertainty_us,
+            drift_ppm: drift,
+            round_trip_us: best.delay_us,
+            samples: self.samples.len(),
+        })
+    }
+}
+
+/// The least squares slope of offset over time, in microseconds per second
+fn drift(samples: &[ClockSample]) -> f64 {
+    let first = samples.iter().map(|sample| sample.at_us).min().unwrap_or(0);
+    let last = samples.iter().map(|sample| sample.at_us).max().unwrap_or(0);
+    if samples.len() < DRIFT_MIN_SAMPLES || last - first < DRIFT_MIN_SPAN_US {
+        return 0.0;
+    }
+
+    let points: Vec<(f64, f64)> = samples.iter()
+        .map(|sample| ((sample.at_us - first) as f64 / 1e6, sample.offset_us as f64))
+        .collect();
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let covariance: f64 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+
+    if variance == 0.0 { 0.0 } else { covariance / variance }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An exchange with a server `offset_us` ahead of us, `up_us` and `down_us` on the wire and
+    /// the request held for 100us
+    fn exchange(at_us: u64, offset_us: i64, up_us: u64, down_us: u64) -> ClockSample {
+        let t0 = at_us;
+        let t1 = (t0 + up_us).saturating_add_signed(offset_us);
+        let t2 = t1 + 100;
+        let t3 = t0 + up_us + 100 + down_us;
+        ClockSample::from_timestamps(t0, t1, t2, t3)
+    }
+
+    #[test]
+    fn test_sample_from_timestamps() {
+        let sample = exchange(1_000_000, 5_000, 2_000, 2_000);
+        assert_eq!((sample.offset_us, sample.delay_us), (5_000, 4_000));
+
+        // An asymmetric path shows up as offset error of half the difference
+        let sample = exchange(1_000_000, -5_000, 1_000, 9_000);
+        assert_eq!((sample.offset_us, sample.delay_us), (-9_000, 10_000));
+    }
+
+    #[test]
+    fn test_min_round_trip_filter() {
+        let mut estimator = ClockEstimator::default();
+        for i in 0..8 {
+            // Queueing on the way back on all but one exchange
+            let down_us = if i == 5 { 1_000 } else { 40_000 };
+            estimator.add(exchange(1_000_000 + i * 100_000, 7_000, 1_000, down_us));
+        }
+
+        let estimate = estimator.estimate(2_000_000).unwrap();
+        assert_eq!(estimate.offset_us, 7_000);
+        assert_eq!(estimate.round_trip_us, 2_000);
+        assert_eq!(estimate.drift_ppm, 0.0);
+        assert_eq!(estimate.uncertainty_us, 1_000);
+        assert_eq!(estimate.samples, 8);
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut estimator = ClockEstimator::new(64);
+        // The server's clock gains 50us every second
+        for second in 0..60u64 {
+            let offset_us = 3_000 + 50 * second as i64;
+            estimator.add(exchange(second * 1_000_000, offset_us, 1_000, 1_000));
+        }
+
+        let estimate = estimator.estimate(100_000_000).unwrap();
+        assert!((estimate.drift_ppm - 50.0).abs() < 1.0, "{:?}", estimate);
+        // Carried forward to 100s from the best sample
+        assert!((estimate.offset_us - 8_000).abs() < 100, "{:?}", estimate);
+    }
+
+    #[test]
+    fn test_window_and_conversion() {
+        let mut estimator = ClockEstimator::new(2);
+        assert_eq!(estimator.estimate(0), None);
+        for i in 0..5 {
+            estimator.add(exchange(i * 1_000, -2_000, 500, 500));
+        }
+        assert_eq!(estimator.sample_count(), 2);
+
+        let estimate = estimator.estimate(5_000).unwrap();
+        assert_eq!(estimate.to_server_time(10_000), 8_000);
+        assert_eq!(estimate.to_local_time(8_000), 10_000);
+    }
+}
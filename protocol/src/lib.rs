@@ -0,0 +1,514 @@
+//! The messages the LagLock server and its clients exchange over their websocket.
+//!
+//! Every text frame is one JSON [`Envelope`], such as
+//! `{"version":4,"message":{"type":"send_play","region":"eu","content":"intro"}}`, so message
+//! content may contain any character. Anything that can't be read is answered with a
+//! [`Message::Error`] rather than ignored.
+//!
+//! A client opens with [`Message::Hello`] and the server answers with [`Message::Welcome`], or
+//! with an error and a closed connection if it can't serve the client.
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+pub use crate::clock_sync::{unix_time_us, ClockEstimate, ClockEstimator, ClockSample};
+
+mod clock_sync;
+
+/// Bumped whenever a message changes in a way older peers can't read
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The oldest version this side still speaks. Version 1 had no handshake, version 2 timed PLAY
+/// on the server's wall clock only and version 3 had no staggered sends.
+pub const MIN_PROTOCOL_VERSION: u32 = 3;
+
+/// The first version that knows [`Message::Trigger`], so can be scheduled
+/// [`SchedulingMode::Staggered`]
+pub const TRIGGER_PROTOCOL_VERSION: u32 = 4;
+
+/// Something a peer can do beyond the basic protocol, agreed on during the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// The client plays audio itself, rather than only reporting when to
+    PlayAudio,
+    /// PLAY targets in Photon server time, see [`Message::PlayPhoton`]
+    PhotonClock,
+    /// Clock synchronisation over UDP
+    UdpSync,
+    /// Clock offset estimation over the websocket, see [`Message::TimeRequest`]
+    ClockSync,
+    /// A capability from a newer peer, which nobody here can negotiate
+    #[serde(other)]
+    Unknown,
+}
+
+/// The capabilities both sides listed, in the order of `ours`
+pub fn negotiate(ours: &[Capability], theirs: &[Capability]) -> Vec<Capability> {
+    ours.iter()
+        .filter(|capability| **capability != Capability::Unknown && theirs.contains(capability))
+        .copied()
+        .collect()
+}
+
+/// Who a client is, as it introduces itself in [`Message::Hello`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub display_name: String,
+    /// Operating system, such as "linux" or "windows"
+    pub platform: String,
+    pub software_version: String,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    /// How the client would like to be scheduled, if it has a preference. Clients that can't
+    /// take part in clock sync ask for [`SchedulingMode::Staggered`].
+    #[serde(default)]
+    pub scheduling: Option<SchedulingMode>,
+}
+
+/// How the server gets a client to play at the target time
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulingMode {
+    /// The client is sent the target ahead of time and waits for it on its own clock
+    #[default]
+    Scheduled,
+    /// The server holds the message back by the client's one-way delay and the client plays on
+    /// receipt, see [`Message::Trigger`]. Needs no clock agreement, but is only as good as the
+    /// latency estimate.
+    Staggered,
+}
+
+/// How one client was scheduled for a PLAY, as reported in [`Message::PlaySent`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientSchedule {
+    pub session_id: u64,
+    pub display_name: String,
+    pub mode: SchedulingMode,
+    /// How far from the target the client is expected to play, at most, in milliseconds. None if
+    /// nothing bounds it, as for clients that are trusted to have the right wall clock.
+    #[serde(default)]
+    pub error_bound_ms: Option<u64>,
+}
+
+/// Server settings a client may want to follow, sent in [`Message::Welcome`]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerConfig {
+    /// How often the server measures the round trip to each client
+    pub ping_interval_ms: u64,
+    /// How long the server waits for [`Message::PhotonPings`] before scheduling a PLAY
+    pub ping_reply_timeout_ms: u64,
+    /// The Photon region PLAY targets are timed for unless a command names another
+    pub default_region: String,
+}
+
+/// One region's latency as measured by a client
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RegionPingInfo {
+    pub region: String,
+    /// Mean round trip in milliseconds
+    pub latency: u64,
+    /// When the region was pinged, in milliseconds since the Unix epoch
+    pub last_updated: u64,
+    // Detailed statistics, missing from clients that only report the average
+    #[serde(default)]
+    pub stats: Option<PingStats>,
+}
+
+/// How a region's latency was measured
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PingMethod {
+    /// Photon's UDP ping
+    #[default]
+    Udp,
+    /// Photon's internal PING operation over a websocket
+    WebSocket,
+    /// Time to establish a TCP connection
+    TcpConnect,
+}
+
+/// Summary of one ping run against a region, all times in microseconds. The same fields as the
+/// photon crate's statistics, which the client converts from.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PingStats {
+    #[serde(default)]
+    pub method: PingMethod,
+    /// The address that was pinged, if known
+    #[serde(default)]
+    pub endpoint: Option<String>,
+    /// Round trip of every answered ping, in the order they were sent
+    pub samples_us: Vec<u64>,
+    pub min_us: u64,
+    pub max_us: u64,
+    pub mean_us: u64,
+    pub median_us: u64,
+    pub p95_us: u64,
+    pub std_dev_us: u64,
+    pub jitter_us: u64,
+    pub sent: u32,
+    pub received: u32,
+}
+
+impl PingStats {
+    /// Whether any ping was answered at all
+    pub fn is_reachable(&self) -> bool {
+        self.received > 0
+    }
+
+    /// The 95th percentile in whole milliseconds, rounded up
+    pub fn p95_ms(&self) -> u128 {
+        self.p95_us.div_ceil(1000) as u128
+    }
+}
+
+/// When to play, in microseconds since the Unix epoch on one of two clocks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "clock", rename_all = "snake_case")]
+pub enum PlayTarget {
+    /// On the receiving client's own clock, converted by the server from the client's
+    /// [`Message::ClockReport`]
+    Local { timestamp_us: u64 },
+    /// On the server's clock, the time base clock sync estimates against. Clients convert it with
+    /// their own [`ClockEstimate`].
+    Server { timestamp_us: u64 },
+}
+
+/// Why a message was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame is not a message of any protocol version
+    Malformed,
+    /// The sender speaks another protocol version
+    UnsupportedVersion,
+    /// A valid message, but not one the receiver accepts
+    UnexpectedMessage,
+    /// No connected client has the session id the message names
+    UnknownSession,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// The first message a client sends
+    Hello {
+        protocol_version: u32,
+        client: ClientInfo,
+    },
+    /// The server's answer to an acceptable [`Message::Hello`]
+    Welcome {
+        session_id: u64,
+        config: ServerConfig,
+        /// The capabilities both sides have, which are the only ones either may rely on
+        features: Vec<Capability>,
+    },
+    /// Asks the server to have every client play `content`, timed for `region` or the server's
+    /// default region
+    SendPlay {
+        #[serde(default)]
+        region: Option<String>,
+        content: String,
+    },
+    /// The server's confirmation of a [`Message::SendPlay`], with how each client was scheduled
+    PlaySent {
+        content: String,
+        #[serde(default)]
+        clients: Vec<ClientSchedule>,
+    },
+    /// Picks the scheduling mode of the client with `session_id`, or the server's default for
+    /// clients that have none of their own if there is no session id
+    SetScheduling {
+        #[serde(default)]
+        session_id: Option<u64>,
+        mode: SchedulingMode,
+    },
+    /// The server's confirmation of a [`Message::SetScheduling`]
+    SchedulingSet {
+        session_id: Option<u64>,
+        mode: SchedulingMode,
+    },
+    /// Asks for the latency to `region`, or to every region if there is none. Sent by the server
+    /// to its clients, or by a client to have the server ask everyone.
+    RequestPings {
+        #[serde(default)]
+        region: Option<String>,
+    },
+    /// A client's answer to [`Message::RequestPings`]
+    PhotonPings {
+        regions: Vec<RegionPingInfo>,
+        /// The Photon server the client's Photon clock follows, if it is synchronised. Photon
+        /// timestamps from different servers have nothing to do with each other.
+        #[serde(default)]
+        photon_server: Option<String>,
+        /// How far the client's Photon clock may be from `photon_server`'s time, in microseconds
+        #[serde(default)]
+        photon_uncertainty_us: Option<u64>,
+    },
+    /// The server's confirmation of a [`Message::PhotonPings`]
+    PingsReceived,
+    /// Play `content` at `target`
+    Play {
+        target: PlayTarget,
+        content: String,
+        /// How far ahead the target was set, in milliseconds
+        lead_ms: u64,
+    },
+    /// Play `content` at `photon_timestamp` in the time of `photon_server`, or at
+    /// `target_timestamp` on the server's wall clock if the client isn't synchronised with that
+    /// Photon server
+    PlayPhoton {
+        photon_timestamp: i32,
+        target_timestamp: u64,
+        content: String,
+        #[serde(default)]
+        photon_server: Option<String>,
+    },
+    /// Play `content` now. Sent instead of [`Message::Play`] to clients in
+    /// [`SchedulingMode::Staggered`], which only peers at [`TRIGGER_PROTOCOL_VERSION`] or above are.
+    Trigger { content: String },
+    /// Asks the server for its time, starting an NTP style exchange. Times are in microseconds
+    /// since the Unix epoch on the clock of whoever took them.
+    TimeRequest { client_send_us: u64 },
+    /// The server's answer to a [`Message::TimeRequest`]. Together with when this arrives, the
+    /// four timestamps make a [`ClockSample`].
+    TimeResponse {
+        client_send_us: u64,
+        server_receive_us: u64,
+        server_send_us: u64,
+    },
+    /// The client's current estimate of its offset from the server's clock
+    ClockReport { estimate: ClockEstimate },
+    Error { code: ErrorCode, message: String },
+}
+
+impl Message {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Message::Error { code, message: message.into() }
+    }
+}
+
+/// What goes over the wire: a message and the protocol version it was written in
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u32,
+    pub message: Message,
+}
+
+/// Errors reading a frame with [`decode`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Not valid JSON, or not a message we know
+    Malformed(String),
+    /// Written in a protocol version outside [`MIN_PROTOCOL_VERSION`] to [`PROTOCOL_VERSION`]
+    UnsupportedVersion(u32),
+}
+
+impl ProtocolError {
+    /// Checks that a peer's protocol version is one we speak
+    pub fn check_version(version: u32) -> Result<(), ProtocolError> {
+        if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+            Ok(())
+        } else {
+            Err(ProtocolError::UnsupportedVersion(version))
+        }
+    }
+
+    /// The error to send back to the peer the frame came from
+    pub fn reply(&self) -> Message {
+        match self {
+            ProtocolError::Malformed(_) => Message::error(ErrorCode::Malformed, self.to_string()),
+            ProtocolError::UnsupportedVersion(_) => Message::error(ErrorCode::UnsupportedVersion, self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Malformed(e) => write!(f, "malformed message: {}", e),
+            ProtocolError::UnsupportedVersion(version) if MIN_PROTOCOL_VERSION == PROTOCOL_VERSION => {
+                write!(f, "unsupported protocol version {}, expected {}", version, PROTOCOL_VERSION)
+            }
+            ProtocolError::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}, expected {} to {}", version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Writes `message` as a frame in the current protocol version
+pub fn encode(message: &Message) -> String {
+    encode_as(message, PROTOCOL_VERSION)
+}
+
+/// Writes `message` as a frame in `version`, for peers that only speak an older one. The message
+/// has to be one that version has.
+pub fn encode_as(message: &Message, version: u32) -> String {
+    let envelope = Envelope { version, message: message.clone() };
+    serde_json::to_string(&envelope).expect("messages always serialize")
+}
+
+/// Reads a frame written by [`encode`]
+pub fn decode(text: &str) -> Result<Message, ProtocolError> {
+    // The version decides how the rest is read, so look at it on its own first
+    #[derive(Deserialize)]
+    struct Version {
+        version: u32,
+    }
+
+    let version: Version = serde_json::from_str(text).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    ProtocolError::check_version(version.version)?;
+
+    let envelope: Envelope = serde_json::from_str(text).map_err(|e| ProtocolError::Malformed(e.to_string()))?;
+    Ok(envelope.message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let messages = vec![
+            Message::Hello {
+                protocol_version: PROTOCOL_VERSION,
+                client: ClientInfo {
+                    display_name: "stage left".to_string(),
+                    platform: "linux".to_string(),
+                    software_version: "0.1.0".to_string(),
+                    capabilities: vec![Capability::PlayAudio, Capability::PhotonClock],
+                    scheduling: Some(SchedulingMode::Staggered),
+                },
+            },
+            Message::Welcome {
+                session_id: 7,
+                config: ServerConfig { ping_interval_ms: 2000, ping_reply_timeout_ms: 2000, default_region: "eu".to_string() },
+                features: vec![Capability::PhotonClock],
+            },
+            Message::SendPlay { region: Some("eu".to_string()), content: "intro".to_string() },
+            Message::RequestPings { region: None },
+            Message::PhotonPings {
+                regions: vec![RegionPingInfo {
+                    region: "eu".to_string(),
+                    latency: 23,
+                    last_updated: 1_750_000_000_000,
+                    stats: Some(PingStats { endpoint: Some("10.0.0.1:5055".to_string()), ..PingStats::default() }),
+                }],
+                photon_server: Some("10.0.0.1:443".to_string()),
+                photon_uncertainty_us: Some(4_000),
+            },
+            Message::PingsReceived,
+            Message::SetScheduling { session_id: Some(3), mode: SchedulingMode::Staggered },
+            Message::SchedulingSet { session_id: None, mode: SchedulingMode::Scheduled },
+            Message::PlaySent {
+                content: "b".to_string(),
+                clients: vec![ClientSchedule {
+                    session_id: 3,
+                    display_name: "stage left".to_string(),
+                    mode: SchedulingMode::Staggered,
+                    error_bound_ms: Some(4),
+                }, ClientSchedule {
+                    session_id: 4,
+                    display_name: "stage right".to_string(),
+                    mode: SchedulingMode::Scheduled,
+                    error_bound_ms: None,
+                }],
+            },
+            Message::Trigger { content: "b".to_string() },
+            Message::Play { target: PlayTarget::Server { timestamp_us: 1_750_000_000_500_000 }, content: "b".to_string(), lead_ms: 120 },
+            Message::PlayPhoton {
+                photon_timestamp: -5,
+                target_timestamp: 1_750_000_000_500,
+                content: "a".to_string(),
+                photon_server: Some("10.0.0.1:443".to_string()),
+            },
+            Message::TimeRequest { client_send_us: 1_750_000_000_000_000 },
+            Message::TimeResponse { client_send_us: 1, server_receive_us: 2, server_send_us: 3 },
+            Message::ClockReport {
+                estimate: ClockEstimate { offset_us: -1_500, uncertainty_us: 800, drift_ppm: 1.5, round_trip_us: 1_600, samples: 8 },
+            },
+            Message::error(ErrorCode::UnexpectedMessage, "no"),
+        ];
+
+        for message in messages {
+            assert_eq!(decode(&encode(&message)), Ok(message));
+        }
+    }
+
+    #[test]
+    fn test_play_content_keeps_colons() {
+        // The old PLAY:ts:msg:rtt text format folded the RTT into the content
+        let target = PlayTarget::Local { timestamp_us: 1_750_000_000_500_000 };
+        let message = Message::Play { target, content: "cue:3:go".to_string(), lead_ms: 80 };
+
+        match decode(&encode(&message)).unwrap() {
+            Message::Play { content, lead_ms, .. } => assert_eq!((content.as_str(), lead_ms), ("cue:3:go", 80)),
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_wire_format() {
+        let text = encode(&Message::SendPlay { region: None, content: "intro".to_string() });
+        assert_eq!(text, r#"{"version":4,"message":{"type":"send_play","region":null,"content":"intro"}}"#);
+
+        // Optional fields may be left out
+        let message = decode(r#"{"version":4,"message":{"type":"request_pings"}}"#).unwrap();
+        assert_eq!(message, Message::RequestPings { region: None });
+    }
+
+    #[test]
+    fn test_ping_stats_wire_format() {
+        // As clients that sent the photon crate's own statistics wrote them
+        let json = r#"{"method":"WebSocket","endpoint":"10.0.0.1:443","samples_us":[900,1100],"min_us":900,"max_us":1100,
+            "mean_us":1000,"median_us":1000,"p95_us":1100,"std_dev_us":100,"jitter_us":200,"sent":3,"received":2}"#;
+        let stats: PingStats = serde_json::from_str(json).unwrap();
+
+        assert_eq!(stats.method, PingMethod::WebSocket);
+        assert_eq!(stats.endpoint.as_deref(), Some("10.0.0.1:443"));
+        assert_eq!((stats.p95_us, stats.sent, stats.received), (1100, 3, 2));
+        assert!(stats.is_reachable());
+        assert_eq!(stats.p95_ms(), 2);
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let error = decode(r#"{"version":5,"message":{"type":"something_new"}}"#).unwrap_err();
+
+        assert_eq!(error, ProtocolError::UnsupportedVersion(5));
+        assert!(matches!(error.reply(), Message::Error { code: ErrorCode::UnsupportedVersion, .. }));
+    }
+
+    #[test]
+    fn test_malformed() {
+        for text in ["PLAY:123:hello:80", r#"{"message":{"type":"pings_received"}}"#, r#"{"version":4,"message":{"type":"dance"}}"#] {
+            let error = decode(text).unwrap_err();
+            assert!(matches!(error, ProtocolError::Malformed(_)), "{}", text);
+            assert!(matches!(error.reply(), Message::Error { code: ErrorCode::Malformed, .. }));
+        }
+    }
+
+    #[test]
+    fn test_version_range() {
+        assert_eq!(ProtocolError::check_version(PROTOCOL_VERSION), Ok(()));
+        assert_eq!(ProtocolError::check_version(1), Err(ProtocolError::UnsupportedVersion(1)));
+        assert_eq!(ProtocolError::check_version(3), Ok(()));
+        assert!(decode(r#"{"version":2,"message":{"type":"pings_received"}}"#).is_err());
+
+        // Older peers are written to in their own version
+        let text = encode_as(&Message::PingsReceived, 3);
+        assert_eq!(text, r#"{"version":3,"message":{"type":"pings_received"}}"#);
+        assert_eq!(decode(&text), Ok(Message::PingsReceived));
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let theirs: Vec<Capability> = serde_json::from_str(r#"["udp_sync", "play_audio", "teleport"]"#).unwrap();
+        assert_eq!(theirs[2], Capability::Unknown);
+
+        let ours = [Capability::PlayAudio, Capability::PhotonClock, Capability::Unknown];
+        assert_eq!(negotiate(&ours, &theirs), vec![Capability::PlayAudio]);
+    }
+}
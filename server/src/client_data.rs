@@ -2,25 +2,35 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use websocket::sync::Client;
 use std::net::TcpStream;
 use std::collections::VecDeque;
+use websocket::WebSocketError;
+use protocol::Message;
+use crate::message_handler::send_message_as;
 
-pub use crate::models::ClientData;
+pub use crate::models::{ClientData, ClientSession};
 
 // Extension trait for ClientData
 pub trait ClientDataExt {
-    fn new(client: Client<TcpStream>) -> ClientData;
+    fn new(client: Client<TcpStream>, session: ClientSession) -> Self;
     fn add_ping(&mut self, timestamp: u128, latency: u128);
     fn update_smoothed_ping(&mut self);
+    fn send(&mut self, message: &Message) -> Result<(), WebSocketError>;
 }
 
 impl ClientDataExt for ClientData {
     // Create a new ClientData instance
-    fn new(client: Client<TcpStream>) -> ClientData {
+    fn new(client: Client<TcpStream>, session: ClientSession) -> ClientData {
         ClientData {
             client,
             ping_history: VecDeque::new(),
             smoothed_ping: None,
             photon_pings: None,
+            photon_server: None,
+            photon_uncertainty_us: None,
             waiting_for_photon_pings: false,
+            clock: None,
+            // The client's own preference, until someone picks another
+            scheduling: session.info.scheduling,
+            session,
         }
     }
 
@@ -66,4 +76,9 @@ impl ClientDataExt for ClientData {
             self.smoothed_ping = None;
         }
     }
+
+    // Send a protocol message in the version the client speaks
+    fn send(&mut self, message: &Message) -> Result<(), WebSocketError> {
+        send_message_as(&mut self.client, message, self.session.protocol_version)
+    }
 }
@@ -0,0 +1,100 @@
+use std::net::{SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+use websocket::{OwnedMessage, WebSocketError};
+use websocket::sync::Client;
+use protocol::{Capability, ErrorCode, Message, ProtocolError, ServerConfig};
+use crate::message_handler::{send_message, send_message_as};
+use crate::models::{ClientSession, PHOTON_PINGS_TIMEOUT, PING_INTERVAL, default_target_region};
+
+// How long a new connection has to send its HELLO
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Capabilities this server can make use of, the only ones a client can negotiate
+pub const SERVER_FEATURES: &[Capability] = &[Capability::PlayAudio, Capability::PhotonClock, Capability::ClockSync];
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+// The settings we tell clients about in WELCOME
+pub fn server_config() -> ServerConfig {
+    ServerConfig {
+        ping_interval_ms: PING_INTERVAL.as_millis() as u64,
+        ping_reply_timeout_ms: PHOTON_PINGS_TIMEOUT.as_millis() as u64,
+        default_region: default_target_region().to_string(),
+    }
+}
+
+// Wait for the client's HELLO and answer it with WELCOME. Clients that don't introduce themselves
+// properly are sent the reason and disconnected, and None is returned.
+pub fn accept_client(client: &mut Client<TcpStream>, ip: SocketAddr) -> Option<ClientSession> {
+    let text = receive_hello_text(client, ip)?;
+
+    let rejection = match protocol::decode(&text) {
+        Ok(Message::Hello { protocol_version, client: info }) => match ProtocolError::check_version(protocol_version) {
+            Ok(()) => {
+                let session = ClientSession {
+                    session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+                    protocol_version,
+                    features: protocol::negotiate(SERVER_FEATURES, &info.capabilities),
+                    info,
+                };
+                let welcome = Message::Welcome {
+                    session_id: session.session_id,
+                    config: server_config(),
+                    features: session.features.clone(),
+                };
+                if let Err(e) = send_message_as(client, &welcome, protocol_version) {
+                    println!("Error sending welcome to client {}: {:?}", ip, e);
+                    return None;
+                }
+                return Some(session);
+            }
+            Err(e) => e.reply(),
+        },
+        Ok(message) => Message::error(ErrorCode::UnexpectedMessage, format!("expected hello, got {:?}", message)),
+        Err(e) => e.reply(),
+    };
+
+    reject(client, ip, &rejection);
+    None
+}
+
+fn receive_hello_text(client: &mut Client<TcpStream>, ip: SocketAddr) -> Option<String> {
+    let deadline = Instant::now() + HELLO_TIMEOUT;
+    loop {
+        match client.recv_message() {
+            Ok(OwnedMessage::Text(text)) => return Some(text),
+            Ok(OwnedMessage::Ping(data)) => {
+                let _ = client.send_message(&OwnedMessage::Pong(data));
+            }
+            Ok(OwnedMessage::Close(_)) => {
+                println!("Client {} disconnected before saying hello", ip);
+                return None;
+            }
+            Ok(_) => {
+                reject(client, ip, &Message::error(ErrorCode::UnexpectedMessage, "expected hello"));
+                return None;
+            }
+            // In non-blocking mode this is most likely just no message yet
+            Err(WebSocketError::IoError(_)) => {}
+            Err(e) => {
+                println!("Error receiving hello from client {}: {:?}", ip, e);
+                return None;
+            }
+        }
+
+        if Instant::now() >= deadline {
+            let reason = format!("no hello within {} seconds", HELLO_TIMEOUT.as_secs());
+            reject(client, ip, &Message::error(ErrorCode::UnexpectedMessage, reason));
+            return None;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+fn reject(client: &mut Client<TcpStream>, ip: SocketAddr, reason: &Message) {
+    println!("Rejecting client {}: {:?}", ip, reason);
+    let _ = send_message(client, reason);
+    let _ = client.send_message(&OwnedMessage::Close(None));
+}
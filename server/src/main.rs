@@ -1,6 +1,7 @@
 mod models;
 mod client_data;
 mod message_handler;
+mod handshake;
 
 use std::thread;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -9,9 +10,11 @@ use websocket::OwnedMessage;
 use std::sync::{Arc, Mutex};
 use std::collections::HashMap;
 
-use crate::models::{ClientsRegistry, DEFAULT_PHOTON_TARGET_REGION, PhotonPingsResponse};
-use crate::message_handler::{send_play_message_to_all, request_photon_pings_from_all};
+use protocol::{ErrorCode, Message};
+use crate::models::{ClientsRegistry, BEST_PHOTON_REGION, DEFAULT_SCHEDULING, PING_INTERVAL, default_target_region};
+use crate::message_handler::{error_bound_text, send_play_message_to_all, request_photon_pings_from_all};
 use crate::client_data::{ClientData, ClientDataExt};
+use crate::handshake::accept_client;
 
 fn main() {
     // Create a WebSocket server that will listen on 127.0.0.1:8080
@@ -22,6 +25,32 @@ fn main() {
 
     println!("WebSocket server started on 127.0.0.1:8080");
 
+    // Connect to Photon so PLAY targets can be issued in the shared Photon server time
+    photon::start_connection();
+
+    // Pick the default target region in the background, the previous choice makes this quick
+    thread::spawn(|| {
+        let cache = photon::RegionCache::new("photon_regions.json", Duration::from_secs(60 * 60));
+        let regions = match photon::get_regions_cached(&cache, Duration::from_secs(5)) {
+            Ok(cached) => cached.regions,
+            Err(e) => {
+                println!("No Photon regions to choose from, using default region {}: {}", default_target_region(), e);
+                return;
+            }
+        };
+        let selection = photon::RegionSelection {
+            summary_path: Some("photon_best_region.json".into()),
+            ..photon::RegionSelection::default()
+        };
+        match selection.select(&regions) {
+            Some(best) => {
+                println!("Best Photon region: {} ({}ms)", best.region.short_name, best.ping);
+                let _ = BEST_PHOTON_REGION.set(best.region.short_name);
+            }
+            None => println!("No Photon region answered, using default region {}", default_target_region()),
+        }
+    });
+
     // Listen for connections
     for connection in server.filter_map(Result::ok) {
         // Clone the clients registry for this thread
@@ -30,7 +59,7 @@ fn main() {
         // Spawn a new thread for each connection
         thread::spawn(move || {
             // Accept the connection
-            if let Ok(websocket_client) = connection.accept() {
+            if let Ok(mut websocket_client) = connection.accept() {
                 println!("Client connected");
 
                 // Get the client's IP address
@@ -40,8 +69,20 @@ fn main() {
                 // Set the client to non-blocking mode
                 let _ = websocket_client.set_nonblocking(true);
 
+                // Only clients that introduce themselves are registered and pinged
+                let Some(session) = accept_client(&mut websocket_client, ip) else {
+                    return;
+                };
+                println!("Client {} is session {}: {} (version {} on {}), features {:?}",
+                         ip,
+                         session.session_id,
+                         session.info.display_name,
+                         session.info.software_version,
+                         session.info.platform,
+                         session.features);
+
                 // Create a ClientData instance
-                let client_data = ClientData::new(websocket_client);
+                let client_data = ClientData::new(websocket_client, session);
 
                 // Wrap client_data in Arc<Mutex<>> for thread-safe sharing
                 let client_data = Arc::new(Mutex::new(client_data));
@@ -60,7 +101,7 @@ fn main() {
                 thread::spawn(move || {
                     loop {
                         // Sleep for 2 seconds
-                        thread::sleep(Duration::from_secs(2));
+                        thread::sleep(PING_INTERVAL);
 
                         // Try to acquire lock and send ping
                         if let Ok(mut locked_client_data) = ping_client_data.lock() {
@@ -75,7 +116,7 @@ fn main() {
                             let cur_ping_bytes = locked_client_data.smoothed_ping.unwrap_or(0).to_be_bytes().to_vec();
 
                             // Send message with timestamp and estimated ping
-                            let message = OwnedMessage::Ping(vec![timestamp_bytes, cur_ping_bytes].concat());
+                            let message = OwnedMessage::Ping([timestamp_bytes, cur_ping_bytes].concat());
 
                             if cfg!(debug_assertions) {
                                 println!("Sending ping to client {} with timestamp {}", ip, now);
@@ -104,7 +145,9 @@ fn main() {
                             Ok(mut locked_client_data) => {
                                 // Try to read a message from the client
                                 match locked_client_data.client.recv_message() {
-                                    Ok(msg) => message_option = Some(msg),
+                                    // Noting when it arrived for clock sync exchanges, before
+                                    // anything else can add to it
+                                    Ok(msg) => message_option = Some((msg, protocol::unix_time_us())),
                                     Err(e) => {
                                         // If it's an IO error, it might be because no message is available
                                         // in non-blocking mode, so we'll just continue
@@ -125,7 +168,7 @@ fn main() {
                                 should_break = true;
                             }
                         }
-                    };
+                    }
 
                     if should_break {
                         // Remove client from registry before breaking
@@ -144,7 +187,7 @@ fn main() {
                     }
 
                     // Process the message
-                    let message = message_option.unwrap();
+                    let (message, received_us) = message_option.unwrap();
                     match message {
                         OwnedMessage::Close(_) => {
                             // Client wants to close connection
@@ -171,82 +214,105 @@ fn main() {
                         OwnedMessage::Text(text) => {
                             println!("Received message from {}: {}", ip, text);
 
-                            // Check if this is a command to send a play message to all clients
-                            if text.starts_with("SEND_PLAY:") {
-                                // Check if format is SEND_PLAY:message or SEND_PLAY:region:message
-                                let parts: Vec<&str> = text.trim_start_matches("SEND_PLAY:").splitn(2, ':').collect();
-
-                                let (target_region, message_content) = if parts.len() > 1 {
-                                    // Format is SEND_PLAY:region:message
-                                    (parts[0], parts[1])
-                                } else {
-                                    // Format is SEND_PLAY:message, use default region
-                                    (DEFAULT_PHOTON_TARGET_REGION, parts[0])
-                                };
+                            let reply = match protocol::decode(&text) {
+                                Ok(Message::SendPlay { region, content }) => {
+                                    let target_region = region.as_deref().unwrap_or(default_target_region());
+                                    println!("Received command to send play message: {} for region: {}", content, target_region);
 
-                                println!("Received command to send play message: {} for region: {}", message_content, target_region);
-
-                                // Send play message to all clients
-                                send_play_message_to_all(&thread_clients, message_content, target_region);
+                                    // Send play message to all clients
+                                    let clients = send_play_message_to_all(&thread_clients, &content, target_region);
+                                    for schedule in &clients {
+                                        println!("Session {} ({}) scheduled {:?}, expected error {}",
+                                                 schedule.session_id, schedule.display_name, schedule.mode, error_bound_text(schedule.error_bound_ms));
+                                    }
 
-                                // Also send confirmation to the client that sent the command
-                                if let Ok(mut locked_client_data) = client_data.lock() {
-                                    let _ = locked_client_data.client.send_message(&OwnedMessage::Text(
-                                        format!("Play message '{}' sent to all clients", message_content)
-                                    ));
+                                    // Also confirm to the client that sent the command, with the error bounds
+                                    Message::PlaySent { content, clients }
                                 }
-                            } else if text.starts_with("REQUEST_PING:") {
-                                // Check if a specific region is requested
-                                let parts: Vec<&str> = text.splitn(2, ':').collect();
-                                let target_region = if parts.len() > 1 && !parts[1].is_empty() {
-                                    parts[1]
-                                } else {
-                                    DEFAULT_PHOTON_TARGET_REGION
-                                };
-
-                                if cfg!(debug_assertions) {
-                                    println!("Received command to request photon pings from all clients for region {}", target_region);
+                                Ok(Message::SetScheduling { session_id: None, mode }) => {
+                                    println!("Scheduling clients without a mode of their own {:?}", mode);
+                                    *DEFAULT_SCHEDULING.lock().unwrap() = mode;
+                                    Message::SchedulingSet { session_id: None, mode }
                                 }
-                                request_photon_pings_from_all(&thread_clients, target_region);
-                            } else if text.starts_with("PHOTON_PINGS:") {
-                                let json_content = text.trim_start_matches("PHOTON_PINGS:");
-                                match serde_json::from_str::<PhotonPingsResponse>(json_content) {
-                                    Ok(response) => {
-                                        if cfg!(debug_assertions) {
-                                            println!("Received photon pings from client {}", ip);
-                                            println!("Number of regions: {}", response.regions.len());
-
-                                            // Process the ping data as needed
-                                            for region_info in &response.regions {
-                                                println!("Region: {}, Latency: {}ms, Last updated: {}", 
-                                                         region_info.region, 
-                                                         region_info.latency,
-                                                         region_info.last_updated);
-                                            }
+                                Ok(Message::SetScheduling { session_id: Some(session_id), mode }) => {
+                                    let target = thread_clients.lock().unwrap().values()
+                                        .find(|other| other.lock().is_ok_and(|other| other.session.session_id == session_id))
+                                        .cloned();
+                                    match target {
+                                        Some(target) => {
+                                            println!("Scheduling session {} {:?}", session_id, mode);
+                                            target.lock().unwrap().scheduling = Some(mode);
+                                            Message::SchedulingSet { session_id: Some(session_id), mode }
                                         }
-
-                                        // Store the ping data for later use
-                                        if let Ok(mut locked_client_data) = client_data.lock() {
-                                            // Store the photon ping data
-                                            locked_client_data.photon_pings = Some(response.regions);
-                                            // Mark that we're no longer waiting for photon pings
-                                            locked_client_data.waiting_for_photon_pings = false;
-
-                                            // Acknowledge receipt
-                                            let _ = locked_client_data.client.send_message(&OwnedMessage::Text(
-                                                "Photon ping data received".to_string()
-                                            ));
+                                        None => Message::error(ErrorCode::UnknownSession, format!("no client has session {}", session_id)),
+                                    }
+                                }
+                                Ok(Message::RequestPings { region }) => {
+                                    let target_region = region.as_deref().unwrap_or(default_target_region());
+                                    if cfg!(debug_assertions) {
+                                        println!("Received command to request photon pings from all clients for region {}", target_region);
+                                    }
+                                    request_photon_pings_from_all(&thread_clients, target_region);
+                                    continue;
+                                }
+                                Ok(Message::PhotonPings { regions, photon_server, photon_uncertainty_us }) => {
+                                    if cfg!(debug_assertions) {
+                                        println!("Received photon pings from client {}", ip);
+                                        println!("Number of regions: {}", regions.len());
+
+                                        // Process the ping data as needed
+                                        for region_info in &regions {
+                                            println!("Region: {}, Latency: {}ms, Last updated: {}",
+                                                     region_info.region,
+                                                     region_info.latency,
+                                                     region_info.last_updated);
                                         }
-                                    },
-                                    Err(e) => {
-                                        println!("Error parsing photon ping data from client {}: {:?}", ip, e);
                                     }
+
+                                    // Store the ping data for later use
+                                    if let Ok(mut locked_client_data) = client_data.lock() {
+                                        locked_client_data.photon_pings = Some(regions);
+                                        locked_client_data.photon_server = photon_server;
+                                        locked_client_data.photon_uncertainty_us = photon_uncertainty_us;
+                                        // Mark that we're no longer waiting for photon pings
+                                        locked_client_data.waiting_for_photon_pings = false;
+                                    }
+
+                                    // Acknowledge receipt
+                                    Message::PingsReceived
                                 }
-                            } else {
-                                // Echo text messages back to the client
-                                if let Ok(mut locked_client_data) = client_data.lock() {
-                                    let _ = locked_client_data.client.send_message(&OwnedMessage::Text(format!("Echo: {}", text)));
+                                Ok(Message::TimeRequest { client_send_us }) => Message::TimeResponse {
+                                    client_send_us,
+                                    server_receive_us: received_us,
+                                    server_send_us: protocol::unix_time_us(),
+                                },
+                                Ok(Message::ClockReport { estimate }) => {
+                                    if cfg!(debug_assertions) {
+                                        println!("Client {} clock: server is {}us ahead, +/- {}us, drift {:.2}ppm over {} samples",
+                                                 ip, estimate.offset_us, estimate.uncertainty_us, estimate.drift_ppm, estimate.samples);
+                                    }
+                                    if let Ok(mut locked_client_data) = client_data.lock() {
+                                        locked_client_data.clock = Some(estimate);
+                                    }
+                                    continue;
+                                }
+                                Ok(Message::Error { code, message }) => {
+                                    // Never answered, or two confused peers would argue forever
+                                    println!("Client {} rejected a message ({:?}): {}", ip, code, message);
+                                    continue;
+                                }
+                                Ok(message) => {
+                                    println!("Unexpected message from client {}: {:?}", ip, message);
+                                    Message::error(ErrorCode::UnexpectedMessage, "the server does not accept this message")
                                 }
+                                Err(e) => {
+                                    println!("Error reading message from client {}: {}", ip, e);
+                                    e.reply()
+                                }
+                            };
+
+                            if let Ok(mut locked_client_data) = client_data.lock() {
+                                let _ = locked_client_data.send(&reply);
                             }
                         }
                         OwnedMessage::Binary(data) => {
@@ -272,8 +338,8 @@ fn main() {
                                     .expect("Time went backwards")
                                     .as_millis();
 
-                                // Calculate latency
-                                let latency = now - sent_time;
+                                // Calculate latency, zero rather than a panic if our clock stepped back
+                                let latency = now.saturating_sub(sent_time);
 
                                 // Store the ping data and update smoothed ping
                                 if let Ok(mut locked_client_data) = client_data.lock() {
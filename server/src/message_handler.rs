@@ -1,45 +1,135 @@
+use std::collections::HashMap;
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use websocket::OwnedMessage;
-use crate::models::{ClientsRegistry};
+use std::time::{Duration, Instant, SystemTime};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use websocket::{OwnedMessage, WebSocketError};
+use websocket::sync::Client;
+use photon::PhotonClock;
+use protocol::{unix_time_us, Capability, ClientSchedule, ClockEstimate, Message, PlayTarget, SchedulingMode, PROTOCOL_VERSION, TRIGGER_PROTOCOL_VERSION};
+use crate::client_data::ClientDataExt;
+use crate::models::{ClientData, ClientsRegistry, DEFAULT_SCHEDULING, PHOTON_PINGS_TIMEOUT};
 
-// Function to get the highest RTT among all connected clients
-pub fn get_highest_rtt(clients: &ClientsRegistry, target_region: &str) -> u128 {
-    let locked_clients = clients.lock().unwrap();
-    let mut highest_server_ping = 0;
-    let mut highest_photon_ping = 0;
+// Send a protocol message as a text frame
+pub fn send_message(client: &mut Client<TcpStream>, message: &Message) -> Result<(), WebSocketError> {
+    send_message_as(client, message, PROTOCOL_VERSION)
+}
 
-    for (_, client_data) in locked_clients.iter() {
-        if let Ok(locked_client) = client_data.lock() {
-            // Check server-client ping
-            if let Some(rtt) = locked_client.smoothed_ping {
-                highest_server_ping = highest_server_ping.max(rtt);
-            }
+// Send a protocol message as a text frame in an older version, for clients that only speak that
+pub fn send_message_as(client: &mut Client<TcpStream>, message: &Message, version: u32) -> Result<(), WebSocketError> {
+    client.send_message(&OwnedMessage::Text(protocol::encode_as(message, version)))
+}
 
-            // Check photon pings if available
-            if let Some(photon_pings) = &locked_client.photon_pings {
-                for ping_info in photon_pings {
-                    // Only consider pings for the target region
-                    if ping_info.region == target_region {
-                        highest_photon_ping = highest_photon_ping.max(ping_info.latency);
-                    }
-                }
-            }
+// How far off a client may play, for printing
+pub fn error_bound_text(error_bound_ms: Option<u64>) -> String {
+    match error_bound_ms {
+        Some(error_bound_ms) => format!("up to {} ms", error_bound_ms),
+        None => "unbounded".to_string(),
+    }
+}
+
+// Time a client needs beyond the network to act on a PLAY, e.g. to wake its playback thread
+const PLAY_MARGIN_MS: u128 = 20;
+
+// Which clock a client's PLAY target is given on, best first
+pub enum TargetClock {
+    // The client's own, converted with the offset it reported from clock sync
+    Local(ClockEstimate),
+    // Photon server time, when we and the client follow the same Photon server
+    Photon,
+    // Ours, trusting that the client's wall clock agrees
+    Server,
+}
+
+pub fn target_clock(client: &ClientData, photon_clock: Option<&PhotonClock>) -> TargetClock {
+    match &client.clock {
+        Some(estimate) => TargetClock::Local(*estimate),
+        None if shares_photon_clock(client, photon_clock) => TargetClock::Photon,
+        None => TargetClock::Server,
+    }
+}
+
+// Whether the client's Photon clock follows the same server as ours, as other servers' timestamps
+// are unrelated
+fn shares_photon_clock(client: &ClientData, photon_clock: Option<&PhotonClock>) -> bool {
+    client.session.has_feature(Capability::PhotonClock)
+        && photon_clock.is_some_and(|clock| client.photon_server.as_deref() == Some(clock.server()))
+}
+
+// The mode a client is scheduled in: its own if it has one, the server's default otherwise. Clients
+// too old to know TRIGGER are always scheduled.
+pub fn scheduling_mode(client: &ClientData) -> SchedulingMode {
+    if client.session.protocol_version < TRIGGER_PROTOCOL_VERSION {
+        return SchedulingMode::Scheduled;
+    }
+    client.scheduling.unwrap_or_else(|| *DEFAULT_SCHEDULING.lock().unwrap())
+}
+
+// How a PLAY reaches one client on time, in milliseconds
+pub struct ClientTiming {
+    pub mode: SchedulingMode,
+    // Scheduled: half the worst round trip in the client's recent ping history, so the target
+    // arrives in time. Staggered: half the smoothed round trip, which the message is sent ahead by.
+    pub one_way_delay: u128,
+    // How far from the target the client may play: the uncertainty of its clock when scheduled,
+    // the variation in its one-way delay when staggered. None if nothing bounds it.
+    pub error_bound: Option<u128>,
+}
+
+impl ClientTiming {
+    // How far ahead the target has to be for this client
+    pub fn lead(&self) -> u128 {
+        match self.mode {
+            SchedulingMode::Scheduled => self.one_way_delay + self.error_bound.unwrap_or(0),
+            SchedulingMode::Staggered => self.one_way_delay,
         }
     }
 
-    // Return the sum of the highest server ping and the highest photon ping
-    highest_server_ping + highest_photon_ping
+    pub fn error_bound_ms(&self) -> Option<u64> {
+        self.error_bound.map(|error_bound| error_bound as u64)
+    }
+}
+
+pub fn client_timing(client: &ClientData, photon_clock: Option<&PhotonClock>) -> ClientTiming {
+    let mode = scheduling_mode(client);
+    let latencies = || client.ping_history.iter().map(|(_, latency)| *latency);
+
+    if mode == SchedulingMode::Staggered {
+        // Assuming symmetric paths, the one-way delay is somewhere between half the best and
+        // half the worst round trip, and the smoothed one is somewhere in between
+        let spread = latencies().max().unwrap_or(0) - latencies().min().unwrap_or(0);
+        return ClientTiming {
+            mode,
+            one_way_delay: client.smoothed_ping.unwrap_or(0).div_ceil(2),
+            error_bound: Some(spread.div_ceil(2)),
+        };
+    }
+
+    let worst_rtt = latencies().max().or(client.smoothed_ping).unwrap_or(0);
+    let error_bound = match target_clock(client, photon_clock) {
+        TargetClock::Local(estimate) => Some((estimate.uncertainty_us as u128).div_ceil(1000)),
+        // Each side follows Photon's time to within its own uncertainty. A client that doesn't
+        // report its uncertainty leaves the error unbounded.
+        TargetClock::Photon => client.photon_uncertainty_us.map(|theirs| {
+            let ours = photon_clock.map_or(0, |clock| clock.uncertainty().as_millis());
+            ours + (theirs as u128).div_ceil(1000)
+        }),
+        // Nothing bounds how far apart two wall clocks are
+        TargetClock::Server => None,
+    };
+
+    ClientTiming { mode, one_way_delay: worst_rtt.div_ceil(2), error_bound }
 }
 
-// Function to send a play message to all clients with a future timestamp
-pub fn send_play_message_to_all(clients: &ClientsRegistry, message: &str, target_region: &str) {
+// Function to send a play message to all clients with a future timestamp. Returns how each
+// client was scheduled.
+pub fn send_play_message_to_all(clients: &ClientsRegistry, message: &str, target_region: &str) -> Vec<ClientSchedule> {
     // First, request photon pings from all clients for the target region
     println!("Requesting photon pings from all clients for region {} before sending play message", target_region);
     request_photon_pings_from_all(clients, target_region);
 
     // Wait for all clients to respond with their photon pings (with a timeout)
-    let max_wait_time = Duration::from_secs(2); // Maximum wait time of 2 seconds
+    let max_wait_time = PHOTON_PINGS_TIMEOUT;
     let start_time = SystemTime::now();
 
     let mut all_clients_responded = false;
@@ -49,12 +139,11 @@ pub fn send_play_message_to_all(clients: &ClientsRegistry, message: &str, target
 
         let locked_clients = clients.lock().unwrap();
         for (_, client_data) in locked_clients.iter() {
-            if let Ok(locked_client) = client_data.lock() {
-                if locked_client.waiting_for_photon_pings {
-                    // At least one client is still waiting for photon pings
-                    all_clients_responded = false;
-                    break;
-                }
+            if let Ok(locked_client) = client_data.lock()
+                && locked_client.waiting_for_photon_pings {
+                // At least one client is still waiting for photon pings
+                all_clients_responded = false;
+                break;
             }
         }
 
@@ -70,34 +159,87 @@ pub fn send_play_message_to_all(clients: &ClientsRegistry, message: &str, target
         println!("All clients responded with photon pings");
     }
 
-    let now = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis();
+    let photon_clock = photon::clock();
 
-    // Get the highest RTT among all clients (sum of highest server ping and highest photon ping for the target region)
-    let highest_rtt = get_highest_rtt(clients, target_region);
-    println!("Highest RTT (sum of highest server ping and highest photon ping for region {}): {} ms", target_region, highest_rtt);
-
-    // Calculate a future timestamp that gives all clients enough time to receive and process the message
-    // We multiply by 1.5 to add some buffer
-    let future_timestamp = now + (highest_rtt * 3 / 2);
+    // The target must be far enough ahead for the slowest client to get the message and for the
+    // least certain client's clock to still be ahead of it
+    let locked_clients = clients.lock().unwrap();
+    let mut timings = HashMap::new();
+    for (addr, client_data) in locked_clients.iter() {
+        if let Ok(locked_client) = client_data.lock() {
+            let timing = client_timing(&locked_client, photon_clock.as_ref());
+            println!("Client {} ({:?}): one way delay {} ms, expected error {}",
+                     addr, timing.mode, timing.one_way_delay, error_bound_text(timing.error_bound_ms()));
+            timings.insert(*addr, timing);
+        }
+    }
+    let lead = timings.values().map(ClientTiming::lead).max().unwrap_or(0) + PLAY_MARGIN_MS;
+    println!("Lead time for region {}: {} ms", target_region, lead);
 
-    // Create the play message with the future timestamp
-    let play_message = format!("PLAY:{}:{}:{}", future_timestamp, message, highest_rtt);
+    let target_us = unix_time_us() + lead as u64 * 1000;
+    let target = Instant::now() + Duration::from_millis(lead as u64);
+    let photon_timestamp = photon_clock.as_ref().map(|clock| clock.server_timestamp().wrapping_add(lead as i32));
 
-    // Send the message to all clients
-    let locked_clients = clients.lock().unwrap();
+    // Send each client the target on the best clock it has, or hold it back by its one-way delay
+    let mut schedules = Vec::new();
     for (addr, client_data) in locked_clients.iter() {
-        if let Ok(mut locked_client) = client_data.lock() {
-            match locked_client.client.send_message(&OwnedMessage::Text(play_message.clone())) {
-                Ok(_) => println!("Sent play message to client {}", addr),
-                Err(e) => println!("Error sending play message to client {}: {:?}", addr, e),
+        let Some(timing) = timings.get(addr) else {
+            continue;
+        };
+        let Ok(mut locked_client) = client_data.lock() else {
+            continue;
+        };
+        schedules.push(ClientSchedule {
+            session_id: locked_client.session.session_id,
+            display_name: locked_client.session.info.display_name.clone(),
+            mode: timing.mode,
+            error_bound_ms: timing.error_bound_ms(),
+        });
+
+        let play_message = match (timing.mode, target_clock(&locked_client, photon_clock.as_ref()), photon_timestamp) {
+            (SchedulingMode::Staggered, _, _) => {
+                let send_at = target - Duration::from_millis(timing.one_way_delay as u64);
+                send_trigger_at(*addr, client_data.clone(), send_at, message.to_string());
+                continue;
             }
+            (_, TargetClock::Local(estimate), _) => Message::Play {
+                target: PlayTarget::Local { timestamp_us: estimate.to_local_time(target_us) },
+                content: message.to_string(),
+                lead_ms: lead as u64,
+            },
+            (_, TargetClock::Photon, Some(photon_timestamp)) => Message::PlayPhoton {
+                photon_timestamp,
+                target_timestamp: target_us / 1000,
+                content: message.to_string(),
+                photon_server: photon_clock.as_ref().map(|clock| clock.server().to_string()),
+            },
+            _ => Message::Play {
+                target: PlayTarget::Server { timestamp_us: target_us },
+                content: message.to_string(),
+                lead_ms: lead as u64,
+            },
+        };
+        match locked_client.send(&play_message) {
+            Ok(_) => println!("Sent play message to client {}", addr),
+            Err(e) => println!("Error sending play message to client {}: {:?}", addr, e),
         }
     }
 
-    println!("Sent play message to all clients with future timestamp: {}", future_timestamp);
+    println!("Sent play message to all clients with target {}us on the server clock", target_us);
+    schedules
+}
+
+// Send a staggered client its trigger at `send_at`, without holding up everyone else
+fn send_trigger_at(addr: SocketAddr, client_data: Arc<Mutex<ClientData>>, send_at: Instant, content: String) {
+    thread::spawn(move || {
+        thread::sleep(send_at.saturating_duration_since(Instant::now()));
+        if let Ok(mut locked_client) = client_data.lock() {
+            match locked_client.send(&Message::Trigger { content }) {
+                Ok(_) => println!("Sent trigger to client {}", addr),
+                Err(e) => println!("Error sending trigger to client {}: {:?}", addr, e),
+            }
+        }
+    });
 }
 
 pub fn request_photon_pings_from_all(clients: &ClientsRegistry, target_region: &str) {
@@ -108,7 +250,8 @@ pub fn request_photon_pings_from_all(clients: &ClientsRegistry, target_region: &
             // Clear any previous photon ping data
             locked_client.photon_pings = None;
 
-            match locked_client.client.send_message(&OwnedMessage::Text(format!("REQUEST_PING:{}", target_region))) {
+            let request = Message::RequestPings { region: Some(target_region.to_string()) };
+            match locked_client.send(&request) {
                 Ok(_) => {
                     if cfg!(debug_assertions) {
                         println!("Sent ping message to client {} for region {}", addr, target_region);
@@ -118,4 +261,151 @@ pub fn request_photon_pings_from_all(clients: &ClientsRegistry, target_region: &
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::JoinHandle;
+    use websocket::ClientBuilder;
+    use websocket::sync::Server;
+    use protocol::ClientInfo;
+    use crate::models::ClientSession;
+
+    const PHOTON_SERVER: &str = "10.0.0.1:5055";
+
+    // A scheduled client with the given features, over a real loopback connection. The other end
+    // is handed back so it stays open for as long as the test needs.
+    fn client_data(features: Vec<Capability>) -> (ClientData, JoinHandle<Client<TcpStream>>) {
+        let mut server = Server::bind("127.0.0.1:0").unwrap();
+        let address = server.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            ClientBuilder::new(&format!("ws://{}", address)).unwrap().connect_insecure().unwrap()
+        });
+        let Ok(upgrade) = server.accept() else { panic!("no connection") };
+        let Ok(client) = upgrade.accept() else { panic!("handshake failed") };
+
+        let session = ClientSession {
+            session_id: 1,
+            protocol_version: PROTOCOL_VERSION,
+            info: ClientInfo {
+                display_name: "stage left".to_string(),
+                platform: "linux".to_string(),
+                software_version: "0.1.0".to_string(),
+                capabilities: features.clone(),
+                scheduling: None,
+            },
+            features,
+        };
+        let mut client_data = ClientData::new(client, session);
+        client_data.scheduling = Some(SchedulingMode::Scheduled);
+        (client_data, peer)
+    }
+
+    fn estimate(offset_us: i64, uncertainty_us: u64) -> ClockEstimate {
+        ClockEstimate { offset_us, uncertainty_us, drift_ppm: 0.0, round_trip_us: 2 * uncertainty_us, samples: 8 }
+    }
+
+    #[test]
+    fn test_target_clock_prefers_reported_offset() {
+        let (mut client, _peer) = client_data(vec![Capability::PhotonClock]);
+        let photon_clock = PhotonClock::from_offset(0, 10, PHOTON_SERVER);
+
+        // Without an offset or a shared Photon server, all that is left is the wall clock
+        assert!(matches!(target_clock(&client, Some(&photon_clock)), TargetClock::Server));
+
+        client.photon_server = Some(PHOTON_SERVER.to_string());
+        assert!(matches!(target_clock(&client, Some(&photon_clock)), TargetClock::Photon));
+        assert!(matches!(target_clock(&client, None), TargetClock::Server));
+
+        client.clock = Some(estimate(1500, 200));
+        assert!(matches!(target_clock(&client, Some(&photon_clock)), TargetClock::Local(e) if e.offset_us == 1500));
+    }
+
+    #[test]
+    fn test_photon_clock_needs_feature_and_same_server() {
+        let (mut client, _peer) = client_data(vec![]);
+        let photon_clock = PhotonClock::from_offset(0, 10, PHOTON_SERVER);
+        client.photon_server = Some(PHOTON_SERVER.to_string());
+        assert!(matches!(target_clock(&client, Some(&photon_clock)), TargetClock::Server));
+
+        let (mut client, _peer) = client_data(vec![Capability::PhotonClock]);
+        client.photon_server = Some("10.0.0.2:5055".to_string());
+        assert!(matches!(target_clock(&client, Some(&photon_clock)), TargetClock::Server));
+    }
+
+    #[test]
+    fn test_scheduled_timing_covers_worst_round_trip() {
+        let (mut client, _peer) = client_data(vec![]);
+        for (timestamp, latency) in [(0, 40), (1, 101), (2, 60)] {
+            client.ping_history.push_back((timestamp, latency));
+        }
+        client.smoothed_ping = Some(67);
+
+        let timing = client_timing(&client, None);
+        assert_eq!(timing.mode, SchedulingMode::Scheduled);
+        assert_eq!(timing.one_way_delay, 51);
+        // On the wall clock alone, nothing bounds the error
+        assert_eq!(timing.error_bound, None);
+        assert_eq!(timing.lead(), 51);
+
+        // The smoothed ping stands in until there is a history
+        client.ping_history.clear();
+        assert_eq!(client_timing(&client, None).one_way_delay, 34);
+    }
+
+    #[test]
+    fn test_error_bound_follows_target_clock() {
+        let (mut client, _peer) = client_data(vec![Capability::PhotonClock]);
+        client.smoothed_ping = Some(20);
+        let photon_clock = PhotonClock::from_offset(0, 8, PHOTON_SERVER);
+
+        // Photon: unbounded until the client says how far its Photon clock may be off
+        client.photon_server = Some(PHOTON_SERVER.to_string());
+        assert_eq!(client_timing(&client, Some(&photon_clock)).error_bound, None);
+
+        // Then our uncertainty plus the client's, rounded up to whole milliseconds
+        client.photon_uncertainty_us = Some(6_500);
+        let timing = client_timing(&client, Some(&photon_clock));
+        assert_eq!(timing.error_bound, Some(4 + 7));
+        assert_eq!(timing.lead(), 10 + 11);
+
+        // Local: the uncertainty of the reported offset, rounded up to whole milliseconds
+        client.clock = Some(estimate(-3_000, 2_500));
+        assert_eq!(client_timing(&client, Some(&photon_clock)).error_bound, Some(3));
+    }
+
+    #[test]
+    fn test_staggered_timing_uses_smoothed_round_trip() {
+        let (mut client, _peer) = client_data(vec![]);
+        client.scheduling = Some(SchedulingMode::Staggered);
+        for (timestamp, latency) in [(0, 40), (1, 100), (2, 61)] {
+            client.ping_history.push_back((timestamp, latency));
+        }
+        client.smoothed_ping = Some(67);
+        client.clock = Some(estimate(0, 50_000));
+
+        let timing = client_timing(&client, None);
+        assert_eq!(timing.one_way_delay, 34);
+        assert_eq!(timing.error_bound, Some(30));
+        // The trigger is sent ahead by the delay, so its spread doesn't push the target out
+        assert_eq!(timing.lead(), 34);
+
+        // Clients from before TRIGGER can't be staggered, whatever they are set to
+        client.session.protocol_version = TRIGGER_PROTOCOL_VERSION - 1;
+        let timing = client_timing(&client, None);
+        assert_eq!(timing.mode, SchedulingMode::Scheduled);
+        assert_eq!(timing.one_way_delay, 50);
+        assert_eq!(timing.error_bound, Some(50));
+    }
+
+    #[test]
+    fn test_local_target_is_converted_with_offset() {
+        // The client's clock is 2.5 ms behind ours, so it plays when its clock reads that much less
+        let estimate = estimate(2_500, 100);
+        assert_eq!(estimate.to_local_time(1_000_000), 997_500);
+        assert_eq!(estimate.to_server_time(estimate.to_local_time(1_000_000)), 1_000_000);
+
+        let ahead = ClockEstimate { offset_us: -2_500, ..estimate };
+        assert_eq!(ahead.to_local_time(1_000_000), 1_002_500);
+    }
+}
@@ -1,31 +1,61 @@
-use serde::{Serialize, Deserialize};
 use std::collections::{VecDeque, HashMap};
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
 use websocket::sync::Client;
 use std::net::TcpStream;
+use protocol::{Capability, ClientInfo, ClockEstimate, RegionPingInfo, SchedulingMode};
 
-#[derive(Serialize, Deserialize)]
-pub struct RegionPingInfo {
-    pub region: String,
-    pub latency: u128,
-    pub last_updated: u128,
+// A client that completed the HELLO/WELCOME handshake
+pub struct ClientSession {
+    pub session_id: u64,
+    pub info: ClientInfo,
+    // The version the client said hello in, which everything sent to it is written in
+    pub protocol_version: u32,
+    // Capabilities both we and the client have
+    pub features: Vec<Capability>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct PhotonPingsResponse {
-    pub regions: Vec<RegionPingInfo>,
+impl ClientSession {
+    pub fn has_feature(&self, capability: Capability) -> bool {
+        self.features.contains(&capability)
+    }
 }
 
 // Structure to store client data including ping history
 pub struct ClientData {
     pub client: Client<TcpStream>,
+    pub session: ClientSession,
     pub ping_history: VecDeque<(u128, u128)>,
     pub smoothed_ping: Option<u128>,
     pub photon_pings: Option<Vec<RegionPingInfo>>,
+    // The Photon server the client's Photon clock follows, as of its last ping report
+    pub photon_server: Option<String>,
+    // How far the client's Photon clock may be off, in microseconds, if it said
+    pub photon_uncertainty_us: Option<u64>,
     pub waiting_for_photon_pings: bool,
+    // The client's latest clock sync report: our clock minus the client's, and how sure it is
+    pub clock: Option<ClockEstimate>,
+    // How this client is scheduled, if not the server's default
+    pub scheduling: Option<SchedulingMode>,
 }
 
 pub type ClientsRegistry = Arc<Mutex<HashMap<SocketAddr, Arc<Mutex<ClientData>>>>>;
 
-pub const DEFAULT_PHOTON_TARGET_REGION: &str = "us";
\ No newline at end of file
+pub const DEFAULT_PHOTON_TARGET_REGION: &str = "us";
+
+// How often each client's round trip is measured
+pub const PING_INTERVAL: Duration = Duration::from_secs(2);
+
+// How long a PLAY waits for clients to report their Photon pings
+pub const PHOTON_PINGS_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Best region measured from this machine at startup, used instead of the default once known
+pub static BEST_PHOTON_REGION: OnceLock<String> = OnceLock::new();
+
+// How clients without a scheduling mode of their own are scheduled
+pub static DEFAULT_SCHEDULING: Mutex<SchedulingMode> = Mutex::new(SchedulingMode::Scheduled);
+
+pub fn default_target_region() -> &'static str {
+    BEST_PHOTON_REGION.get().map(String::as_str).unwrap_or(DEFAULT_PHOTON_TARGET_REGION)
+}
\ No newline at end of file